@@ -7,16 +7,24 @@ mod ui;
 mod utils;
 
 use std::collections::VecDeque;
+use std::io::ErrorKind;
 use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 use bytes::Bytes;
 use druid::{AppLauncher, ExtEventSink, WindowDesc};
-use eyre::{eyre, WrapErr};
-use quinn::{ClientConfig, Connection, Endpoint, TransportConfig};
-use tokio::sync::mpsc::UnboundedReceiver;
-use tokio::time::Instant;
+use eyre::{bail, ensure, eyre, WrapErr};
+use inputshare_common::{ControlMessage, DeviceStatus, StreamType, PROTOCOL_VERSION};
+use quinn::{ClientConfig, Connection, Endpoint, ReadExactError, RecvStream, SendStream, TransportConfig};
+use rustls::client::ServerCertVerifier;
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::task::JoinSet;
+use tokio::time::{interval, Instant, MissedTickBehavior};
 use tokio::{select, spawn};
 use tracing::instrument;
 use tracing_error::ErrorLayer;
@@ -26,12 +34,14 @@ use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use yawi::InputHook;
 
-use crate::model::{AppState, ConnectionCommand, NetworkInfo};
+use crate::model::{AppState, ConnectionCommand, ConnectionState, NetworkInfo, Profile, Side, TypingProgress};
 use crate::runtime::{ExtEventSinkCallback, RuntimeDelegate};
-use crate::sender::InputSender;
+use crate::sender::{InputSender, QueuePolicy};
 use crate::ui::widget::{theme, Theme};
 use crate::utils::error::set_eyre_hook;
-use crate::utils::{hook, process_hook_event, SkipServerVerification};
+use crate::utils::gamepad::GamepadForwarder;
+use crate::utils::hook::HookEvent;
+use crate::utils::{hook, process_hook_event, PinnedServerVerification, SkipServerVerification};
 
 #[instrument]
 pub fn main() {
@@ -64,47 +74,255 @@ pub fn main() {
         .expect("launch failed");
 }
 
-#[instrument(skip(sink, controller))]
+/// The settings that apply to the connections to all servers
+#[derive(Debug, Copy, Clone)]
+pub struct ConnectionOptions {
+    pub info: bool,
+    pub rate: f32,
+    pub gamepad: bool,
+    pub reconnect: bool
+}
+
+/// One of the servers that the input can be switched to
+#[derive(Debug)]
+struct Target {
+    name: String,
+    commands: UnboundedSender<ConnectionCommand>,
+    events: UnboundedSender<HookEvent>
+}
+
+/// Connects to all `profiles` at once and forwards the input to the one that was selected with its hotkey.
+/// Commands from the UI go to the target that received the input last.
+#[instrument(skip(sink, controller, profiles))]
 async fn connection(
-    sink: &ExtEventSink, mut controller: UnboundedReceiver<ConnectionCommand>, host: &str, info: bool, rate: f32
+    sink: &ExtEventSink, mut controller: UnboundedReceiver<ConnectionCommand>, profiles: Vec<Profile>, options: ConnectionOptions
 ) -> eyre::Result<()> {
-    let wait = async {
-        loop {
-            match controller.recv().await {
-                None => return Err(eyre!("control channel closed")),
-                Some(ConnectionCommand::ShutdownServer) => tracing::warn!("Can not send a shutdown signal until connected"),
-                Some(ConnectionCommand::Disconnect) => {
-                    tracing::debug!("Canceling connection");
-                    return Ok(());
+    ensure!(!profiles.is_empty(), "No profile is enabled");
+    let hook_profiles = profiles.clone();
+    // Shared with the hook, so that the screen edges only switch to connected targets
+    let connected: Arc<[AtomicBool]> = profiles.iter().map(|_| AtomicBool::new(false)).collect();
+    let (hook_sender, mut hook_events) = unbounded_channel();
+    let (update_sender, mut updates) = unbounded_channel();
+    let mut tasks = JoinSet::new();
+    let mut targets = Vec::new();
+    for (index, profile) in profiles.into_iter().enumerate() {
+        let (commands, command_receiver) = unbounded_channel();
+        let (events, event_receiver) = unbounded_channel();
+        targets.push(Target {
+            name: profile.name.clone(),
+            commands,
+            events
+        });
+        let sink = sink.clone();
+        let updates = update_sender.clone();
+        // The network info of several servers would overwrite each other
+        let options = ConnectionOptions {
+            info: options.info && index == 0,
+            ..options
+        };
+        tasks.spawn(async move {
+            let result = target_connection(&sink, command_receiver, event_receiver, updates, index, &profile, options).await;
+            (index, result)
+        });
+    }
+
+    let mut active = None;
+    let mut focused = 0;
+    let mut hook_registered = false;
+    let mut lost = false;
+    let result = loop {
+        select! {
+            Some((index, is_connected)) = updates.recv() => {
+                connected[index].store(is_connected, Ordering::Relaxed);
+                lost |= !is_connected;
+                if is_connected && !hook_registered {
+                    register_hook(sink, hook_profiles.clone(), connected.clone(), hook_sender.clone());
+                    hook_registered = true;
+                }
+                if !is_connected && active == Some(index) {
+                    // A new hook starts on the local side, which gives the user back control over the local pc
+                    active = None;
+                    register_hook(sink, hook_profiles.clone(), connected.clone(), hook_sender.clone());
+                }
+                show_state(sink, &targets, &connected, active, options.reconnect && lost);
+            },
+            Some(event) = hook_events.recv() => match event {
+                HookEvent::Captured(target) => {
+                    if let Some(previous) = active.take() {
+                        let _ = targets[previous].events.send(HookEvent::Captured(None));
+                    }
+                    match target {
+                        Some(index) if !connected[index].load(Ordering::Relaxed) => {
+                            tracing::warn!("Can not switch to {} until it is connected", targets[index].name);
+                            register_hook(sink, hook_profiles.clone(), connected.clone(), hook_sender.clone());
+                        }
+                        Some(index) => {
+                            let _ = targets[index].events.send(HookEvent::Captured(Some(index)));
+                            active = Some(index);
+                            focused = index;
+                        }
+                        None => {}
+                    }
+                    show_state(sink, &targets, &connected, active, options.reconnect && lost);
+                }
+                HookEvent::Input(event) => {
+                    if let Some(index) = active {
+                        let _ = targets[index].events.send(HookEvent::Input(event));
+                    }
+                }
+                HookEvent::Reset => {
+                    let _ = targets[active.unwrap_or(focused)].events.send(HookEvent::Reset);
+                }
+            },
+            cmd = controller.recv() => match cmd {
+                None => break Err(eyre!("control channel got removed")),
+                Some(ConnectionCommand::Disconnect) => break Ok(()),
+                Some(cmd) => {
+                    if targets[focused].commands.send(cmd).is_err() {
+                        tracing::warn!("{} is no longer connected", targets[focused].name);
+                    }
+                }
+            },
+            Some(finished) = tasks.join_next() => {
+                let (index, result) = finished?;
+                // The tasks only finish on their own when they fail
+                if let Err(err) = result {
+                    if tasks.is_empty() {
+                        break Err(err);
+                    }
+                    tracing::warn!("Gave up on {}: {:?}", targets[index].name, err);
+                    let msg = format!("Gave up on {}: {}", targets[index].name, err);
+                    sink.add_idle_callback(move |data: &mut AppState| data.server_status = Some(msg));
                 }
             }
         }
     };
-    let connection: Connection = select! {
-        conn = connect(host) => conn?,
-        res = wait => return res
-    };
-    tracing::debug!("Connected to {}", connection.remote_address());
-
-    if info {
-        spawn(collect_network_info(connection.clone(), sink.clone()));
+    for target in &targets {
+        let _ = target.commands.send(ConnectionCommand::Disconnect);
     }
+    while tasks.join_next().await.is_some() {}
+    result
+}
 
-    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+/// Replaces the current hook, if any, which also releases the input
+fn register_hook(sink: &ExtEventSink, profiles: Vec<Profile>, connected: Arc<[AtomicBool]>, sender: UnboundedSender<HookEvent>) {
     sink.add_rt_callback(|rt, data| {
         tracing::info_span!("register input hook").in_scope(move || {
-            if rt.hook.is_some() {
-                tracing::warn!("Hook already exists");
-            }
-            rt.hook = InputHook::register(hook::create_callback(&data.config, sender))
+            // Only one hook can exist at a time
+            rt.hook = None;
+            rt.hook = InputHook::register(hook::create_callback(&data.config, profiles, connected, sender))
                 .map_err(|err| tracing::warn!("Failed to register hook: {}", err))
                 .ok();
-            data.enable_shutdown = true;
         })
     });
+}
 
-    let mut sender = InputSender::new(1.0);
+fn show_state(sink: &ExtEventSink, targets: &[Target], connected: &[AtomicBool], active: Option<usize>, reconnecting: bool) {
+    let connected = connected.iter().any(|connected| connected.load(Ordering::Relaxed));
+    let state = match active {
+        Some(index) => ConnectionState::Connected(Side::Remote(targets[index].name.clone())),
+        None if connected => ConnectionState::Connected(Side::Local),
+        None if reconnecting => ConnectionState::Reconnecting,
+        None => ConnectionState::Connecting
+    };
+    sink.add_idle_callback(move |data: &mut AppState| {
+        data.connection_state = state;
+        data.enable_shutdown = connected;
+        if !connected {
+            data.network_info = None;
+            data.typing = None;
+        }
+    });
+}
+
+/// The delay before the first reconnection attempt, which doubles with every failed attempt
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Keeps the connection to a single server and reports to `updates` whenever it connects or disconnects.
+/// With `reconnect` a connection that got lost after it was established is retried until the user cancels.
+#[instrument(skip(sink, controller, events, updates, profile), fields(profile = %profile.name))]
+async fn target_connection(
+    sink: &ExtEventSink, mut controller: UnboundedReceiver<ConnectionCommand>, mut events: UnboundedReceiver<HookEvent>,
+    updates: UnboundedSender<(usize, bool)>, index: usize, profile: &Profile, options: ConnectionOptions
+) -> eyre::Result<()> {
+    let mut retry = None;
+    loop {
+        let connection = select! {
+            conn = connect(&profile.host_address, profile.pinned_fingerprint()) => conn,
+            res = wait_for_disconnect(&mut controller) => return res
+        };
+        let result = match connection {
+            Ok(connection) => {
+                let reconnected = retry.is_some();
+                retry = options.reconnect.then_some(RECONNECT_DELAY);
+                let speed = profile.mouse_speed_factor as f32;
+                let _ = updates.send((index, true));
+                let result = session(sink, &mut controller, &mut events, connection, options, speed, reconnected).await;
+                let _ = updates.send((index, false));
+                result
+            }
+            Err(err) => Err(err)
+        };
+        let delay = match (result, retry) {
+            (Err(err), Some(delay)) => {
+                tracing::warn!("Lost the connection: {:?}", err);
+                delay
+            }
+            (result, _) => return result
+        };
+        tracing::debug!("Reconnecting in {:?}", delay);
+        select! {
+            _ = tokio::time::sleep(delay) => retry = Some((delay * 2).min(MAX_RECONNECT_DELAY)),
+            res = wait_for_disconnect(&mut controller) => return res
+        }
+    }
+}
+
+/// Handles the commands while there is no connection and returns once the user cancels
+async fn wait_for_disconnect(controller: &mut UnboundedReceiver<ConnectionCommand>) -> eyre::Result<()> {
+    loop {
+        match controller.recv().await {
+            None => return Err(eyre!("control channel closed")),
+            Some(ConnectionCommand::ShutdownServer) => tracing::warn!("Can not send a shutdown signal until connected"),
+            Some(ConnectionCommand::PushFile(_)) => tracing::warn!("Can not push a file until connected"),
+            Some(ConnectionCommand::TypeText(_)) => tracing::warn!("Can not type text until connected"),
+            Some(ConnectionCommand::CancelTyping) => {}
+            Some(ConnectionCommand::Disconnect) => {
+                tracing::debug!("Canceling connection");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Forwards the input until the user disconnects or the connection fails
+#[instrument(skip(sink, controller, events, connection))]
+async fn session(
+    sink: &ExtEventSink, controller: &mut UnboundedReceiver<ConnectionCommand>, events: &mut UnboundedReceiver<HookEvent>, connection: Connection,
+    options: ConnectionOptions, mouse_speed_factor: f32, reconnected: bool
+) -> eyre::Result<()> {
+    tracing::debug!("Connected to {}", connection.remote_address());
+    let (mut control, mut control_messages) = open_control(&connection).await?;
+
+    if options.info {
+        spawn(collect_network_info(connection.clone(), sink.clone()));
+    }
+
+    // Input is only routed to connected targets, so anything left over is outdated
+    while events.try_recv().is_ok() {}
+
+    let mut sender = InputSender::new(mouse_speed_factor, QueuePolicy::default());
+    if reconnected {
+        // Releases everything that was held when the connection got lost
+        sender.reset();
+    }
     let mut deadline = None;
+    let mut captured = false;
+    let mut forwarder = GamepadForwarder::default();
+    let mut typing: Option<oneshot::Sender<()>> = None;
+    let mut gamepad_poll = interval(Duration::from_secs_f32(1.0 / options.rate));
+    gamepad_poll.set_missed_tick_behavior(MissedTickBehavior::Skip);
     loop {
         let timeout = async move {
             match deadline {
@@ -117,20 +335,56 @@ async fn connection(
                 let datagram: Bytes = datagram?;
                 sender.read_packet(&datagram)?;
             },
-            event = receiver.recv() => match event {
-                Some(event) => process_hook_event(&mut sender, sink, event),
+            event = events.recv() => match event {
+                Some(event) => {
+                    if let HookEvent::Captured(target) = event {
+                        captured = target.is_some();
+                        forwarder.reset();
+                    }
+                    process_hook_event(&mut sender, event)
+                },
                 None => return Err(eyre!("Input hook got removed"))
             },
+            _ = gamepad_poll.tick(), if options.gamepad && captured => forwarder.poll(&mut sender),
+            msg = control_messages.recv() => match msg {
+                Some(ControlMessage::Status(status)) => {
+                    tracing::info!("The server reported {:?}", status);
+                    sink.add_idle_callback(move |data: &mut AppState| data.server_status = Some(describe_status(status).to_string()));
+                }
+                Some(ControlMessage::Error(msg)) => {
+                    tracing::warn!("The server reported an error: {}", msg);
+                    sink.add_idle_callback(move |data: &mut AppState| data.server_status = Some(msg));
+                }
+                Some(msg) => tracing::warn!("Unexpected control message: {:?}", msg),
+                None => return Err(eyre!("The control stream was closed"))
+            },
             cmd = controller.recv() => match cmd {
                 None => return Err(eyre!("control channel got removed")),
                 Some(ConnectionCommand::Disconnect) => break,
-                Some(ConnectionCommand::ShutdownServer) => sender.shutdown_remote()
+                Some(ConnectionCommand::ShutdownServer) => control.write_all(&ControlMessage::Shutdown.to_frame()).await?,
+                Some(ConnectionCommand::PushFile(path)) => {
+                    spawn(push_file(connection.clone(), path));
+                }
+                Some(ConnectionCommand::TypeText(text)) => {
+                    let (cancel, canceled) = oneshot::channel();
+                    if let Some(previous) = typing.replace(cancel) {
+                        let _ = previous.send(());
+                    }
+                    spawn(type_text(connection.clone(), sink.clone(), text, canceled));
+                }
+                Some(ConnectionCommand::CancelTyping) => {
+                    if let Some(cancel) = typing.take() {
+                        let _ = cancel.send(());
+                    }
+                }
             },
             _ = timeout => {
-                let msg = sender.write_packet()?;
-                debug_assert!(msg.len() <= connection.max_datagram_size().unwrap());
+                let max_size = connection
+                    .max_datagram_size()
+                    .ok_or_else(|| eyre!("The server does not accept datagrams"))?;
+                let msg = sender.write_packet(max_size)?;
                 connection.send_datagram(Bytes::copy_from_slice(msg))?;
-                deadline = Some(Instant::now() + Duration::from_secs_f32(1.0 / rate));
+                deadline = Some(Instant::now() + Duration::from_secs_f32(1.0 / options.rate));
                 //tracing::debug!("stats: {:#?}", connection.stats().path);
             }
         };
@@ -146,11 +400,154 @@ async fn connection(
     Ok(())
 }
 
+/// Performs the handshake and forwards all further messages of the server
+#[instrument(skip_all)]
+async fn open_control(connection: &Connection) -> eyre::Result<(SendStream, UnboundedReceiver<ControlMessage>)> {
+    let (mut send, mut recv) = connection.open_bi().await?;
+    send.write_all(&[StreamType::Control.into()]).await?;
+    send.write_all(&ControlMessage::Hello { protocol: PROTOCOL_VERSION }.to_frame())
+        .await?;
+    match read_control_message(&mut recv).await? {
+        Some(ControlMessage::Hello { protocol }) => tracing::debug!("Server uses protocol version {}", protocol),
+        Some(ControlMessage::Error(msg)) => bail!("The server refused the connection: {}", msg),
+        Some(msg) => bail!("Unexpected handshake: {:?}", msg),
+        None => bail!("The server closed the control stream. Is it outdated?")
+    }
+    let (sender, receiver) = unbounded_channel();
+    spawn(async move {
+        loop {
+            match read_control_message(&mut recv).await {
+                Ok(Some(msg)) => {
+                    if sender.send(msg).is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    tracing::warn!("Could not read from the control stream: {:?}", err);
+                    break;
+                }
+            }
+        }
+    });
+    Ok((send, receiver))
+}
+
+fn describe_status(status: DeviceStatus) -> &'static str {
+    match status {
+        DeviceStatus::HidWriteFailed => "The server can not send input to the target",
+        DeviceStatus::GadgetReconnected => "The target is connected again",
+        DeviceStatus::HostSuspended => "The target is suspended",
+        DeviceStatus::HostDisconnected => "The target is disconnected",
+        DeviceStatus::ProcessorRestarted => "The server recovered from an error"
+    }
+}
+
+/// Returns `None` when the stream is finished or the connection is gone
+async fn read_control_message(recv: &mut RecvStream) -> eyre::Result<Option<ControlMessage>> {
+    let len = match recv.read_u16_le().await {
+        Ok(len) => len,
+        Err(err) if matches!(err.kind(), ErrorKind::UnexpectedEof | ErrorKind::NotConnected) => return Ok(None),
+        Err(err) => return Err(err.into())
+    };
+    let mut payload = vec![0u8; len as usize];
+    recv.read_exact(&mut payload).await?;
+    ControlMessage::from_payload(&payload)
+        .map(Some)
+        .ok_or_else(|| eyre!("Invalid control message"))
+}
+
+#[instrument(skip(connection))]
+async fn push_file(connection: Connection, path: PathBuf) {
+    let result = async {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| eyre!("Invalid file name"))?;
+        ensure!(name.len() <= u16::MAX as usize, "The file name is too long");
+        let mut file = tokio::fs::File::open(&path).await?;
+        let (mut send, recv) = connection.open_bi().await?;
+        send.write_all(&[StreamType::PushStorageFile.into()]).await?;
+        send.write_all(&(name.len() as u16).to_le_bytes()).await?;
+        send.write_all(name.as_bytes()).await?;
+        tokio::io::copy(&mut file, &mut send).await?;
+        send.finish().await?;
+        let response = recv.read_to_end(u16::MAX as usize).await?;
+        match response.split_first() {
+            Some((0, _)) => Ok(()),
+            Some((_, msg)) => Err(eyre!("{}", String::from_utf8_lossy(msg))),
+            None => Err(eyre!("The server did not respond"))
+        }
+    };
+    match result.await {
+        Ok(()) => tracing::info!("Pushed {} to the mass storage device", path.display()),
+        Err(err) => tracing::warn!("Could not push {}: {:?}", path.display(), err)
+    }
+}
+
+/// Typing stops when `canceled` resolves, which includes dropping its sender
+#[instrument(skip_all)]
+async fn type_text(connection: Connection, sink: ExtEventSink, text: String, canceled: oneshot::Receiver<()>) {
+    let total = text.chars().count() as u32;
+    let update = |typed: Option<u32>| sink.add_idle_callback(move |data: &mut AppState| data.typing = typed.map(|typed| TypingProgress { typed, total }));
+    update(Some(0));
+    let result = async {
+        let (mut send, mut recv) = connection.open_bi().await?;
+        send.write_all(&[StreamType::TypeText.into()]).await?;
+        let canceled = {
+            let upload = async {
+                send.write_all(text.as_bytes()).await?;
+                send.finish().await?;
+                eyre::Ok(())
+            };
+            let progress = async {
+                let mut status = [0u8];
+                recv.read_exact(&mut status).await?;
+                if status[0] != 0 {
+                    let mut msg = Vec::new();
+                    AsyncReadExt::read_to_end(&mut recv, &mut msg).await?;
+                    return Err(eyre!("{}", String::from_utf8_lossy(&msg)));
+                }
+                let mut typed = [0u8; 4];
+                loop {
+                    match recv.read_exact(&mut typed).await {
+                        Ok(()) => update(Some(u32::from_le_bytes(typed))),
+                        Err(ReadExactError::FinishedEarly) => return Ok(()),
+                        Err(err) => return Err(err.into())
+                    }
+                }
+            };
+            select! {
+                (upload, progress) = async { tokio::join!(upload, progress) } => {
+                    progress.and(upload)?;
+                    false
+                },
+                _ = canceled => true
+            }
+        };
+        if canceled {
+            send.reset(0u8.into())?;
+            recv.stop(0u8.into())?;
+        }
+        eyre::Ok(canceled)
+    };
+    match result.await {
+        Ok(false) => tracing::info!("Typed {} characters", total),
+        Ok(true) => tracing::info!("Canceled typing"),
+        Err(err) => tracing::warn!("Could not type the text: {:?}", err)
+    }
+    update(None);
+}
+
 #[instrument]
-async fn connect(host: &str) -> eyre::Result<Connection> {
+async fn connect(host: &str, fingerprint: Option<&str>) -> eyre::Result<Connection> {
+    let verifier: Arc<dyn ServerCertVerifier> = match fingerprint {
+        Some(fingerprint) => PinnedServerVerification::new(fingerprint),
+        None => SkipServerVerification::new()
+    };
     let crypto = rustls::ClientConfig::builder()
         .with_safe_defaults()
-        .with_custom_certificate_verifier(SkipServerVerification::new())
+        .with_custom_certificate_verifier(verifier)
         .with_no_client_auth();
     let mut transport = TransportConfig::default();
     transport.keep_alive_interval(Some(Duration::from_secs(1)));
@@ -12,42 +12,181 @@ use yawi::VirtualKey;
 
 use crate::utils::keyset::VirtualKeySet;
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum ConnectionCommand {
     ShutdownServer,
+    PushFile(PathBuf),
+    TypeText(String),
+    CancelTyping,
     Disconnect
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Data, Lens)]
 pub struct Hotkey {
     pub modifiers: VirtualKeySet,
-    pub trigger: VirtualKey
+    pub trigger: VirtualKey,
+    /// The trigger has to be pressed twice in quick succession
+    #[serde(default)]
+    pub double_tap: bool
 }
 
 impl Hotkey {
     pub fn new<T: IntoIterator<Item = VirtualKey>>(modifiers: T, trigger: VirtualKey) -> Self {
         Self {
             modifiers: VirtualKeySet::from_iter(modifiers),
-            trigger
+            trigger,
+            double_tap: false
         }
     }
 }
 
+/// Hotkeys that work the same for every server
+#[derive(Debug, Default, Clone, Serialize, Deserialize, Data, Lens)]
+#[serde(default)]
+pub struct ActionHotkeys {
+    /// Switches to the server that received the input last
+    pub remote: Option<Hotkey>,
+    pub local: Option<Hotkey>,
+    /// Releases everything that is held on the server
+    pub reset: Option<Hotkey>,
+    /// Releases everything that is held on the local pc
+    pub release: Option<Hotkey>
+}
+
+/// The side of the local screen that borders the remote screen
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, Data)]
+pub enum ScreenEdge {
+    #[default]
+    None,
+    Left,
+    Right,
+    Top,
+    Bottom
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, Data, Lens)]
+pub struct Resolution {
+    pub width: u32,
+    pub height: u32
+}
+
+impl Default for Resolution {
+    fn default() -> Self {
+        Self { width: 1920, height: 1080 }
+    }
+}
+
+/// The settings that differ between the servers
 #[derive(Debug, Clone, Serialize, Deserialize, Data, Lens)]
-pub struct Config {
+pub struct Profile {
+    pub name: String,
     pub host_address: String,
+    /// Only a server with a certificate that has this SHA-256 fingerprint is accepted. Any server is accepted if empty.
+    #[serde(default)]
+    pub fingerprint: String,
+    pub mouse_speed_factor: f64,
+    /// Switches to this server. Servers that share a hotkey are cycled through.
     pub hotkey: Hotkey,
+    /// Connect to this server together with the other enabled ones
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    /// Switches to this server when the cursor hits this edge of the local screen
+    #[serde(default)]
+    pub screen_edge: ScreenEdge,
+    /// Used to tell when the cursor leaves the remote screen again
+    #[serde(default)]
+    pub remote_resolution: Resolution
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            name: "Default".to_string(),
+            host_address: "localhost:12345".to_string(),
+            fingerprint: String::new(),
+            mouse_speed_factor: 1.0,
+            hotkey: Hotkey::new([VirtualKey::LControl], VirtualKey::Tab),
+            enabled: true,
+            screen_edge: ScreenEdge::None,
+            remote_resolution: Resolution::default()
+        }
+    }
+}
+
+impl Profile {
+    pub fn pinned_fingerprint(&self) -> Option<&str> {
+        Some(self.fingerprint.trim()).filter(|fingerprint| !fingerprint.is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Data, Lens)]
+pub struct Config {
+    pub profiles: Vector<Profile>,
+    pub selected_profile: usize,
     pub blacklist: VirtualKeySet,
     pub show_network_info: bool,
     pub network_send_rate: u32,
-    pub mouse_speed_factor: f64
+    #[serde(default)]
+    pub forward_gamepad: bool,
+    #[serde(default)]
+    pub auto_reconnect: bool,
+    /// The distance from the corners of the screen in which the screen edges don't switch
+    #[serde(default)]
+    pub edge_dead_zone: u32,
+    /// Holding any of these keys keeps the cursor on the current screen
+    #[serde(default)]
+    pub edge_lock: VirtualKeySet,
+    #[serde(default)]
+    pub actions: ActionHotkeys
+}
+
+/// The layout before the profiles, which kept the settings of the only server at the top level
+#[derive(Deserialize)]
+struct LegacyConfig {
+    host_address: String,
+    hotkey: Hotkey,
+    blacklist: VirtualKeySet,
+    show_network_info: bool,
+    network_send_rate: u32,
+    mouse_speed_factor: f64,
+    #[serde(default)]
+    forward_gamepad: bool,
+    #[serde(default)]
+    auto_reconnect: bool
+}
+
+impl From<LegacyConfig> for Config {
+    fn from(legacy: LegacyConfig) -> Self {
+        let profile = Profile {
+            host_address: legacy.host_address,
+            mouse_speed_factor: legacy.mouse_speed_factor,
+            hotkey: legacy.hotkey,
+            ..Default::default()
+        };
+        Self {
+            profiles: Vector::unit(profile),
+            selected_profile: 0,
+            blacklist: legacy.blacklist,
+            show_network_info: legacy.show_network_info,
+            network_send_rate: legacy.network_send_rate,
+            forward_gamepad: legacy.forward_gamepad,
+            auto_reconnect: legacy.auto_reconnect,
+            edge_dead_zone: 0,
+            edge_lock: VirtualKeySet::new(),
+            actions: ActionHotkeys::default()
+        }
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
-            host_address: "localhost:12345".to_string(),
-            hotkey: Hotkey::new([VirtualKey::LControl], VirtualKey::Tab),
+            profiles: Vector::unit(Profile::default()),
+            selected_profile: 0,
             blacklist: VirtualKeySet::from_iter([
                 VirtualKey::VolumeDown,
                 VirtualKey::VolumeUp,
@@ -59,7 +198,11 @@ impl Default for Config {
             ]),
             show_network_info: false,
             network_send_rate: 100,
-            mouse_speed_factor: 1.0
+            forward_gamepad: false,
+            auto_reconnect: false,
+            edge_dead_zone: 0,
+            edge_lock: VirtualKeySet::new(),
+            actions: ActionHotkeys::default()
         }
     }
 }
@@ -76,10 +219,21 @@ impl Config {
 
     pub fn load() -> eyre::Result<Self> {
         let path = Self::path();
-        let config: Self = match path.exists() {
+        let mut config: Self = match path.exists() {
             true => {
                 let file = std::fs::read_to_string(path)?;
-                ron::from_str(&file)?
+                match ron::from_str(&file) {
+                    Ok(config) => config,
+                    Err(err) => match ron::from_str::<LegacyConfig>(&file) {
+                        Ok(legacy) => {
+                            tracing::info!("Moving the server settings into a default profile");
+                            let conf = Self::from(legacy);
+                            conf.save()?;
+                            conf
+                        }
+                        Err(_) => return Err(err.into())
+                    }
+                }
             }
             false => {
                 let conf = Self::default();
@@ -87,6 +241,10 @@ impl Config {
                 conf
             }
         };
+        if config.profiles.is_empty() {
+            config.profiles.push_back(Profile::default());
+        }
+        config.selected_profile = config.selected_profile.min(config.profiles.len() - 1);
         Ok(config)
     }
 
@@ -94,18 +252,54 @@ impl Config {
         let pretty = PrettyConfig::new();
         Ok(std::fs::write(Self::path(), to_string_pretty(self, pretty)?)?)
     }
+
+    pub fn profile(&self) -> &Profile {
+        &self.profiles[self.selected_profile]
+    }
+
+    pub fn profile_mut(&mut self) -> &mut Profile {
+        &mut self.profiles[self.selected_profile]
+    }
+
+    /// Selects the next or previous profile and wraps around at the ends
+    pub fn cycle_profile(&mut self, forward: bool) {
+        let len = self.profiles.len();
+        self.selected_profile = match forward {
+            true => (self.selected_profile + 1) % len,
+            false => (self.selected_profile + len - 1) % len
+        };
+    }
+
+    /// Adds a copy of the selected profile and selects it
+    pub fn add_profile(&mut self) {
+        let mut profile = self.profile().clone();
+        profile.name = format!("Profile {}", self.profiles.len() + 1);
+        self.profiles.push_back(profile);
+        self.selected_profile = self.profiles.len() - 1;
+    }
+
+    /// Removes the selected profile unless it is the last one
+    pub fn remove_profile(&mut self) {
+        if self.profiles.len() > 1 {
+            self.profiles.remove(self.selected_profile);
+            self.selected_profile = self.selected_profile.min(self.profiles.len() - 1);
+        }
+    }
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Data)]
+#[derive(Debug, Clone, Eq, PartialEq, Data)]
 pub enum Side {
     Local,
-    Remote
+    /// The name of the profile that receives the input
+    Remote(String)
 }
 
-#[derive(Default, Debug, Copy, Clone, Eq, PartialEq, Data)]
+#[derive(Default, Debug, Clone, Eq, PartialEq, Data)]
 pub enum ConnectionState {
     Connected(Side),
     Connecting,
+    /// Lost the connection and waits for the next attempt
+    Reconnecting,
     #[default]
     Disconnected
 }
@@ -133,11 +327,20 @@ pub struct NetworkInfo {
     pub recent_loss_rate: f64
 }
 
+#[derive(Default, Debug, Copy, Clone, Eq, PartialEq, Data)]
+pub struct TypingProgress {
+    pub typed: u32,
+    pub total: u32
+}
+
 #[derive(Default, Debug, Clone, Data, Lens)]
 pub struct AppState {
     pub config: Config,
     pub connection_state: ConnectionState,
     pub enable_shutdown: bool,
     pub network_info: Option<NetworkInfo>,
+    pub typing: Option<TypingProgress>,
+    /// The latest status or error reported by the server
+    pub server_status: Option<String>,
     pub popup: Option<PopupType>
 }
@@ -1,5 +1,6 @@
 use std::cell::Cell;
 
+use druid::commands::OPEN_FILE;
 use druid::{AppDelegate, Command, DelegateCtx, Env, EventCtx, ExtEventSink, Handled, Selector, Target};
 use mdns_sd::ServiceDaemon;
 use tokio::runtime::{Builder, Runtime};
@@ -53,6 +54,13 @@ impl RuntimeDelegate {
 
 impl AppDelegate<AppState> for RuntimeDelegate {
     fn command(&mut self, _: &mut DelegateCtx, _target: Target, cmd: &Command, data: &mut AppState, _env: &Env) -> Handled {
+        if let Some(file) = cmd.get(OPEN_FILE) {
+            self.connection
+                .as_ref()
+                .and_then(|sender| sender.send(ConnectionCommand::PushFile(file.path().to_path_buf())).ok())
+                .unwrap_or_else(|| tracing::warn!("Failed to send file!"));
+            return Handled::Yes;
+        }
         match cmd.get(CALLBACK) {
             Some(callback) => {
                 if let Some(callback) = callback.take() {
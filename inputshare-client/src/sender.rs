@@ -1,33 +1,73 @@
 use std::collections::VecDeque;
-use std::io::{Result, Write};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use inputshare_common::{ConsumerDeviceCode, HidButtonCode, HidKeyCode, MessageType, MouseType, Vec2};
+use inputshare_common::{
+    is_newer_sequence, AckPacket, ConsumerDeviceCode, GamepadAxis, GamepadButton, GamepadHat, HidButtonCode, HidKeyCode, InputHeader, MessageType,
+    MouseType, SystemControlCode, Vec2
+};
+
+const MESSAGE_SIZE: usize = 2;
+
+/// Decides what happens to messages that the server doesn't acknowledge in time
+#[derive(Debug, Copy, Clone)]
+pub struct QueuePolicy {
+    /// The most messages that wait for the server. The oldest unsent ones get dropped beyond that.
+    pub max_messages: usize,
+    /// Messages that waited longer than this are dropped instead of replayed when the connection recovers
+    pub max_age: Duration
+}
+
+impl Default for QueuePolicy {
+    fn default() -> Self {
+        Self {
+            max_messages: 1024,
+            max_age: Duration::from_secs(2)
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct QueuedMessage {
+    message: [u8; 2],
+    queued: Instant
+}
 
 #[derive(Debug)]
 pub struct InputSender {
+    /// Lets the server tell this sender apart from earlier ones that reused the sequence numbers
+    session: u32,
     local_sequence: u64,
     remote_sequence: u64,
+    /// The packet whose mouse position the server acknowledged last
+    acked_sequence: u64,
     packet_buffer: Vec<u8>,
     local_mouse_pos: Vec2<MouseType>,
     local_mouse_pos_raw: Vec2<MouseType>,
     mouse_speed_factor: f64,
     remote_mouse_pos: Vec2<MouseType>,
-    message_queue: VecDeque<[u8; 2]>,
+    message_queue: VecDeque<QueuedMessage>,
+    /// How many messages at the front of the queue were part of a packet
+    sent_messages: usize,
+    policy: QueuePolicy,
     last_message: u64
 }
 
 impl InputSender {
-    pub fn new(mouse_speed_factor: f32) -> Self {
+    pub fn new(mouse_speed_factor: f32, policy: QueuePolicy) -> Self {
         Self {
+            session: new_session(),
             local_sequence: 1,
             remote_sequence: 0,
+            acked_sequence: 0,
             packet_buffer: Vec::new(),
             local_mouse_pos: Vec2::new(0, 0),
             local_mouse_pos_raw: Vec2::new(0, 0),
             mouse_speed_factor: mouse_speed_factor.into(),
             remote_mouse_pos: Vec2::new(0, 0),
             message_queue: VecDeque::new(),
+            sent_messages: 0,
+            policy,
             last_message: 0
         }
     }
@@ -44,53 +84,82 @@ impl InputSender {
         }
     }
 
-    pub fn shutdown_remote(&mut self) {
-        self.message_queue
-            .push_back([MessageType::Shutdown.into(), 0])
-    }
-
+    /// Stays in the datagrams because it has to be ordered with the key events that it releases
     pub fn reset(&mut self) {
-        self.message_queue.push_back([MessageType::Reset.into(), 0])
+        self.push_message(reset_message())
     }
 
     pub fn press_key(&mut self, key: HidKeyCode) {
-        self.message_queue
-            .push_back([MessageType::KeyPress.into(), key.into()])
+        self.push_message([MessageType::KeyPress.into(), key.into()])
     }
 
     pub fn release_key(&mut self, key: HidKeyCode) {
-        self.message_queue
-            .push_back([MessageType::KeyRelease.into(), key.into()])
+        self.push_message([MessageType::KeyRelease.into(), key.into()])
     }
 
     pub fn press_mouse_button(&mut self, button: HidButtonCode) {
-        self.message_queue
-            .push_back([MessageType::MouseButtonPress.into(), button.into()])
+        self.push_message([MessageType::MouseButtonPress.into(), button.into()])
     }
 
     pub fn release_mouse_button(&mut self, button: HidButtonCode) {
-        self.message_queue
-            .push_back([MessageType::MouseButtonRelease.into(), button.into()])
+        self.push_message([MessageType::MouseButtonRelease.into(), button.into()])
     }
 
     pub fn press_consumer_device(&mut self, button: ConsumerDeviceCode) {
-        self.message_queue
-            .push_back([MessageType::ConsumerDevicePress.into(), button.into()])
+        self.push_message([MessageType::ConsumerDevicePress.into(), button.into()])
     }
 
     pub fn release_consumer_device(&mut self, button: ConsumerDeviceCode) {
-        self.message_queue
-            .push_back([MessageType::ConsumerDeviceRelease.into(), button.into()])
+        self.push_message([MessageType::ConsumerDeviceRelease.into(), button.into()])
+    }
+
+    pub fn press_system_control(&mut self, button: SystemControlCode) {
+        self.push_message([MessageType::SystemControlPress.into(), button.into()])
+    }
+
+    pub fn release_system_control(&mut self, button: SystemControlCode) {
+        self.push_message([MessageType::SystemControlRelease.into(), button.into()])
+    }
+
+    pub fn press_gamepad_button(&mut self, button: GamepadButton) {
+        self.push_message([MessageType::GamepadButtonPress.into(), button.into()])
+    }
+
+    pub fn release_gamepad_button(&mut self, button: GamepadButton) {
+        self.push_message([MessageType::GamepadButtonRelease.into(), button.into()])
     }
 
-    pub fn scroll_horizontal(&mut self, amount: i8) {
-        self.message_queue
-            .push_back([MessageType::HorizontalScrolling.into(), amount as u8])
+    pub fn set_gamepad_hat(&mut self, hat: GamepadHat) {
+        self.push_message([MessageType::GamepadHat.into(), hat.into()])
     }
 
-    pub fn scroll_vertical(&mut self, amount: i8) {
-        self.message_queue
-            .push_back([MessageType::VerticalScrolling.into(), amount as u8])
+    pub fn set_gamepad_axis(&mut self, axis: GamepadAxis, value: u8) {
+        self.push_message([axis.message_type().into(), value])
+    }
+
+    /// Scrolls by `amount` high resolution units (see `SCROLL_RESOLUTION`)
+    pub fn scroll_horizontal(&mut self, amount: i32) {
+        self.push_scroll(MessageType::HighResHorizontalScrolling, amount)
+    }
+
+    /// Scrolls by `amount` high resolution units (see `SCROLL_RESOLUTION`)
+    pub fn scroll_vertical(&mut self, amount: i32) {
+        self.push_scroll(MessageType::HighResVerticalScrolling, amount)
+    }
+
+    fn push_message(&mut self, message: [u8; 2]) {
+        self.message_queue.push_back(QueuedMessage {
+            message,
+            queued: Instant::now()
+        });
+    }
+
+    fn push_scroll(&mut self, message: MessageType, mut amount: i32) {
+        while amount != 0 {
+            let chunk = amount.clamp(i8::MIN.into(), i8::MAX.into());
+            self.push_message([message.into(), chunk as i8 as u8]);
+            amount -= chunk;
+        }
     }
 
     pub fn in_sync(&self) -> bool {
@@ -98,39 +167,306 @@ impl InputSender {
     }
 
     pub fn read_packet(&mut self, mut packet: &[u8]) -> Result<()> {
-        let sequence = packet.read_u64::<LittleEndian>()?;
-        if sequence <= self.remote_sequence {
+        let ack = AckPacket::read(&mut packet).ok_or(ErrorKind::UnexpectedEof)?;
+        if ack.session != self.session {
+            tracing::debug!("Ignoring a packet of another session");
             return Ok(());
         }
-        self.remote_sequence = sequence;
-        self.remote_mouse_pos.x = packet.read_i64::<LittleEndian>()?;
-        self.remote_mouse_pos.y = packet.read_i64::<LittleEndian>()?;
-        let received_index = packet.read_u64::<LittleEndian>()?;
-        let diff = received_index.saturating_sub(self.last_message);
+        if !is_newer_sequence(ack.sequence, self.remote_sequence) {
+            return Ok(());
+        }
+        self.remote_sequence = ack.sequence;
+        self.acked_sequence = ack.applied_sequence;
+        self.remote_mouse_pos = Vec2::new(ack.mouse_x, ack.mouse_y);
+        let diff = ack.last_message.saturating_sub(self.last_message);
         self.message_queue.drain(..(diff as usize));
-        self.last_message = received_index;
+        self.sent_messages = self.sent_messages.saturating_sub(diff as usize);
+        self.last_message = ack.last_message;
 
         Ok(())
     }
 
-    pub fn write_packet(&mut self) -> Result<&[u8]> {
+    /// Writes a packet of at most `max_size` bytes. Messages that don't fit follow once the server acknowledged the earlier ones.
+    pub fn write_packet(&mut self, max_size: usize) -> Result<&[u8]> {
+        self.trim_queue(Instant::now());
+        let mut header = InputHeader {
+            session: self.session,
+            sequence: self.local_sequence,
+            base_distance: self.local_sequence.wrapping_sub(self.acked_sequence),
+            mouse_x: self.local_mouse_pos.x - self.remote_mouse_pos.x,
+            mouse_y: self.local_mouse_pos.y - self.remote_mouse_pos.y,
+            start_message: self.last_message,
+            message_count: 0
+        };
+        self.packet_buffer.clear();
+        header.write(&mut self.packet_buffer);
+        // Without room for a single message the queue would never make progress
+        let required = match self.message_queue.is_empty() {
+            true => self.packet_buffer.len(),
+            false => self.packet_buffer.len() + MESSAGE_SIZE
+        };
+        if required > max_size {
+            return Err(Error::new(ErrorKind::InvalidInput, "The maximum packet size is too small"));
+        }
+        let message_count = self
+            .message_queue
+            .len()
+            .min(u8::MAX as usize)
+            .min((max_size - self.packet_buffer.len()) / MESSAGE_SIZE);
+        header.message_count = message_count as u8;
         self.packet_buffer.clear();
-        self.packet_buffer
-            .write_u64::<LittleEndian>(self.local_sequence)?;
-        self.packet_buffer
-            .write_i64::<LittleEndian>(self.local_mouse_pos.x)?;
-        self.packet_buffer
-            .write_i64::<LittleEndian>(self.local_mouse_pos.y)?;
-        self.packet_buffer
-            .write_u64::<LittleEndian>(self.last_message)?;
-        let size_index = self.packet_buffer.len();
-        self.packet_buffer.write_u8(0)?;
-        for i in 0..usize::min(self.message_queue.len(), u8::MAX as usize) {
-            self.packet_buffer[size_index] += 1;
-            self.packet_buffer
-                .write_all(self.message_queue.get(i).unwrap())?;
+        header.write(&mut self.packet_buffer);
+        for message in self.message_queue.iter().take(message_count) {
+            self.packet_buffer.write_all(&message.message)?;
         }
-        self.local_sequence += 1;
+        self.sent_messages = self.sent_messages.max(message_count);
+        self.local_sequence = self.local_sequence.wrapping_add(1);
         Ok(self.packet_buffer.as_slice())
     }
+
+    /// Applies the `QueuePolicy`. The server may have applied messages that were sent already, so they have to keep
+    /// their index and stale ones can only be replaced by resets. Unsent messages can be removed freely.
+    fn trim_queue(&mut self, now: Instant) {
+        let max_age = self.policy.max_age;
+        let is_stale = |msg: &QueuedMessage| now.saturating_duration_since(msg.queued) > max_age;
+        // This runs for every packet, so the queue is only rebuilt when something has to go
+        let needs_trim = self.message_queue.len() > self.policy.max_messages
+            || self
+                .message_queue
+                .iter()
+                .enumerate()
+                .any(|(i, msg)| is_stale(msg) && (i >= self.sent_messages || msg.message != reset_message()));
+        if !needs_trim {
+            return;
+        }
+
+        let mut dropped = 0;
+        for msg in self.message_queue.iter_mut().take(self.sent_messages) {
+            if is_stale(msg) && msg.message != reset_message() {
+                msg.message = reset_message();
+                dropped += 1;
+            }
+        }
+
+        let mut unsent: Vec<Option<QueuedMessage>> = self
+            .message_queue
+            .drain(self.sent_messages..)
+            .map(Some)
+            .collect();
+        // A press that is released again before it was sent changes nothing
+        let mut collapsed = 0;
+        for i in 0..unsent.len() {
+            let Some(release) = unsent[i].filter(is_stale).and_then(|msg| release_message(msg.message)) else {
+                continue;
+            };
+            if let Some(j) = (i + 1..unsent.len()).find(|&j| unsent[j].is_some_and(|msg| msg.message == release)) {
+                unsent[i] = None;
+                unsent[j] = None;
+                collapsed += 2;
+            }
+        }
+        let mut unsent: VecDeque<QueuedMessage> = unsent
+            .into_iter()
+            .flatten()
+            .collect();
+        let len = unsent.len();
+        unsent.retain(|msg| !is_stale(msg));
+        dropped += len - unsent.len();
+        // Leaves room for the reset
+        let room = self
+            .policy
+            .max_messages
+            .saturating_sub(self.message_queue.len() + 1);
+        if unsent.len() > room {
+            dropped += unsent.len() - room;
+            unsent.drain(..(unsent.len() - room));
+        }
+        if dropped > 0 {
+            tracing::debug!("Dropped {} and collapsed {} messages that could not be delivered in time", dropped, collapsed);
+            unsent.push_front(QueuedMessage {
+                message: reset_message(),
+                queued: now
+            });
+        } else if collapsed > 0 {
+            tracing::debug!("Collapsed {} messages that could not be delivered in time", collapsed);
+        }
+        self.message_queue.extend(unsent);
+    }
+}
+
+fn reset_message() -> [u8; 2] {
+    [MessageType::Reset.into(), 0]
+}
+
+/// The message that undoes a press
+fn release_message(message: [u8; 2]) -> Option<[u8; 2]> {
+    let release = match MessageType::try_from(message[0]).ok()? {
+        MessageType::KeyPress => MessageType::KeyRelease,
+        MessageType::MouseButtonPress => MessageType::MouseButtonRelease,
+        MessageType::ConsumerDevicePress => MessageType::ConsumerDeviceRelease,
+        MessageType::SystemControlPress => MessageType::SystemControlRelease,
+        MessageType::GamepadButtonPress => MessageType::GamepadButtonRelease,
+        _ => return None
+    };
+    Some([release.into(), message[1]])
+}
+
+/// Only has to differ from the previous session, so the current time is unique enough
+fn new_session() -> u32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.subsec_nanos() ^ time.as_secs() as u32)
+        .unwrap_or_default();
+    now.max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> impl Iterator<Item = HidKeyCode> {
+        (0..10).map(|i| HidKeyCode::from(HidKeyCode::KeyA as u8 + i))
+    }
+
+    /// Returns the header and acknowledges every message of the packet
+    fn deliver(sender: &mut InputSender, mut packet: &[u8]) -> (InputHeader, Vec<[u8; 2]>) {
+        let header = InputHeader::read(&mut packet).unwrap();
+        let messages = packet
+            .chunks(MESSAGE_SIZE)
+            .map(|message| [message[0], message[1]])
+            .collect::<Vec<_>>();
+        assert_eq!(messages.len(), header.message_count as usize);
+        let mut ack = Vec::new();
+        AckPacket {
+            session: header.session,
+            sequence: header.sequence,
+            applied_sequence: header.sequence,
+            last_message: header.start_message + header.message_count as u64,
+            ..Default::default()
+        }
+        .write(&mut ack);
+        sender.read_packet(&ack).unwrap();
+        (header, messages)
+    }
+
+    #[test]
+    fn small_packets_spill_messages_in_order() {
+        let mut sender = InputSender::new(1.0, QueuePolicy::default());
+        let header_size = sender.write_packet(usize::MAX).unwrap().len();
+        keys().for_each(|key| sender.press_key(key));
+        // Room for three messages and a spare byte
+        let max_size = header_size + 3 * MESSAGE_SIZE + 1;
+
+        let unacked = sender.write_packet(max_size).unwrap().to_vec();
+        let mut received = Vec::new();
+        while !sender.in_sync() {
+            let packet = sender.write_packet(max_size).unwrap().to_vec();
+            assert!(packet.len() <= max_size);
+            let (header, messages) = deliver(&mut sender, &packet);
+            assert_eq!(header.start_message, received.len() as u64);
+            assert!(messages.len() <= 3);
+            received.extend(messages);
+        }
+        let expected = keys()
+            .map(|key| [MessageType::KeyPress.into(), key.into()])
+            .collect::<Vec<_>>();
+        assert_eq!(received, expected);
+        // Without an ack the messages are sent again instead of the following ones
+        assert_eq!(unacked[header_size..], expected[..3].concat());
+    }
+
+    fn press(key: HidKeyCode) -> [u8; 2] {
+        [MessageType::KeyPress.into(), key.into()]
+    }
+
+    fn release(key: HidKeyCode) -> [u8; 2] {
+        [MessageType::KeyRelease.into(), key.into()]
+    }
+
+    fn queue(sender: &mut InputSender, message: [u8; 2], queued: Instant) {
+        sender.message_queue.push_back(QueuedMessage { message, queued });
+    }
+
+    fn queued(sender: &InputSender) -> Vec<[u8; 2]> {
+        sender
+            .message_queue
+            .iter()
+            .map(|msg| msg.message)
+            .collect()
+    }
+
+    #[test]
+    fn stale_presses_collapse_with_their_releases() {
+        let mut sender = InputSender::new(1.0, QueuePolicy::default());
+        let start = Instant::now();
+        queue(&mut sender, press(HidKeyCode::KeyA), start);
+        queue(&mut sender, press(HidKeyCode::KeyB), start + Duration::from_millis(1000));
+        queue(&mut sender, release(HidKeyCode::KeyA), start + Duration::from_millis(1500));
+        sender.trim_queue(start + Duration::from_millis(2500));
+        // Nothing was lost, so no reset is needed
+        assert_eq!(queued(&sender), [press(HidKeyCode::KeyB)]);
+    }
+
+    #[test]
+    fn stale_messages_are_replaced_by_a_reset() {
+        let mut sender = InputSender::new(1.0, QueuePolicy::default());
+        let start = Instant::now();
+        queue(&mut sender, press(HidKeyCode::KeyA), start);
+        queue(&mut sender, press(HidKeyCode::KeyB), start + Duration::from_millis(1000));
+        sender.trim_queue(start + Duration::from_millis(1500));
+        assert_eq!(queued(&sender), [press(HidKeyCode::KeyA), press(HidKeyCode::KeyB)]);
+        sender.trim_queue(start + Duration::from_millis(2500));
+        assert_eq!(queued(&sender), [reset_message(), press(HidKeyCode::KeyB)]);
+    }
+
+    #[test]
+    fn unsent_messages_are_bounded() {
+        let policy = QueuePolicy {
+            max_messages: 4,
+            ..Default::default()
+        };
+        let mut sender = InputSender::new(1.0, policy);
+        let start = Instant::now();
+        keys().take(4).for_each(|key| queue(&mut sender, press(key), start));
+        sender.trim_queue(start);
+        assert_eq!(queued(&sender), keys().take(4).map(press).collect::<Vec<_>>());
+        keys().skip(4).take(2).for_each(|key| queue(&mut sender, press(key), start));
+        sender.trim_queue(start);
+        // The oldest ones make room for the reset
+        let expected = [reset_message()]
+            .into_iter()
+            .chain(keys().skip(3).take(3).map(press))
+            .collect::<Vec<_>>();
+        assert_eq!(queued(&sender), expected);
+    }
+
+    #[test]
+    fn sent_messages_keep_their_positions() {
+        let mut sender = InputSender::new(1.0, QueuePolicy::default());
+        let start = Instant::now();
+        sender.press_key(HidKeyCode::KeyA);
+        sender.release_key(HidKeyCode::KeyA);
+        let packet = sender.write_packet(usize::MAX).unwrap().to_vec();
+        queue(&mut sender, press(HidKeyCode::KeyB), start + Duration::from_millis(1900));
+        sender.trim_queue(start + Duration::from_secs(3));
+        // The server may have applied the sent press already, so even the release only becomes a reset
+        let messages = queued(&sender);
+        assert_eq!(messages[..2], [reset_message(), reset_message()]);
+        assert_eq!(messages.last(), Some(&press(HidKeyCode::KeyB)));
+        assert_eq!(sender.sent_messages, 2);
+
+        deliver(&mut sender, &packet);
+        assert_eq!(queued(&sender)[..], messages[2..]);
+    }
+
+    #[test]
+    fn too_small_for_a_single_message() {
+        let mut sender = InputSender::new(1.0, QueuePolicy::default());
+        let header_size = sender.write_packet(usize::MAX).unwrap().len();
+        assert!(sender.write_packet(header_size).is_ok());
+        sender.press_key(HidKeyCode::KeyA);
+        let err = sender.write_packet(header_size + 1).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(sender.write_packet(header_size + MESSAGE_SIZE).unwrap().len(), header_size + MESSAGE_SIZE);
+    }
 }
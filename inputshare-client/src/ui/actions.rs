@@ -2,12 +2,13 @@ use std::cell::Cell;
 use std::net::{IpAddr, SocketAddr};
 
 use druid::im::Vector;
-use druid::{EventCtx, ExtEventSink};
+use druid::commands::SHOW_OPEN_PANEL;
+use druid::{Application, EventCtx, ExtEventSink, FileDialogOptions};
 use mdns_sd::{Receiver, ServiceDaemon, ServiceEvent};
 use tracing::instrument;
 use yawi::{HookAction, InputEvent, InputHook, KeyState, VirtualKey};
 
-use crate::connection;
+use crate::{connection, ConnectionOptions};
 use crate::model::{AppState, ConnectionCommand, ConnectionState, PopupType, SearchResult};
 use crate::runtime::{ExtEventSinkCallback, RuntimeDelegate};
 use crate::utils::error::strip_color;
@@ -19,17 +20,29 @@ pub fn initiate_connection(ctx: &mut EventCtx) {
         ConnectionState::Disconnected => {
             data.connection_state = ConnectionState::Connecting;
             let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
-            let host = data.config.host_address.clone();
-            let info = data.config.show_network_info;
-            let rate = data.config.network_send_rate as f32;
+            let profiles = data
+                .config
+                .profiles
+                .iter()
+                .filter(|profile| profile.enabled)
+                .cloned()
+                .collect();
+            let options = ConnectionOptions {
+                info: data.config.show_network_info,
+                rate: data.config.network_send_rate as f32,
+                gamepad: data.config.forward_gamepad,
+                reconnect: data.config.auto_reconnect
+            };
             rt.runtime.spawn(async move {
-                let result = connection(&handle, receiver, &host, info, rate).await;
+                let result = connection(&handle, receiver, profiles, options).await;
                 handle.add_rt_callback(|rt, data| {
                     rt.hook = None;
                     rt.connection = None;
                     data.connection_state = ConnectionState::Disconnected;
                     data.enable_shutdown = false;
                     data.network_info = None;
+                    data.typing = None;
+                    data.server_status = None;
                     if let Err(err) = result {
                         tracing::warn!("could not establish connection: {:?}", err);
                         open_popup(rt, data, PopupType::Error(strip_color(&format!("{:?}", err))));
@@ -61,6 +74,36 @@ pub fn shutdown_server(ctx: &mut EventCtx) {
     });
 }
 
+/// The selected file ends up on the mass storage device of the server
+#[instrument(skip(ctx))]
+pub fn pick_file_to_push(ctx: &mut EventCtx) {
+    let options = FileDialogOptions::new()
+        .title("Send a file to the mass storage device")
+        .button_text("Send");
+    ctx.submit_command(SHOW_OPEN_PANEL.with(options));
+}
+
+/// Types the text of the clipboard on the remote side or stops the typing that is in progress
+#[instrument(skip(ctx))]
+pub fn type_clipboard(ctx: &mut EventCtx) {
+    ctx.add_rt_callback(|rt, data| {
+        let command = match data.typing.is_some() {
+            true => ConnectionCommand::CancelTyping,
+            false => match Application::global().clipboard().get_string() {
+                Some(text) => ConnectionCommand::TypeText(text.replace("\r\n", "\n")),
+                None => {
+                    tracing::warn!("The clipboard does not contain any text");
+                    return;
+                }
+            }
+        };
+        rt.connection
+            .as_ref()
+            .and_then(|sender| sender.send(command).ok())
+            .unwrap_or_else(|| tracing::warn!("Failed to send typing command!"));
+    });
+}
+
 #[instrument(skip(ctx))]
 pub fn start_search(ctx: &mut EventCtx) {
     let handle = ctx.get_external_handle();
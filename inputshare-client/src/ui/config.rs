@@ -2,14 +2,16 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use druid::text::ParseFormatter;
-use druid::widget::{Button, Controller, CrossAxisAlignment, Flex, Label, Scroll, Stepper, Switch, TextBox, ValueTextBox};
-use druid::{theme, Color, Data, Env, Event, EventCtx, LifeCycle, LifeCycleCtx, TimerToken, UpdateCtx, Widget, WidgetExt};
+use druid::widget::{
+    Button, Checkbox, Controller, CrossAxisAlignment, Flex, Label, Maybe, RadioGroup, Scroll, Stepper, Switch, TextBox, ValueTextBox
+};
+use druid::{theme, Color, Data, Env, Event, EventCtx, Lens, LensExt, LifeCycle, LifeCycleCtx, TimerToken, UpdateCtx, Widget, WidgetExt};
 use druid_material_icons::normal::action::SEARCH;
 use druid_material_icons::normal::content::ADD;
 use parking_lot::Mutex;
 use yawi::VirtualKey;
 
-use crate::model::{AppState, Config, Hotkey};
+use crate::model::{ActionHotkeys, AppState, Config, Hotkey, Profile, Resolution, ScreenEdge};
 use crate::runtime::ExtEventSinkCallback;
 use crate::ui::actions::{open_key_picker, start_search};
 use crate::ui::widget::{Icon, WidgetButton, WrappingList};
@@ -17,39 +19,156 @@ use crate::utils::keyset::VirtualKeySet;
 
 #[rustfmt::skip]
 pub fn ui() -> impl Widget<Config> + 'static {
+    let profile = profile_ui();
     let host = host_ui()
-        .lens(Config::host_address);
+        .lens(profile_lens().then(Profile::host_address));
+    let fingerprint = TextBox::new()
+        .with_placeholder("Accept any server")
+        .expand_width()
+        .lens(profile_lens().then(Profile::fingerprint));
     let speed = speed_ui()
-        .lens(Config::mouse_speed_factor);
-    let network = network_ui()
+        .lens(profile_lens().then(Profile::mouse_speed_factor));
+    let enabled = switch_ui()
+        .lens(profile_lens().then(Profile::enabled));
+    let network = switch_ui()
         .lens(Config::show_network_info);
+    let reconnect = switch_ui()
+        .lens(Config::auto_reconnect);
+    let gamepad = switch_ui()
+        .lens(Config::forward_gamepad);
     let small_options = Flex::row()
         .with_child(Flex::column()
             .with_child(Label::new("Mouse Speed"))
             .with_child(speed))
         .with_default_spacer()
+        .with_child(Flex::column()
+            .with_child(Label::new("Connect"))
+            .with_child(enabled))
+        .with_default_spacer()
         .with_child(Flex::column()
             .with_child(Label::new("Network Info"))
-            .with_child(network));
-    let blacklist = blacklist_ui()
+            .with_child(network))
+        .with_default_spacer()
+        .with_child(Flex::column()
+            .with_child(Label::new("Reconnect"))
+            .with_child(reconnect))
+        .with_default_spacer()
+        .with_child(Flex::column()
+            .with_child(Label::new("Gamepad"))
+            .with_child(gamepad));
+    let blacklist = key_set_ui(|data| &mut data.config.blacklist)
+        .expand_width()
+        .fix_height(80.0)
         .lens(Config::blacklist);
-    let hotkey = hotkey_ui()
-        .lens(Config::hotkey);
-    Flex::column()
+    let hotkey = hotkey_ui(|data| Some(&mut data.config.profile_mut().hotkey))
+        .lens(profile_lens().then(Profile::hotkey));
+    let actions = Flex::column()
+        .with_child(action_ui("Remote", |data| &mut data.config.actions.remote)
+            .lens(ActionHotkeys::remote))
+        .with_spacer(2.0)
+        .with_child(action_ui("Local", |data| &mut data.config.actions.local)
+            .lens(ActionHotkeys::local))
+        .with_spacer(2.0)
+        .with_child(action_ui("Reset", |data| &mut data.config.actions.reset)
+            .lens(ActionHotkeys::reset))
+        .with_spacer(2.0)
+        .with_child(action_ui("Release", |data| &mut data.config.actions.release)
+            .lens(ActionHotkeys::release))
+        .lens(Config::actions);
+    let edge = RadioGroup::row([
+        ("Off", ScreenEdge::None),
+        ("Left", ScreenEdge::Left),
+        ("Right", ScreenEdge::Right),
+        ("Top", ScreenEdge::Top),
+        ("Bottom", ScreenEdge::Bottom)
+    ])
+        .lens(profile_lens().then(Profile::screen_edge));
+    let resolution = resolution_ui()
+        .disabled_if(|profile: &Profile, _| profile.screen_edge == ScreenEdge::None)
+        .lens(profile_lens());
+    let dead_zone = ValueTextBox::new(TextBox::new(), ParseFormatter::new())
+        .fix_width(60.0)
+        .lens(Config::edge_dead_zone);
+    let edge_lock = key_set_ui(|data| &mut data.config.edge_lock)
+        .expand_width()
+        .fix_height(40.0)
+        .lens(Config::edge_lock);
+    let settings = Flex::column()
         .cross_axis_alignment(CrossAxisAlignment::Start)
+        .with_child(Label::new("Profile"))
+        .with_child(profile)
+        .with_default_spacer()
         .with_child(Label::new("Host"))
         .with_child(host)
         .with_default_spacer()
+        .with_child(Label::new("Certificate Fingerprint"))
+        .with_child(fingerprint)
+        .with_default_spacer()
         .with_child(small_options)
         .with_default_spacer()
         .with_child(Label::new("Hotkey"))
         .with_child(hotkey)
         .with_default_spacer()
+        .with_child(Label::new("Screen Edge"))
+        .with_child(edge)
+        .with_default_spacer()
+        .with_child(Label::new("Remote Resolution"))
+        .with_child(resolution)
+        .with_default_spacer()
+        .with_child(Label::new("Corner Dead Zone"))
+        .with_child(dead_zone)
+        .with_default_spacer()
+        .with_child(Label::new("Edge Lock Keys"))
+        .with_child(edge_lock)
+        .with_default_spacer()
+        .with_child(Label::new("Action Hotkeys"))
+        .with_child(actions)
+        .with_default_spacer()
         .with_child(Label::new("Blacklist"))
-        .with_flex_child(blacklist, 1.0)
+        .with_child(blacklist);
+    Scroll::new(settings)
+        .vertical()
         .controller(SaveController::default())
 }
 
+/// Only the selected profile can be edited
+fn profile_lens() -> impl Lens<Config, Profile> {
+    druid::lens::Identity.map(
+        |config: &Config| config.profile().clone(),
+        |config: &mut Config, profile: Profile| {
+            if !config.profile().same(&profile) {
+                *config.profile_mut() = profile;
+            }
+        }
+    )
+}
+
+#[rustfmt::skip]
+fn profile_ui() -> impl Widget<Config> + 'static {
+    let previous = Button::new("<")
+        .on_click(|_, data: &mut Config, _| data.cycle_profile(false));
+    let next = Button::new(">")
+        .on_click(|_, data: &mut Config, _| data.cycle_profile(true));
+    let name = TextBox::new()
+        .expand_width()
+        .lens(profile_lens().then(Profile::name));
+    let add = Button::new("+")
+        .on_click(|_, data: &mut Config, _| data.add_profile());
+    let remove = Button::new("-")
+        .on_click(|_, data: &mut Config, _| data.remove_profile())
+        .disabled_if(|data: &Config, _| data.profiles.len() < 2);
+    Flex::row()
+        .with_child(previous)
+        .with_spacer(2.0)
+        .with_flex_child(name, 1.0)
+        .with_spacer(2.0)
+        .with_child(next)
+        .with_spacer(5.0)
+        .with_child(add)
+        .with_spacer(2.0)
+        .with_child(remove)
+}
+
 #[rustfmt::skip]
 fn host_ui() -> impl Widget<String> + 'static {
     let host = TextBox::new().expand_width();
@@ -75,7 +194,23 @@ fn speed_ui() -> impl Widget<f64> + 'static {
 }
 
 #[rustfmt::skip]
-fn network_ui() -> impl Widget<bool> + 'static {
+fn resolution_ui() -> impl Widget<Profile> + 'static {
+    let width = ValueTextBox::new(TextBox::new(), ParseFormatter::new())
+        .fix_width(60.0)
+        .lens(Profile::remote_resolution.then(Resolution::width));
+    let height = ValueTextBox::new(TextBox::new(), ParseFormatter::new())
+        .fix_width(60.0)
+        .lens(Profile::remote_resolution.then(Resolution::height));
+    Flex::row()
+        .with_child(width)
+        .with_spacer(5.0)
+        .with_child(Label::new("x"))
+        .with_spacer(5.0)
+        .with_child(height)
+}
+
+#[rustfmt::skip]
+fn switch_ui() -> impl Widget<bool> + 'static {
     Switch::new()
         .env_scope(|env, _| {
             env.set(theme::FOREGROUND_LIGHT, env.get(theme::BACKGROUND_LIGHT));
@@ -87,13 +222,15 @@ fn network_ui() -> impl Widget<bool> + 'static {
         })
 }
 
+/// `hotkey` returns the hotkey that gets edited by this widget
 #[rustfmt::skip]
-fn hotkey_ui() -> impl Widget<Hotkey> + 'static {
-    let add = add_button(|data, key| {
-        let hotkey = &mut data.config.hotkey;
-        match key != hotkey.trigger {
-            true => hotkey.modifiers.insert(key),
-            false => tracing::warn!("The trigger can not be a modifier")
+fn hotkey_ui(hotkey: impl Fn(&mut AppState) -> Option<&mut Hotkey> + Copy + Send + 'static) -> impl Widget<Hotkey> + 'static {
+    let add = add_button(move |data, key| {
+        if let Some(hotkey) = hotkey(data) {
+            match key != hotkey.trigger {
+                true => hotkey.modifiers.insert(key),
+                false => tracing::warn!("The trigger can not be a modifier")
+            }
         }
     });
     let list = WrappingList::new(key_ui)
@@ -105,25 +242,50 @@ fn hotkey_ui() -> impl Widget<Hotkey> + 'static {
         .horizontal()
         .lens(Hotkey::modifiers);
     let trigger = Button::dynamic(|data: &VirtualKey, _| data.to_string())
-        .on_click(|ctx, _, _| {
-            open_key_picker(ctx, |data, key| {
-                let hotkey = &mut data.config.hotkey;
-                hotkey.modifiers.remove(key);
-                hotkey.trigger = key;
+        .on_click(move |ctx, _, _| {
+            open_key_picker(ctx, move |data, key| {
+                if let Some(hotkey) = hotkey(data) {
+                    hotkey.modifiers.remove(key);
+                    hotkey.trigger = key;
+                }
             })
         })
         .lens(Hotkey::trigger);
+    let double_tap = Checkbox::new("2x")
+        .lens(Hotkey::double_tap);
     Flex::row()
         .with_child(modifiers)
         .with_child(Icon::from(ADD))
         .with_child(trigger)
+        .with_spacer(5.0)
+        .with_child(double_tap)
         .with_spacer(2.0)
         .border(druid::theme::BORDER_DARK, 2.0)
         .rounded(2.0)
 }
 
-fn blacklist_ui() -> impl Widget<VirtualKeySet> + 'static {
-    let add = add_button(|data, key| data.config.blacklist.insert(key));
+/// A hotkey that can be left unset
+#[rustfmt::skip]
+fn action_ui(name: &'static str, hotkey: fn(&mut AppState) -> &mut Option<Hotkey>) -> impl Widget<Option<Hotkey>> + 'static {
+    let editor = Maybe::new(
+        move || hotkey_ui(move |data| hotkey(data).as_mut()),
+        move || Button::new("Set")
+            .on_click(move |ctx, _, _| open_key_picker(ctx, move |data, key| *hotkey(data) = Some(Hotkey::new([], key))))
+    );
+    let clear = Button::new("-")
+        .on_click(|_, data: &mut Option<Hotkey>, _| *data = None)
+        .disabled_if(|data: &Option<Hotkey>, _| data.is_none());
+    Flex::row()
+        .with_child(Label::new(name)
+            .fix_width(60.0))
+        .with_flex_child(editor, 1.0)
+        .with_spacer(2.0)
+        .with_child(clear)
+}
+
+/// The key picker adds to `set` of the app state, the list edits the lensed set
+fn key_set_ui(set: fn(&mut AppState) -> &mut VirtualKeySet) -> impl Widget<VirtualKeySet> + 'static {
+    let add = add_button(move |data, key| set(data).insert(key));
     let list = WrappingList::new(key_ui)
         .with_end(add)
         .horizontal()
@@ -135,7 +297,7 @@ fn blacklist_ui() -> impl Widget<VirtualKeySet> + 'static {
         .rounded(2.0)
 }
 
-fn add_button(setter: fn(&mut AppState, VirtualKey)) -> impl Widget<()> + 'static {
+fn add_button(setter: impl Fn(&mut AppState, VirtualKey) + Copy + Send + 'static) -> impl Widget<()> + 'static {
     Button::new("+")
         .env_scope(|env, _| {
             env.set(druid::theme::BUTTON_DARK, Color::TRANSPARENT);
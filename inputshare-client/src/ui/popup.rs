@@ -83,7 +83,7 @@ fn search_result_ui() -> impl Widget<SearchResult> + 'static {
         .on_click(|ctx, data: &mut SearchResult, _| {
             let addrs = data.addrs;
             ctx.add_rt_callback(move |rt, data| {
-                data.config.host_address = addrs.to_string();
+                data.config.profile_mut().host_address = addrs.to_string();
                 close_popup(rt, data);
             });
         })
@@ -1,9 +1,9 @@
 use druid::theme::TEXT_COLOR;
-use druid::widget::{Button, Either, Flex, Label, Maybe, SizedBox};
+use druid::widget::{Button, Either, Flex, Label, LineBreaking, Maybe, SizedBox};
 use druid::{Color, Env, Insets, Lens, LensExt, Widget, WidgetExt};
 
-use crate::model::{AppState, ConnectionState, NetworkInfo, Side};
-use crate::ui::actions::{initiate_connection, shutdown_server};
+use crate::model::{AppState, ConnectionState, NetworkInfo, Side, TypingProgress};
+use crate::ui::actions::{initiate_connection, pick_file_to_push, shutdown_server, type_clipboard};
 
 #[rustfmt::skip]
 pub fn ui() -> impl Widget<AppState> + 'static {
@@ -12,6 +12,8 @@ pub fn ui() -> impl Widget<AppState> + 'static {
         .with_child(Label::dynamic(connection_status)
             .with_text_size(15.0))
         .with_child(Maybe::or_empty(side_ui).lens(side_lens()))
+        .with_child(Maybe::or_empty(typing_ui).lens(AppState::typing))
+        .with_child(Maybe::or_empty(server_status_ui).lens(AppState::server_status))
         .center()
         .expand()
         .border(druid::theme::BORDER_DARK, 2.0)
@@ -23,24 +25,39 @@ pub fn ui() -> impl Widget<AppState> + 'static {
         .on_click(|ctx, _, _| shutdown_server(ctx))
         .padding(Insets::new(0.0, 3.0, 0.0, 0.0))
         .expand_width();
+    let push_button = Button::new("Send File")
+        .on_click(|ctx, _, _| pick_file_to_push(ctx))
+        .padding(Insets::new(0.0, 3.0, 0.0, 0.0))
+        .expand_width();
+    let type_button = Button::dynamic(type_button_label)
+        .on_click(|ctx, _, _| type_clipboard(ctx))
+        .padding(Insets::new(0.0, 3.0, 0.0, 0.0))
+        .expand_width();
+    let remote_buttons = Flex::column()
+        .with_child(shutdown_button)
+        .with_child(push_button)
+        .with_child(type_button);
     let buttons = Flex::column()
         .with_flex_child(connect_button, 1.0)
-        .with_child(Either::new(|data: &AppState, _| data.enable_shutdown, shutdown_button, SizedBox::empty()))
+        .with_child(Either::new(|data: &AppState, _| data.enable_shutdown, remote_buttons, SizedBox::empty()))
         .fix_width(100.0);
     Flex::row()
         .with_flex_child(status, 1.0)
         .with_spacer(3.0)
         .with_child(buttons)
-        .fix_height(80.0)
+        .fix_height(130.0)
 }
 
 #[rustfmt::skip]
 fn side_ui() -> impl Widget<Side> + 'static {
-    Label::dynamic(|side: &Side, _| format!("{:?}", side))
+    Label::dynamic(|side: &Side, _| match side {
+        Side::Local => "Local".to_string(),
+        Side::Remote(name) => name.clone()
+    })
         .with_text_size(25.0)
         .env_scope(|env, data: &Side| env.set(TEXT_COLOR, match data {
             Side::Local => Color::BLUE,
-            Side::Remote => Color::RED
+            Side::Remote(_) => Color::RED
         }))
 }
 
@@ -50,10 +67,31 @@ fn info_ui() -> impl Widget<NetworkInfo> + 'static {
         .with_text_size(12.0)
 }
 
+#[rustfmt::skip]
+fn typing_ui() -> impl Widget<TypingProgress> + 'static {
+    Label::dynamic(|progress: &TypingProgress, _| format!("typing: {}/{}", progress.typed, progress.total))
+        .with_text_size(12.0)
+}
+
+#[rustfmt::skip]
+fn server_status_ui() -> impl Widget<String> + 'static {
+    Label::dynamic(|status: &String, _| status.clone())
+        .with_text_size(12.0)
+        .with_line_break_mode(LineBreaking::WordWrap)
+}
+
+fn type_button_label(data: &AppState, _: &Env) -> String {
+    match data.typing.is_some() {
+        true => "Stop Typing",
+        false => "Type Clipboard"
+    }
+    .to_string()
+}
+
 fn button_label(data: &AppState, _: &Env) -> String {
     match data.connection_state {
         ConnectionState::Disconnected => "Connect",
-        ConnectionState::Connecting => "Cancel",
+        ConnectionState::Connecting | ConnectionState::Reconnecting => "Cancel",
         ConnectionState::Connected(_) => "Disconnect"
     }
     .to_string()
@@ -63,6 +101,7 @@ fn connection_status(data: &AppState, _: &Env) -> String {
     match data.connection_state {
         ConnectionState::Connected(_) => "Connected",
         ConnectionState::Connecting => "Connecting",
+        ConnectionState::Reconnecting => "Reconnecting",
         ConnectionState::Disconnected => "Disconnected"
     }
     .to_string()
@@ -70,8 +109,8 @@ fn connection_status(data: &AppState, _: &Env) -> String {
 
 fn side_lens() -> impl Lens<AppState, Option<Side>> {
     druid::lens::Identity.map(
-        |data: &AppState| match data.connection_state {
-            ConnectionState::Connected(s) => Some(s),
+        |data: &AppState| match &data.connection_state {
+            ConnectionState::Connected(s) => Some(s.clone()),
             _ => None
         },
         |_, _| {}
@@ -1,8 +1,8 @@
-use inputshare_common::{ConsumerDeviceCode, HidButtonCode, HidKeyCode};
-use yawi::{VirtualKey, WindowsScanCode};
+use inputshare_common::{ConsumerDeviceCode, GamepadAxis, GamepadButton, GamepadHat, HidButtonCode, HidKeyCode, SystemControlCode, SCROLL_RESOLUTION};
+use yawi::{ControllerButton, ControllerState, VirtualKey, WindowsScanCode};
 
-pub fn f32_to_i8(v: f32) -> i8 {
-    v.round().clamp(i8::MIN as f32, i8::MAX as f32) as i8
+pub fn detents_to_scroll_units(v: f32) -> i32 {
+    (v * SCROLL_RESOLUTION as f32).round() as i32
 }
 
 pub fn vk_to_mb(key: VirtualKey) -> Option<HidButtonCode> {
@@ -148,9 +148,6 @@ pub fn wsc_to_hkc(scancode: WindowsScanCode) -> Option<HidKeyCode> {
         0xe05b => Some(HidKeyCode::LeftMeta),
         0xe05c => Some(HidKeyCode::RightMeta),
         0xe05d => Some(HidKeyCode::Compose),
-        0xe05e => Some(HidKeyCode::Power),
-        0xe05f => Some(HidKeyCode::LockingCapsLock),
-        0xe063 => Some(HidKeyCode::LockingNumLock),
         0xe11d => Some(HidKeyCode::Pause),
         _ => None
     }
@@ -179,3 +176,83 @@ pub fn wsc_to_cdc(scancode: WindowsScanCode) -> Option<ConsumerDeviceCode> {
         _ => None
     }
 }
+
+/// For media keys whose scan code is unknown, e.g. because they were sent by the driver of a keyboard
+pub fn vk_to_cdc(key: VirtualKey) -> Option<ConsumerDeviceCode> {
+    match key {
+        VirtualKey::MediaNextTrack => Some(ConsumerDeviceCode::NextTrack),
+        VirtualKey::MediaPrevTrack => Some(ConsumerDeviceCode::PreviousTrack),
+        VirtualKey::MediaStop => Some(ConsumerDeviceCode::Stop),
+        VirtualKey::MediaPlayPause => Some(ConsumerDeviceCode::PlayPause),
+        VirtualKey::VolumeMute => Some(ConsumerDeviceCode::Mute),
+        VirtualKey::VolumeUp => Some(ConsumerDeviceCode::VolumeUp),
+        VirtualKey::VolumeDown => Some(ConsumerDeviceCode::VolumeDown),
+        VirtualKey::LaunchMediaSelect => Some(ConsumerDeviceCode::MediaSelect),
+        VirtualKey::LaunchMail => Some(ConsumerDeviceCode::Mail),
+        VirtualKey::LaunchApp1 => Some(ConsumerDeviceCode::MyComputer),
+        VirtualKey::LaunchApp2 => Some(ConsumerDeviceCode::Calculator),
+        VirtualKey::BrowserSearch => Some(ConsumerDeviceCode::BrowserSearch),
+        VirtualKey::BrowserHome => Some(ConsumerDeviceCode::BrowserHome),
+        VirtualKey::BrowserBack => Some(ConsumerDeviceCode::BrowserBack),
+        VirtualKey::BrowserForward => Some(ConsumerDeviceCode::BrowserForward),
+        VirtualKey::BrowserStop => Some(ConsumerDeviceCode::BrowserStop),
+        VirtualKey::BrowserRefresh => Some(ConsumerDeviceCode::BrowserRefresh),
+        VirtualKey::BrowserFavorites => Some(ConsumerDeviceCode::BrowserFavorites),
+        VirtualKey::Play => Some(ConsumerDeviceCode::Play),
+        _ => None
+    }
+}
+
+pub fn cb_to_gb(button: ControllerButton) -> Option<GamepadButton> {
+    match button {
+        ControllerButton::A => Some(GamepadButton::South),
+        ControllerButton::B => Some(GamepadButton::East),
+        ControllerButton::X => Some(GamepadButton::West),
+        ControllerButton::Y => Some(GamepadButton::North),
+        ControllerButton::LeftShoulder => Some(GamepadButton::LeftShoulder),
+        ControllerButton::RightShoulder => Some(GamepadButton::RightShoulder),
+        ControllerButton::Back => Some(GamepadButton::Select),
+        ControllerButton::Start => Some(GamepadButton::Start),
+        ControllerButton::LeftThumb => Some(GamepadButton::LeftStick),
+        ControllerButton::RightThumb => Some(GamepadButton::RightStick),
+        ControllerButton::DPadUp | ControllerButton::DPadDown | ControllerButton::DPadLeft | ControllerButton::DPadRight => None
+    }
+}
+
+pub fn controller_hat(state: &ControllerState) -> GamepadHat {
+    let vertical = (state.is_pressed(ControllerButton::DPadUp), state.is_pressed(ControllerButton::DPadDown));
+    let horizontal = (state.is_pressed(ControllerButton::DPadLeft), state.is_pressed(ControllerButton::DPadRight));
+    match (vertical, horizontal) {
+        ((true, false), (false, true)) => GamepadHat::UpRight,
+        ((true, false), (true, false)) => GamepadHat::UpLeft,
+        ((true, false), _) => GamepadHat::Up,
+        ((false, true), (false, true)) => GamepadHat::DownRight,
+        ((false, true), (true, false)) => GamepadHat::DownLeft,
+        ((false, true), _) => GamepadHat::Down,
+        (_, (false, true)) => GamepadHat::Right,
+        (_, (true, false)) => GamepadHat::Left,
+        _ => GamepadHat::Centered
+    }
+}
+
+/// Converts the controller state into the unsigned axis values of the HID gamepad. XInput's y axes point up, HID's point down.
+pub fn controller_axis(state: &ControllerState, axis: GamepadAxis) -> u8 {
+    let stick = |v: i16| ((i32::from(v) + 0x8000) >> 8) as u8;
+    match axis {
+        GamepadAxis::LeftStickX => stick(state.left_stick.0),
+        GamepadAxis::LeftStickY => u8::MAX - stick(state.left_stick.1),
+        GamepadAxis::RightStickX => stick(state.right_stick.0),
+        GamepadAxis::RightStickY => u8::MAX - stick(state.right_stick.1),
+        GamepadAxis::LeftTrigger => state.left_trigger,
+        GamepadAxis::RightTrigger => state.right_trigger
+    }
+}
+
+pub fn wsc_to_scc(scancode: WindowsScanCode) -> Option<SystemControlCode> {
+    match scancode {
+        0xe05e => Some(SystemControlCode::PowerDown),
+        0xe05f => Some(SystemControlCode::Sleep),
+        0xe063 => Some(SystemControlCode::WakeUp),
+        _ => None
+    }
+}
@@ -0,0 +1,45 @@
+use inputshare_common::GamepadAxis;
+use yawi::{get_controller_state, ControllerButton, ControllerState};
+
+use crate::sender::InputSender;
+use crate::utils::conversions::{cb_to_gb, controller_axis, controller_hat};
+
+/// Polls the first XInput controller and forwards every change as gamepad messages
+#[derive(Debug, Default)]
+pub struct GamepadForwarder {
+    last: ControllerState
+}
+
+impl GamepadForwarder {
+    /// Should be called whenever the remote gamepad got reset
+    pub fn reset(&mut self) {
+        self.last = ControllerState::default();
+    }
+
+    pub fn poll(&mut self, sender: &mut InputSender) {
+        let state = get_controller_state(0).unwrap_or_default();
+        if state == self.last {
+            return;
+        }
+        for button in ControllerButton::ALL {
+            if let Some(gb) = cb_to_gb(button) {
+                match (self.last.is_pressed(button), state.is_pressed(button)) {
+                    (false, true) => sender.press_gamepad_button(gb),
+                    (true, false) => sender.release_gamepad_button(gb),
+                    _ => {}
+                }
+            }
+        }
+        let hat = controller_hat(&state);
+        if hat != controller_hat(&self.last) {
+            sender.set_gamepad_hat(hat);
+        }
+        for axis in GamepadAxis::ALL {
+            let value = controller_axis(&state, axis);
+            if value != controller_axis(&self.last, axis) {
+                sender.set_gamepad_axis(axis, value);
+            }
+        }
+        self.last = state;
+    }
+}
@@ -1,20 +1,38 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use tokio::sync::mpsc::UnboundedSender;
 use yawi::{send_inputs, HookAction, HookFn, Input, InputEvent, KeyEvent, KeyState, VirtualKey};
 
-use crate::model::Config;
+use crate::model::{ActionHotkeys, Config, Hotkey, Profile, Resolution, ScreenEdge};
 use crate::utils::keyset::VirtualKeySet;
 
 #[derive(Debug, Copy, Clone)]
 pub enum HookEvent {
-    Captured(bool),
-    Input(InputEvent)
+    /// The index of the hotkey whose target now receives the input or `None` for the local pc
+    Captured(Option<usize>),
+    Input(InputEvent),
+    /// Releases everything that is held on the server
+    Reset
 }
 
 const CAPTURE_TIMEOUT: Duration = Duration::from_millis(500);
+/// The longest time between the two presses of a double tap
+const DOUBLE_TAP_INTERVAL: Duration = Duration::from_millis(400);
+/// How far from the screen edge the cursor comes back from a remote screen, so that it doesn't switch right back
+const EDGE_RETURN_DISTANCE: i32 = 5;
 
-pub fn create_callback(config: &Config, sender: UnboundedSender<HookEvent>) -> HookFn {
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Action {
+    Switch(Option<usize>),
+    Reset,
+    Release
+}
+
+/// Switches between the local pc and the servers of `profiles` with their hotkeys or screen edges.
+/// The screen edges skip the profiles whose flag in `connected` isn't set.
+pub fn create_callback(config: &Config, profiles: Vec<Profile>, connected: Arc<[AtomicBool]>, sender: UnboundedSender<HookEvent>) -> HookFn {
     let send = move |event| {
         sender
             .send(event)
@@ -24,16 +42,26 @@ pub fn create_callback(config: &Config, sender: UnboundedSender<HookEvent>) -> H
     let mut old_mouse_pos = yawi::get_cursor_pos();
 
     let blacklist = config.blacklist;
-    let modifiers = config.hotkey.modifiers;
-    let trigger = config.hotkey.trigger;
+    let edge_lock = config.edge_lock;
+    let dead_zone = config.edge_dead_zone as i32;
+    let screen = Screen::virtual_screen();
+    let hotkeys: Vec<Hotkey> = profiles
+        .iter()
+        .map(|profile| profile.hotkey.clone())
+        .collect();
+    let actions = config.actions.clone();
 
-    let mut captured = false;
+    let mut target: Option<usize> = None;
+    let mut last_target = 0;
+    // Where the cursor is estimated to be on the remote screen
+    let mut remote_pos = (0.0, 0.0);
     let mut pressed_keys = VirtualKeySet::new();
-    let mut hotkey_pressed = false;
+    let mut hotkey_pressed = None;
+    let mut last_press: Option<(VirtualKey, Instant)> = None;
 
     let mut last_swap = Instant::now();
 
-    send(HookEvent::Captured(captured));
+    send(HookEvent::Captured(target));
     HookFn::new(move |event| {
         let key_event = event.to_key_event();
         if is_blacklisted(blacklist, key_event) {
@@ -44,44 +72,226 @@ pub fn create_callback(config: &Config, sender: UnboundedSender<HookEvent>) -> H
 
         if should_handle {
             if let Some(KeyEvent { key, state }) = key_event {
-                if pressed_keys.is_superset(modifiers) && key == trigger && state == KeyState::Pressed {
-                    hotkey_pressed = true;
-                    if last_swap.elapsed() >= CAPTURE_TIMEOUT {
-                        if !captured {
-                            try_release_all(pressed_keys, trigger);
+                if state == KeyState::Pressed {
+                    let now = Instant::now();
+                    let double_tapped = is_double_tap(last_press, key, now);
+                    last_press = Some((key, now));
+                    let pressed = Pressed {
+                        keys: pressed_keys,
+                        key,
+                        double_tapped
+                    };
+                    if let Some(action) = find_action(&hotkeys, &actions, pressed, target, last_target) {
+                        hotkey_pressed = Some(key);
+                        // A third tap should not count as another double tap
+                        last_press = None;
+                        match action {
+                            Action::Switch(next) => {
+                                if next != target && last_swap.elapsed() >= CAPTURE_TIMEOUT {
+                                    if target.is_none() {
+                                        try_release_all(pressed_keys, Some(key));
+                                    }
+                                    target = next;
+                                    if let Some(index) = target {
+                                        let resolution = profiles[index].remote_resolution;
+                                        remote_pos = (resolution.width as f64 / 2.0, resolution.height as f64 / 2.0);
+                                        last_target = index;
+                                    }
+                                    last_swap = Instant::now();
+                                    send(HookEvent::Captured(target));
+                                }
+                            }
+                            Action::Reset => send(HookEvent::Reset),
+                            Action::Release => {
+                                try_release_all(pressed_keys, Some(key));
+                                // The hook ignores injected input, so the releases have to be applied here
+                                pressed_keys = VirtualKeySet::from_iter([key]);
+                            }
                         }
-                        captured = !captured;
-                        last_swap = Instant::now();
-                        send(HookEvent::Captured(captured));
+                        return HookAction::Block;
                     }
-                    return HookAction::Block;
                 }
-                if hotkey_pressed && key == trigger && state == KeyState::Released {
-                    hotkey_pressed = false;
+                if hotkey_pressed == Some(key) && state == KeyState::Released {
+                    hotkey_pressed = None;
                     return HookAction::Block;
                 }
             }
-            if captured {
+            let locked = pressed_keys.iter().any(|key| edge_lock.contains(key));
+            let can_swap = !locked && last_swap.elapsed() >= CAPTURE_TIMEOUT;
+            if let Some(index) = target {
                 if let InputEvent::MouseMoveEvent(x, y) = event {
                     let (ox, oy) = old_mouse_pos;
                     if x != ox || y != oy {
                         send(HookEvent::Input(InputEvent::MouseMoveEvent(x - ox, y - oy)));
+                        let profile = &profiles[index];
+                        let factor = profile.mouse_speed_factor;
+                        remote_pos = (remote_pos.0 + (x - ox) as f64 * factor, remote_pos.1 + (y - oy) as f64 * factor);
+                        match has_left_remote(profile.screen_edge, profile.remote_resolution, remote_pos) && can_swap {
+                            true => {
+                                target = None;
+                                last_swap = Instant::now();
+                                old_mouse_pos = screen.exit_point(profile.screen_edge, profile.remote_resolution, remote_pos);
+                                yawi::set_cursor_pos(old_mouse_pos.0, old_mouse_pos.1)
+                                    .unwrap_or_else(|err| tracing::warn!("Could not move the cursor: {}", err));
+                                send(HookEvent::Captured(target));
+                            }
+                            false => remote_pos = clamp_to_remote(profile.remote_resolution, remote_pos)
+                        }
                     }
                 } else {
                     send(HookEvent::Input(event));
                 }
             } else if let InputEvent::MouseMoveEvent(x, y) = event {
                 old_mouse_pos = (x, y);
+                let entered = match can_swap {
+                    true => profiles
+                        .iter()
+                        .enumerate()
+                        .filter(|(index, _)| connected[*index].load(Ordering::Relaxed))
+                        .find_map(|(index, profile)| {
+                            screen
+                                .entry_point(profile.screen_edge, profile.remote_resolution, (x, y), dead_zone)
+                                .map(|pos| (index, pos))
+                        }),
+                    false => None
+                };
+                if let Some((index, pos)) = entered {
+                    try_release_all(pressed_keys, None);
+                    target = Some(index);
+                    last_target = index;
+                    remote_pos = pos;
+                    last_swap = Instant::now();
+                    send(HookEvent::Captured(target));
+                    return HookAction::Block;
+                }
             }
         }
 
-        match captured {
+        match target.is_some() {
             true => HookAction::Block,
             false => HookAction::Continue
         }
     })
 }
 
+/// Whether `key` was pressed shortly before
+fn is_double_tap(last_press: Option<(VirtualKey, Instant)>, key: VirtualKey, now: Instant) -> bool {
+    matches!(last_press, Some((previous, time)) if previous == key && now.saturating_duration_since(time) <= DOUBLE_TAP_INTERVAL)
+}
+
+/// A key press together with the keys that are already held
+#[derive(Debug, Copy, Clone)]
+struct Pressed {
+    keys: VirtualKeySet,
+    key: VirtualKey,
+    double_tapped: bool
+}
+
+impl Pressed {
+    fn triggers(self, hotkey: &Hotkey) -> bool {
+        hotkey.trigger == self.key && self.keys.is_superset(hotkey.modifiers) && (!hotkey.double_tap || self.double_tapped)
+    }
+}
+
+/// The hotkeys of the servers take precedence over the action hotkeys
+fn find_action(hotkeys: &[Hotkey], actions: &ActionHotkeys, pressed: Pressed, current: Option<usize>, last_target: usize) -> Option<Action> {
+    let triggered = |hotkey: &Option<Hotkey>| hotkey.as_ref().map_or(false, |hotkey| pressed.triggers(hotkey));
+    if let Some(next) = next_target(hotkeys, pressed, current) {
+        Some(Action::Switch(next))
+    } else if triggered(&actions.remote) {
+        Some(Action::Switch(Some(current.unwrap_or(last_target))))
+    } else if triggered(&actions.local) {
+        Some(Action::Switch(None))
+    } else if triggered(&actions.reset) {
+        Some(Action::Reset)
+    } else if triggered(&actions.release) {
+        Some(Action::Release)
+    } else {
+        None
+    }
+}
+
+/// Returns `None` if no hotkey got pressed.
+/// Hotkeys that are shared by several targets cycle through them and return to the local pc after the last one.
+fn next_target(hotkeys: &[Hotkey], pressed: Pressed, current: Option<usize>) -> Option<Option<usize>> {
+    let matching: Vec<usize> = hotkeys
+        .iter()
+        .enumerate()
+        .filter(|(_, hotkey)| pressed.triggers(hotkey))
+        .map(|(index, _)| index)
+        .collect();
+    let first = *matching.first()?;
+    Some(match current.and_then(|current| matching.iter().position(|index| *index == current)) {
+        Some(position) => matching.get(position + 1).copied(),
+        None => Some(first)
+    })
+}
+
+/// The area that is covered by all monitors
+#[derive(Debug, Copy, Clone)]
+struct Screen {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32
+}
+
+impl Screen {
+    fn virtual_screen() -> Self {
+        let (x, y, width, height) = yawi::get_virtual_screen();
+        Self { x, y, width, height }
+    }
+
+    /// Returns the position on the remote screen at which the cursor enters it if `pos` touches `edge` outside of the dead zone
+    fn entry_point(self, edge: ScreenEdge, resolution: Resolution, (x, y): (i32, i32), dead_zone: i32) -> Option<(f64, f64)> {
+        let (right, bottom) = (self.x + self.width - 1, self.y + self.height - 1);
+        let along_x = x >= self.x + dead_zone && x <= right - dead_zone;
+        let along_y = y >= self.y + dead_zone && y <= bottom - dead_zone;
+        let (width, height) = (resolution.width as f64, resolution.height as f64);
+        let fx = (x - self.x) as f64 / self.width as f64 * width;
+        let fy = (y - self.y) as f64 / self.height as f64 * height;
+        match edge {
+            ScreenEdge::None => None,
+            ScreenEdge::Left => (x <= self.x && along_y).then_some((width - 1.0, fy)),
+            ScreenEdge::Right => (x >= right && along_y).then_some((0.0, fy)),
+            ScreenEdge::Top => (y <= self.y && along_x).then_some((fx, height - 1.0)),
+            ScreenEdge::Bottom => (y >= bottom && along_x).then_some((fx, 0.0))
+        }
+    }
+
+    /// Returns the position next to `edge` at which the cursor comes back from `pos` on the remote screen
+    fn exit_point(self, edge: ScreenEdge, resolution: Resolution, (x, y): (f64, f64)) -> (i32, i32) {
+        let (right, bottom) = (self.x + self.width - 1, self.y + self.height - 1);
+        let lx = (self.x + (x / resolution.width as f64 * self.width as f64) as i32).clamp(self.x, right);
+        let ly = (self.y + (y / resolution.height as f64 * self.height as f64) as i32).clamp(self.y, bottom);
+        match edge {
+            ScreenEdge::None => (lx, ly),
+            ScreenEdge::Left => (self.x + EDGE_RETURN_DISTANCE, ly),
+            ScreenEdge::Right => (right - EDGE_RETURN_DISTANCE, ly),
+            ScreenEdge::Top => (lx, self.y + EDGE_RETURN_DISTANCE),
+            ScreenEdge::Bottom => (lx, bottom - EDGE_RETURN_DISTANCE)
+        }
+    }
+}
+
+/// The cursor leaves the remote screen through the edge that faces the local screen
+fn has_left_remote(edge: ScreenEdge, resolution: Resolution, (x, y): (f64, f64)) -> bool {
+    match edge {
+        ScreenEdge::None => false,
+        ScreenEdge::Left => x > resolution.width as f64 - 1.0,
+        ScreenEdge::Right => x < 0.0,
+        ScreenEdge::Top => y > resolution.height as f64 - 1.0,
+        ScreenEdge::Bottom => y < 0.0
+    }
+}
+
+fn clamp_to_remote(resolution: Resolution, (x, y): (f64, f64)) -> (f64, f64) {
+    (
+        x.clamp(0.0, resolution.width.saturating_sub(1) as f64),
+        y.clamp(0.0, resolution.height.saturating_sub(1) as f64)
+    )
+}
+
 fn is_blacklisted(blacklist: VirtualKeySet, event: Option<KeyEvent>) -> bool {
     event.map_or(false, |event| blacklist.contains(event.key))
 }
@@ -103,10 +313,10 @@ fn is_repeated_event(pressed_keys: &mut VirtualKeySet, event: Option<KeyEvent>)
     }
 }
 
-fn try_release_all(keys: VirtualKeySet, trigger: VirtualKey) {
+fn try_release_all(keys: VirtualKeySet, trigger: Option<VirtualKey>) {
     send_inputs(
         keys.iter()
-            .filter(|k| *k != trigger)
+            .filter(|k| Some(*k) != trigger)
             .map(|k| match k.is_mouse_button() {
                 true => Input::MouseButtonInput(k, KeyState::Released),
                 false => Input::KeyboardKeyInput(k, KeyState::Released)
@@ -114,3 +324,146 @@ fn try_release_all(keys: VirtualKeySet, trigger: VirtualKey) {
     )
     .unwrap_or_else(|err| tracing::warn!("Could not send input events: {}", err));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The held keys include the trigger like they do in the hook
+    fn pressed<const N: usize>(modifiers: [VirtualKey; N], key: VirtualKey, double_tapped: bool) -> Pressed {
+        Pressed {
+            keys: VirtualKeySet::from_iter(modifiers.into_iter().chain([key])),
+            key,
+            double_tapped
+        }
+    }
+
+    fn double_tap(trigger: VirtualKey) -> Hotkey {
+        Hotkey {
+            double_tap: true,
+            ..Hotkey::new([], trigger)
+        }
+    }
+
+    #[test]
+    fn hotkeys_need_their_modifiers() {
+        let hotkey = Hotkey::new([VirtualKey::LControl], VirtualKey::KeyA);
+        assert!(pressed([VirtualKey::LControl], VirtualKey::KeyA, false).triggers(&hotkey));
+        assert!(pressed([VirtualKey::LControl, VirtualKey::LShift], VirtualKey::KeyA, false).triggers(&hotkey));
+        assert!(!pressed([], VirtualKey::KeyA, false).triggers(&hotkey));
+        assert!(!pressed([VirtualKey::LControl, VirtualKey::KeyA], VirtualKey::Key1, false).triggers(&hotkey));
+    }
+
+    #[test]
+    fn double_tap_hotkeys_need_a_second_press() {
+        let hotkey = double_tap(VirtualKey::LControl);
+        assert!(!pressed([], VirtualKey::LControl, false).triggers(&hotkey));
+        assert!(pressed([], VirtualKey::LControl, true).triggers(&hotkey));
+        assert!(pressed([], VirtualKey::F1, true).triggers(&Hotkey::new([], VirtualKey::F1)));
+    }
+
+    #[test]
+    fn double_taps_are_quick_presses_of_the_same_key() {
+        let start = Instant::now();
+        let last_press = Some((VirtualKey::LControl, start));
+        assert!(is_double_tap(last_press, VirtualKey::LControl, start + DOUBLE_TAP_INTERVAL));
+        assert!(!is_double_tap(last_press, VirtualKey::LControl, start + DOUBLE_TAP_INTERVAL + Duration::from_millis(1)));
+        assert!(!is_double_tap(last_press, VirtualKey::LShift, start));
+        assert!(!is_double_tap(None, VirtualKey::LControl, start));
+    }
+
+    #[test]
+    fn shared_hotkeys_cycle_through_their_targets() {
+        let hotkeys = [Hotkey::new([], VirtualKey::F1), Hotkey::new([], VirtualKey::F2), Hotkey::new([], VirtualKey::F1)];
+        let shared = pressed([], VirtualKey::F1, false);
+        assert_eq!(next_target(&hotkeys, shared, None), Some(Some(0)));
+        assert_eq!(next_target(&hotkeys, shared, Some(0)), Some(Some(2)));
+        assert_eq!(next_target(&hotkeys, shared, Some(2)), Some(None));
+        assert_eq!(next_target(&hotkeys, shared, Some(1)), Some(Some(0)));
+        // A hotkey of a single target toggles it
+        assert_eq!(next_target(&hotkeys, pressed([], VirtualKey::F2, false), Some(1)), Some(None));
+        assert_eq!(next_target(&hotkeys, pressed([], VirtualKey::F5, false), None), None);
+    }
+
+    #[test]
+    fn actions_follow_the_server_hotkeys() {
+        let hotkeys = [Hotkey::new([], VirtualKey::F1), double_tap(VirtualKey::F6)];
+        let actions = ActionHotkeys {
+            remote: Some(Hotkey::new([], VirtualKey::F5)),
+            local: Some(Hotkey::new([], VirtualKey::F6)),
+            reset: Some(Hotkey::new([], VirtualKey::F7)),
+            release: Some(double_tap(VirtualKey::F8))
+        };
+        let action = |key, double_tapped, current| find_action(&hotkeys, &actions, pressed([], key, double_tapped), current, 1);
+        assert_eq!(action(VirtualKey::F1, false, None), Some(Action::Switch(Some(0))));
+        assert_eq!(action(VirtualKey::F5, false, None), Some(Action::Switch(Some(1))));
+        assert_eq!(action(VirtualKey::F5, false, Some(0)), Some(Action::Switch(Some(0))));
+        assert_eq!(action(VirtualKey::F6, false, Some(0)), Some(Action::Switch(None)));
+        assert_eq!(action(VirtualKey::F6, true, None), Some(Action::Switch(Some(1))));
+        assert_eq!(action(VirtualKey::F7, false, None), Some(Action::Reset));
+        assert_eq!(action(VirtualKey::F8, false, None), None);
+        assert_eq!(action(VirtualKey::F8, true, None), Some(Action::Release));
+        assert_eq!(action(VirtualKey::Key1, false, None), None);
+    }
+
+    /// Two monitors side by side, the left one being to the left of the primary one
+    const SCREEN: Screen = Screen { x: -1920, y: 0, width: 3840, height: 1080 };
+    const REMOTE: Resolution = Resolution { width: 1280, height: 720 };
+    const EDGES: [ScreenEdge; 4] = [ScreenEdge::Left, ScreenEdge::Right, ScreenEdge::Top, ScreenEdge::Bottom];
+
+    #[test]
+    fn entry_points_map_onto_the_opposite_remote_edge() {
+        assert_eq!(SCREEN.entry_point(ScreenEdge::Left, REMOTE, (-1920, 540), 10), Some((1279.0, 360.0)));
+        assert_eq!(SCREEN.entry_point(ScreenEdge::Right, REMOTE, (1919, 270), 10), Some((0.0, 180.0)));
+        assert_eq!(SCREEN.entry_point(ScreenEdge::Top, REMOTE, (0, 0), 10), Some((640.0, 719.0)));
+        assert_eq!(SCREEN.entry_point(ScreenEdge::Bottom, REMOTE, (960, 1079), 10), Some((960.0, 0.0)));
+        assert_eq!(SCREEN.entry_point(ScreenEdge::Left, REMOTE, (-1919, 540), 10), None);
+        assert_eq!(SCREEN.entry_point(ScreenEdge::Right, REMOTE, (-1920, 540), 10), None);
+        assert_eq!(SCREEN.entry_point(ScreenEdge::None, REMOTE, (-1920, 540), 10), None);
+    }
+
+    #[test]
+    fn corners_are_in_the_dead_zone() {
+        assert_eq!(SCREEN.entry_point(ScreenEdge::Left, REMOTE, (-1920, 9), 10), None);
+        assert_eq!(SCREEN.entry_point(ScreenEdge::Left, REMOTE, (-1920, 1070), 10), None);
+        assert_eq!(SCREEN.entry_point(ScreenEdge::Top, REMOTE, (-1911, 0), 10), None);
+        assert_eq!(SCREEN.entry_point(ScreenEdge::Bottom, REMOTE, (1910, 1079), 10), None);
+        assert!(SCREEN.entry_point(ScreenEdge::Left, REMOTE, (-1920, 10), 10).is_some());
+        assert!(SCREEN.entry_point(ScreenEdge::Left, REMOTE, (-1920, 0), 0).is_some());
+    }
+
+    #[test]
+    fn remote_screen_is_left_through_the_facing_edge() {
+        assert!(!has_left_remote(ScreenEdge::Left, REMOTE, (1279.0, 360.0)));
+        assert!(has_left_remote(ScreenEdge::Left, REMOTE, (1279.5, 360.0)));
+        assert!(!has_left_remote(ScreenEdge::Right, REMOTE, (0.0, 360.0)));
+        assert!(has_left_remote(ScreenEdge::Right, REMOTE, (-0.5, 360.0)));
+        assert!(!has_left_remote(ScreenEdge::Top, REMOTE, (640.0, 719.0)));
+        assert!(has_left_remote(ScreenEdge::Top, REMOTE, (640.0, 720.0)));
+        assert!(!has_left_remote(ScreenEdge::Bottom, REMOTE, (640.0, 0.0)));
+        assert!(has_left_remote(ScreenEdge::Bottom, REMOTE, (640.0, -1.0)));
+        // Only the facing edge counts
+        assert!(!has_left_remote(ScreenEdge::Left, REMOTE, (-1.0, 360.0)));
+        assert!(!has_left_remote(ScreenEdge::None, REMOTE, (-1.0, -1.0)));
+    }
+
+    #[test]
+    fn positions_are_clamped_to_the_remote_screen() {
+        assert_eq!(clamp_to_remote(REMOTE, (-5.0, 800.0)), (0.0, 719.0));
+        assert_eq!(clamp_to_remote(REMOTE, (1300.0, 300.5)), (1279.0, 300.5));
+        assert_eq!(clamp_to_remote(Resolution { width: 0, height: 0 }, (10.0, 10.0)), (0.0, 0.0));
+    }
+
+    #[test]
+    fn cursor_returns_next_to_the_edge() {
+        assert_eq!(SCREEN.exit_point(ScreenEdge::Left, REMOTE, (1280.0, 360.0)), (-1915, 540));
+        assert_eq!(SCREEN.exit_point(ScreenEdge::Right, REMOTE, (-1.0, 180.0)), (1914, 270));
+        assert_eq!(SCREEN.exit_point(ScreenEdge::Top, REMOTE, (640.0, 720.0)), (0, 5));
+        assert_eq!(SCREEN.exit_point(ScreenEdge::Bottom, REMOTE, (960.0, -1.0)), (960, 1074));
+        assert_eq!(SCREEN.exit_point(ScreenEdge::None, REMOTE, (640.0, 360.0)), (0, 540));
+        for edge in EDGES {
+            let exit = SCREEN.exit_point(edge, REMOTE, (-1.0, -1.0));
+            assert_eq!(SCREEN.entry_point(edge, REMOTE, exit, 0), None);
+        }
+    }
+}
@@ -1,29 +1,22 @@
 use std::sync::Arc;
 
-use druid::ExtEventSink;
+use inputshare_common::format_fingerprint;
+use ring::digest::{digest, SHA256};
 use yawi::{InputEvent, KeyState, ScrollDirection};
 
-use crate::model::{AppState, ConnectionState, Side};
 use crate::sender::InputSender;
-use crate::utils::conversions::{f32_to_i8, vk_to_mb, wsc_to_cdc, wsc_to_hkc};
+use crate::utils::conversions::{detents_to_scroll_units, vk_to_cdc, vk_to_mb, wsc_to_cdc, wsc_to_hkc, wsc_to_scc};
 use crate::utils::hook::HookEvent;
 
 pub mod conversions;
 pub mod error;
+pub mod gamepad;
 pub mod hook;
 pub mod keyset;
 
-pub fn process_hook_event(sender: &mut InputSender, sink: &ExtEventSink, event: HookEvent) {
+pub fn process_hook_event(sender: &mut InputSender, event: HookEvent) {
     match event {
-        HookEvent::Captured(captured) => {
-            sink.add_idle_callback(move |data: &mut AppState| {
-                data.connection_state = ConnectionState::Connected(match captured {
-                    true => Side::Remote,
-                    false => Side::Local
-                });
-            });
-            sender.reset();
-        }
+        HookEvent::Captured(_) | HookEvent::Reset => sender.reset(),
         HookEvent::Input(event) => match event {
             InputEvent::MouseMoveEvent(x, y) => {
                 sender.move_mouse(x as i64, y as i64);
@@ -33,14 +26,20 @@ pub fn process_hook_event(sender: &mut InputSender, sink: &ExtEventSink, event:
                     KeyState::Pressed => sender.press_key(kc),
                     KeyState::Released => sender.release_key(kc)
                 },
-                None => match wsc_to_cdc(sc) {
+                None => match wsc_to_cdc(sc).or_else(|| vk_to_cdc(vk)) {
                     Some(cdc) => match ks {
                         KeyState::Pressed => sender.press_consumer_device(cdc),
                         KeyState::Released => sender.release_consumer_device(cdc)
                     },
-                    None => {
-                        if !matches!(sc, 0x21d) {
-                            tracing::warn!("Unknown key: {} ({:x})", vk, sc)
+                    None => match wsc_to_scc(sc) {
+                        Some(scc) => match ks {
+                            KeyState::Pressed => sender.press_system_control(scc),
+                            KeyState::Released => sender.release_system_control(scc)
+                        },
+                        None => {
+                            if !matches!(sc, 0x21d) {
+                                tracing::warn!("Unknown key: {} ({:x})", vk, sc)
+                            }
                         }
                     }
                 }
@@ -53,8 +52,8 @@ pub fn process_hook_event(sender: &mut InputSender, sink: &ExtEventSink, event:
                 None => tracing::warn!("Unknown mouse button: {}", mb)
             },
             InputEvent::MouseWheelEvent(sd) => match sd {
-                ScrollDirection::Horizontal(amount) => sender.scroll_horizontal(f32_to_i8(amount)),
-                ScrollDirection::Vertical(amount) => sender.scroll_vertical(f32_to_i8(amount))
+                ScrollDirection::Horizontal(amount) => sender.scroll_horizontal(detents_to_scroll_units(amount)),
+                ScrollDirection::Vertical(amount) => sender.scroll_vertical(detents_to_scroll_units(amount))
             }
         }
     }
@@ -76,3 +75,38 @@ impl rustls::client::ServerCertVerifier for SkipServerVerification {
         Ok(rustls::client::ServerCertVerified::assertion())
     }
 }
+
+/// Only accepts the server whose certificate matches the fingerprint of the profile
+pub struct PinnedServerVerification {
+    fingerprint: String
+}
+
+impl PinnedServerVerification {
+    pub fn new(fingerprint: &str) -> Arc<Self> {
+        Arc::new(Self {
+            fingerprint: normalize_fingerprint(fingerprint)
+        })
+    }
+}
+
+impl rustls::client::ServerCertVerifier for PinnedServerVerification {
+    fn verify_server_cert(
+        &self, end_entity: &rustls::Certificate, _intermediates: &[rustls::Certificate], _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>, _ocsp_response: &[u8], _now: std::time::SystemTime
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        let fingerprint = format_fingerprint(digest(&SHA256, &end_entity.0).as_ref());
+        match normalize_fingerprint(&fingerprint) == self.fingerprint {
+            true => Ok(rustls::client::ServerCertVerified::assertion()),
+            false => Err(rustls::Error::General(format!("The server has the unexpected certificate fingerprint {}", fingerprint)))
+        }
+    }
+}
+
+/// Ignores the separators and the case
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(char::is_ascii_hexdigit)
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
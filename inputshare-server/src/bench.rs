@@ -0,0 +1,192 @@
+use std::collections::VecDeque;
+use std::f64::consts::TAU;
+use std::future::{pending, Future};
+use std::num::NonZeroU8;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use anyhow::Result;
+use inputshare_common::{AckPacket, InputHeader};
+use rand::Rng;
+use tokio::fs::File;
+use tokio::io::AsyncWrite;
+use tokio::select;
+use tokio::time::{interval, sleep, sleep_until, Instant, Sleep};
+use tracing::instrument;
+
+use crate::configfs::Mouse;
+
+const REPORT_SIZE: u64 = 9;
+const CIRCLE_RADIUS: f64 = 300.0;
+
+/// The previous layout: session, sequence, mouse position and start message as u64 plus the message count
+const FIXED_HEADER_SIZE: usize = 41;
+/// The previous layout: session, sequence, mouse position and last message as u64
+const FIXED_ACK_SIZE: usize = 40;
+/// The simulated client types a key every this many packets
+const KEYSTROKE_INTERVAL: u32 = 10;
+const MESSAGE_SIZE: usize = 2;
+
+/// A file that only accepts one write per `delay`, mimicking a HID gadget that is polled by a slow host.
+#[derive(Debug)]
+pub struct ThrottledFile {
+    file: File,
+    delay: Duration,
+    ready: Pin<Box<Sleep>>,
+    written: u64
+}
+
+impl ThrottledFile {
+    pub fn new(file: File, delay: Duration) -> Self {
+        Self {
+            file,
+            delay,
+            ready: Box::pin(sleep(Duration::ZERO)),
+            written: 0
+        }
+    }
+}
+
+impl AsyncWrite for ThrottledFile {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.ready.as_mut().poll(cx));
+        let written = ready!(Pin::new(&mut this.file).poll_write(cx, buf))?;
+        this.ready.as_mut().reset(Instant::now() + this.delay);
+        this.written += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().file).poll_shutdown(cx)
+    }
+}
+
+/// Moves the mouse in circles at `packet_rate` movements per second and reports how the reports got distributed.
+#[instrument]
+pub async fn bench_mouse(
+    tess_factor: NonZeroU8, output: &Path, report_delay: Duration, packet_rate: u32, duration: Duration
+) -> Result<()> {
+    let file = File::create(output).await?;
+    let mut mouse = Mouse::with_device(ThrottledFile::new(file, report_delay), tess_factor);
+    let mut packets = interval(Duration::from_secs(1) / packet_rate.max(1));
+    let end = Instant::now() + duration;
+
+    let mut moves = 0u32;
+    let mut behind = 0u32;
+    let mut position = (CIRCLE_RADIUS, 0.0);
+    loop {
+        let next_step = mouse.next_step();
+        let step = async move {
+            match next_step {
+                Some(next_step) => sleep_until(next_step).await,
+                None => pending().await
+            }
+        };
+        select! {
+            _ = sleep_until(end) => break,
+            _ = packets.tick() => {
+                moves += 1;
+                let angle = TAU * f64::from(moves) / f64::from(packet_rate);
+                let target = (CIRCLE_RADIUS * angle.cos(), CIRCLE_RADIUS * angle.sin());
+                let (dx, dy) = ((target.0 - position.0).round(), (target.1 - position.1).round());
+                position = (position.0 + dx, position.1 + dy);
+                if mouse.is_behind() {
+                    behind += 1;
+                }
+                mouse.move_by(dx as i32, dy as i32).await?;
+            },
+            _ = step => mouse.send_next_step().await?
+        }
+    }
+    let flush_start = Instant::now();
+    mouse.flush_movement().await?;
+    let flush_time = flush_start.elapsed();
+
+    let reports = mouse.device().written / REPORT_SIZE;
+    println!("movements:          {}", moves);
+    println!("reports:            {}", reports);
+    println!("reports / movement: {:.2}", reports as f64 / f64::from(moves.max(1)));
+    println!("write latency:      {:?}", mouse.write_latency());
+    println!("behind:             {} ({:.1}%)", behind, 100.0 * f64::from(behind) / f64::from(moves.max(1)));
+    println!("final flush:        {:?}", flush_time);
+    Ok(())
+}
+
+/// Simulates a client that moves the mouse in circles and types while packets in both directions get lost,
+/// and compares the bytes that the compact encoding needs with the previous fixed size layout.
+pub fn bench_packets(packet_rate: u32, duration: Duration, loss: f64, latency: Duration) {
+    let mut rng = rand::thread_rng();
+    let packet_rate = packet_rate.max(1);
+    let packets = (duration.as_secs_f64() * f64::from(packet_rate)) as u32;
+    let round_trip = ((2.0 * latency.as_secs_f64() * f64::from(packet_rate)).round() as u32).max(1);
+
+    let mut server = AckPacket::default();
+    let mut acked = server;
+    let mut acks = VecDeque::new();
+    let mut messages = 0u64;
+    let mut buffer = Vec::new();
+    let (mut header_bytes, mut max_header, mut message_bytes) = (0usize, 0usize, 0usize);
+    let (mut ack_bytes, mut ack_count) = (0usize, 0usize);
+    for sequence in 1..=packets {
+        while let Some((_, ack)) = acks.front().filter(|(arrival, _)| *arrival <= sequence) {
+            acked = *ack;
+            acks.pop_front();
+        }
+        if sequence % KEYSTROKE_INTERVAL == 0 {
+            messages += 2;
+        }
+        let angle = TAU * f64::from(sequence) / f64::from(packet_rate);
+        let position = ((CIRCLE_RADIUS * angle.cos()).round() as i64, (CIRCLE_RADIUS * angle.sin()).round() as i64);
+        let message_count = (messages - acked.last_message).min(u8::MAX.into()) as u8;
+
+        buffer.clear();
+        InputHeader {
+            session: 1,
+            sequence: u64::from(sequence),
+            base_distance: u64::from(sequence) - acked.applied_sequence,
+            mouse_x: position.0 - acked.mouse_x,
+            mouse_y: position.1 - acked.mouse_y,
+            start_message: acked.last_message,
+            message_count
+        }
+        .write(&mut buffer);
+        header_bytes += buffer.len();
+        max_header = max_header.max(buffer.len());
+        message_bytes += usize::from(message_count) * MESSAGE_SIZE;
+
+        if rng.gen_bool(1.0 - loss) {
+            server.sequence += 1;
+            server.applied_sequence = u64::from(sequence);
+            server.mouse_x = position.0;
+            server.mouse_y = position.1;
+            server.last_message = acked.last_message + u64::from(message_count);
+            buffer.clear();
+            server.write(&mut buffer);
+            ack_bytes += buffer.len();
+            ack_count += 1;
+            if rng.gen_bool(1.0 - loss) {
+                acks.push_back((sequence + round_trip, server));
+            }
+        }
+    }
+
+    let packets = packets.max(1) as usize;
+    let seconds = duration.as_secs_f64().max(f64::EPSILON);
+    println!("packets:        {}", packets);
+    println!("fixed header:   {} bytes", FIXED_HEADER_SIZE);
+    println!("compact header: {:.1} bytes (max {})", header_bytes as f64 / packets as f64, max_header);
+    println!("fixed ack:      {} bytes", FIXED_ACK_SIZE);
+    println!("compact ack:    {:.1} bytes", ack_bytes as f64 / ack_count.max(1) as f64);
+    println!(
+        "upstream:       {:.0} B/s fixed, {:.0} B/s compact",
+        (packets * FIXED_HEADER_SIZE + message_bytes) as f64 / seconds,
+        (header_bytes + message_bytes) as f64 / seconds
+    );
+}
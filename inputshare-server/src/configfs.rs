@@ -1,14 +1,16 @@
 use std::num::NonZeroU8;
 #[cfg(unix)]
 use std::os::unix;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::time::Duration;
 use std::{env, fs};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use tokio::fs::{File, OpenOptions};
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio::task::spawn_blocking;
+use tokio::time::Instant;
 
 #[cfg(windows)]
 mod unix {
@@ -81,10 +83,10 @@ const MOUSE_REPORT_DESC: &[u8] = &[
     0x95, 0x02, //     Report Count (2)
     0x81, 0x06, //     Input (Data,Var,Rel,No Wrap,Linear,Preferred State,No Null Position)
     0x09, 0x38, //     Usage (Wheel)
-    0x15, 0x81, //     Logical Minimum (-127)
-    0x25, 0x7F, //     Logical Maximum (127)
+    0x16, 0x01, 0x80, //     Logical Minimum (-32767)
+    0x26, 0xFF, 0x7F, //     Logical Maximum (32767)
+    0x75, 0x10, //     Report Size (16)
     0x95, 0x01, //     Report Count (1)
-    0x75, 0x08, //     Report Size (8)
     0x81, 0x06, //     Input (Data,Var,Rel,No Wrap,Linear,Preferred State,No Null Position)
     0x05, 0x0C, //     Usage Page (Consumer)
     0x0A, 0x38, 0x02, //     Usage (AC Pan)
@@ -97,31 +99,466 @@ const CONSUMER_REPORT_DESC: &[u8] = &[
     0x05, 0x0C, // Usage Page (Consumer)
     0x09, 0x01, // Usage (Consumer Control)
     0xA1, 0x01, // Collection (Application)
-    0x05, 0x0C, //   Usage Page (Consumer)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x03, //   Logical Maximum (1023)
+    0x19, 0x00, //   Usage Minimum (Unassigned)
+    0x2A, 0xFF, 0x03, //   Usage Maximum (0x03FF)
+    0x75, 0x10, //   Report Size (16)
+    0x95, 0x04, //   Report Count (4)
+    0x81, 0x00, //   Input (Data,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0xC0  // End Collection
+];
+
+const SYSTEM_CONTROL_REPORT_DESC: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop Ctrls)
+    0x09, 0x80, // Usage (Sys Control)
+    0xA1, 0x01, // Collection (Application)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x03, //   Report Count (3)
+    0x09, 0x81, //   Usage (Sys Power Down)
+    0x09, 0x82, //   Usage (Sys Sleep)
+    0x09, 0x83, //   Usage (Sys Wake Up)
+    0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x05, //   Report Size (5)
+    0x81, 0x03, //   Input (Const,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0xC0  // End Collection
+];
+
+const GAMEPAD_REPORT_DESC: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop Ctrls)
+    0x09, 0x05, // Usage (Game Pad)
+    0xA1, 0x01, // Collection (Application)
+    0x05, 0x09, //   Usage Page (Button)
+    0x19, 0x01, //   Usage Minimum (0x01)
+    0x29, 0x10, //   Usage Maximum (0x10)
     0x15, 0x00, //   Logical Minimum (0)
     0x25, 0x01, //   Logical Maximum (1)
     0x75, 0x01, //   Report Size (1)
     0x95, 0x10, //   Report Count (16)
-    0x09, 0xB5, //   Usage (Scan Next Track)
-    0x09, 0xB6, //   Usage (Scan Previous Track)
-    0x09, 0xB7, //   Usage (Stop)
-    0x09, 0xCD, //   Usage (Play / Pause)
-    0x09, 0xE2, //   Usage (Mute)
-    0x09, 0xE9, //   Usage (Volume Up)
-    0x09, 0xEA, //   Usage (Volume Down)
-    0x0A, 0x23, 0x02, //   Usage (WWW Home)
-    0x0A, 0x94, 0x01, //   Usage (My Computer)
-    0x0A, 0x92, 0x01, //   Usage (Calculator)
-    0x0A, 0x2A, 0x02, //   Usage (WWW fav)
-    0x0A, 0x21, 0x02, //   Usage (WWW search)
-    0x0A, 0x26, 0x02, //   Usage (WWW stop)
-    0x0A, 0x24, 0x02, //   Usage (WWW back)
-    0x0A, 0x83, 0x01, //   Usage (Media sel)
-    0x0A, 0x8A, 0x01, //   Usage (Mail)
     0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x05, 0x01, //   Usage Page (Generic Desktop Ctrls)
+    0x09, 0x39, //   Usage (Hat switch)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x07, //   Logical Maximum (7)
+    0x35, 0x00, //   Physical Minimum (0)
+    0x46, 0x3B, 0x01, //   Physical Maximum (315)
+    0x65, 0x14, //   Unit (System: English Rotation, Length: Centimeter)
+    0x75, 0x04, //   Report Size (4)
+    0x95, 0x01, //   Report Count (1)
+    0x81, 0x42, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,Null State)
+    0x65, 0x00, //   Unit (None)
+    0x45, 0x00, //   Physical Maximum (0)
+    0x81, 0x03, //   Input (Const,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x09, 0x30, //   Usage (X)
+    0x09, 0x31, //   Usage (Y)
+    0x09, 0x33, //   Usage (Rx)
+    0x09, 0x34, //   Usage (Ry)
+    0x09, 0x32, //   Usage (Z)
+    0x09, 0x35, //   Usage (Rz)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x06, //   Report Count (6)
+    0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0xC0  // End Collection
+];
+
+const PEN_REPORT_DESC: &[u8] = &[
+    0x05, 0x0D, // Usage Page (Digitizer)
+    0x09, 0x02, // Usage (Pen)
+    0xA1, 0x01, // Collection (Application)
+    0x09, 0x20, //   Usage (Stylus)
+    0xA1, 0x00, //   Collection (Physical)
+    0x09, 0x42, //     Usage (Tip Switch)
+    0x09, 0x44, //     Usage (Barrel Switch)
+    0x09, 0x45, //     Usage (Eraser)
+    0x09, 0x3C, //     Usage (Invert)
+    0x09, 0x32, //     Usage (In Range)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x75, 0x01, //     Report Size (1)
+    0x95, 0x05, //     Report Count (5)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x95, 0x03, //     Report Count (3)
+    0x81, 0x03, //     Input (Const,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x05, 0x01, //     Usage Page (Generic Desktop Ctrls)
+    0x09, 0x30, //     Usage (X)
+    0x09, 0x31, //     Usage (Y)
+    0x26, 0xFF, 0x7F, //     Logical Maximum (32767)
+    0x55, 0x0E, //     Unit Exponent (-2)
+    0x65, 0x11, //     Unit (System: SI Linear, Length: Centimeter)
+    0x35, 0x00, //     Physical Minimum (0)
+    0x46, 0xB8, 0x0B, //     Physical Maximum (3000)
+    0x75, 0x10, //     Report Size (16)
+    0x95, 0x02, //     Report Count (2)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x05, 0x0D, //     Usage Page (Digitizer)
+    0x09, 0x30, //     Usage (Tip Pressure)
+    0x26, 0xFF, 0x0F, //     Logical Maximum (4095)
+    0x55, 0x00, //     Unit Exponent (0)
+    0x65, 0x00, //     Unit (None)
+    0x45, 0x00, //     Physical Maximum (0)
+    0x95, 0x01, //     Report Count (1)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x09, 0x3D, //     Usage (X Tilt)
+    0x09, 0x3E, //     Usage (Y Tilt)
+    0x15, 0xA6, //     Logical Minimum (-90)
+    0x25, 0x5A, //     Logical Maximum (90)
+    0x65, 0x14, //     Unit (System: English Rotation, Length: Centimeter)
+    0x35, 0xA6, //     Physical Minimum (-90)
+    0x45, 0x5A, //     Physical Maximum (90)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x02, //     Report Count (2)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0xC0, //   End Collection
+    0xC0  // End Collection
+];
+
+/// A multi-touch touchpad with five contacts for hosts with a generic multi-touch driver like Linux.
+/// Windows only accepts precision touchpads that answer feature requests for their capabilities and certification,
+/// which the gadget driver can't do, so the descriptor doesn't declare any feature reports.
+const TOUCHPAD_REPORT_DESC: &[u8] = &[
+    0x05, 0x0D, // Usage Page (Digitizer)
+    0x09, 0x05, // Usage (Touch Pad)
+    0xA1, 0x01, // Collection (Application)
+    0x85, 0x01, //   Report ID (1)
+    0x09, 0x22, //   Usage (Finger)
+    0xA1, 0x02, //   Collection (Logical)
+    0x55, 0x00, //     Unit Exponent (0)
+    0x65, 0x00, //     Unit (None)
+    0x45, 0x00, //     Physical Maximum (0)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x09, 0x47, //     Usage (Confidence)
+    0x09, 0x42, //     Usage (Tip Switch)
+    0x75, 0x01, //     Report Size (1)
+    0x95, 0x02, //     Report Count (2)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x95, 0x06, //     Report Count (6)
+    0x81, 0x03, //     Input (Const,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x09, 0x51, //     Usage (Contact Identifier)
+    0x26, 0xFF, 0x00, //     Logical Maximum (255)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x01, //     Report Count (1)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x05, 0x01, //     Usage Page (Generic Desktop Ctrls)
+    0x55, 0x0E, //     Unit Exponent (-2)
+    0x65, 0x11, //     Unit (System: SI Linear, Length: Centimeter)
+    0x75, 0x10, //     Report Size (16)
+    0x09, 0x30, //     Usage (X)
+    0x26, 0xA0, 0x0F, //     Logical Maximum (4000)
+    0x46, 0xE8, 0x03, //     Physical Maximum (1000)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x09, 0x31, //     Usage (Y)
+    0x26, 0xC4, 0x09, //     Logical Maximum (2500)
+    0x46, 0x71, 0x02, //     Physical Maximum (625)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x05, 0x0D, //     Usage Page (Digitizer)
+    0xC0, //   End Collection
+    0x09, 0x22, //   Usage (Finger)
+    0xA1, 0x02, //   Collection (Logical)
+    0x55, 0x00, //     Unit Exponent (0)
+    0x65, 0x00, //     Unit (None)
+    0x45, 0x00, //     Physical Maximum (0)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x09, 0x47, //     Usage (Confidence)
+    0x09, 0x42, //     Usage (Tip Switch)
+    0x75, 0x01, //     Report Size (1)
+    0x95, 0x02, //     Report Count (2)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x95, 0x06, //     Report Count (6)
+    0x81, 0x03, //     Input (Const,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x09, 0x51, //     Usage (Contact Identifier)
+    0x26, 0xFF, 0x00, //     Logical Maximum (255)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x01, //     Report Count (1)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x05, 0x01, //     Usage Page (Generic Desktop Ctrls)
+    0x55, 0x0E, //     Unit Exponent (-2)
+    0x65, 0x11, //     Unit (System: SI Linear, Length: Centimeter)
+    0x75, 0x10, //     Report Size (16)
+    0x09, 0x30, //     Usage (X)
+    0x26, 0xA0, 0x0F, //     Logical Maximum (4000)
+    0x46, 0xE8, 0x03, //     Physical Maximum (1000)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x09, 0x31, //     Usage (Y)
+    0x26, 0xC4, 0x09, //     Logical Maximum (2500)
+    0x46, 0x71, 0x02, //     Physical Maximum (625)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x05, 0x0D, //     Usage Page (Digitizer)
+    0xC0, //   End Collection
+    0x09, 0x22, //   Usage (Finger)
+    0xA1, 0x02, //   Collection (Logical)
+    0x55, 0x00, //     Unit Exponent (0)
+    0x65, 0x00, //     Unit (None)
+    0x45, 0x00, //     Physical Maximum (0)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x09, 0x47, //     Usage (Confidence)
+    0x09, 0x42, //     Usage (Tip Switch)
+    0x75, 0x01, //     Report Size (1)
+    0x95, 0x02, //     Report Count (2)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x95, 0x06, //     Report Count (6)
+    0x81, 0x03, //     Input (Const,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x09, 0x51, //     Usage (Contact Identifier)
+    0x26, 0xFF, 0x00, //     Logical Maximum (255)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x01, //     Report Count (1)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x05, 0x01, //     Usage Page (Generic Desktop Ctrls)
+    0x55, 0x0E, //     Unit Exponent (-2)
+    0x65, 0x11, //     Unit (System: SI Linear, Length: Centimeter)
+    0x75, 0x10, //     Report Size (16)
+    0x09, 0x30, //     Usage (X)
+    0x26, 0xA0, 0x0F, //     Logical Maximum (4000)
+    0x46, 0xE8, 0x03, //     Physical Maximum (1000)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x09, 0x31, //     Usage (Y)
+    0x26, 0xC4, 0x09, //     Logical Maximum (2500)
+    0x46, 0x71, 0x02, //     Physical Maximum (625)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x05, 0x0D, //     Usage Page (Digitizer)
+    0xC0, //   End Collection
+    0x09, 0x22, //   Usage (Finger)
+    0xA1, 0x02, //   Collection (Logical)
+    0x55, 0x00, //     Unit Exponent (0)
+    0x65, 0x00, //     Unit (None)
+    0x45, 0x00, //     Physical Maximum (0)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x09, 0x47, //     Usage (Confidence)
+    0x09, 0x42, //     Usage (Tip Switch)
+    0x75, 0x01, //     Report Size (1)
+    0x95, 0x02, //     Report Count (2)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x95, 0x06, //     Report Count (6)
+    0x81, 0x03, //     Input (Const,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x09, 0x51, //     Usage (Contact Identifier)
+    0x26, 0xFF, 0x00, //     Logical Maximum (255)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x01, //     Report Count (1)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x05, 0x01, //     Usage Page (Generic Desktop Ctrls)
+    0x55, 0x0E, //     Unit Exponent (-2)
+    0x65, 0x11, //     Unit (System: SI Linear, Length: Centimeter)
+    0x75, 0x10, //     Report Size (16)
+    0x09, 0x30, //     Usage (X)
+    0x26, 0xA0, 0x0F, //     Logical Maximum (4000)
+    0x46, 0xE8, 0x03, //     Physical Maximum (1000)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x09, 0x31, //     Usage (Y)
+    0x26, 0xC4, 0x09, //     Logical Maximum (2500)
+    0x46, 0x71, 0x02, //     Physical Maximum (625)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x05, 0x0D, //     Usage Page (Digitizer)
+    0xC0, //   End Collection
+    0x09, 0x22, //   Usage (Finger)
+    0xA1, 0x02, //   Collection (Logical)
+    0x55, 0x00, //     Unit Exponent (0)
+    0x65, 0x00, //     Unit (None)
+    0x45, 0x00, //     Physical Maximum (0)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x09, 0x47, //     Usage (Confidence)
+    0x09, 0x42, //     Usage (Tip Switch)
+    0x75, 0x01, //     Report Size (1)
+    0x95, 0x02, //     Report Count (2)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x95, 0x06, //     Report Count (6)
+    0x81, 0x03, //     Input (Const,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x09, 0x51, //     Usage (Contact Identifier)
+    0x26, 0xFF, 0x00, //     Logical Maximum (255)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x01, //     Report Count (1)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x05, 0x01, //     Usage Page (Generic Desktop Ctrls)
+    0x55, 0x0E, //     Unit Exponent (-2)
+    0x65, 0x11, //     Unit (System: SI Linear, Length: Centimeter)
+    0x75, 0x10, //     Report Size (16)
+    0x09, 0x30, //     Usage (X)
+    0x26, 0xA0, 0x0F, //     Logical Maximum (4000)
+    0x46, 0xE8, 0x03, //     Physical Maximum (1000)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x09, 0x31, //     Usage (Y)
+    0x26, 0xC4, 0x09, //     Logical Maximum (2500)
+    0x46, 0x71, 0x02, //     Physical Maximum (625)
+    0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x05, 0x0D, //     Usage Page (Digitizer)
+    0xC0, //   End Collection
+    0x55, 0x0C, //   Unit Exponent (-4)
+    0x66, 0x01, 0x10, //   Unit (System: SI Linear, Time: Seconds)
+    0x47, 0xFF, 0xFF, 0x00, 0x00, //   Physical Maximum (65535)
+    0x27, 0xFF, 0xFF, 0x00, 0x00, //   Logical Maximum (65535)
+    0x75, 0x10, //   Report Size (16)
+    0x95, 0x01, //   Report Count (1)
+    0x09, 0x56, //   Usage (Scan Time)
+    0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x55, 0x00, //   Unit Exponent (0)
+    0x65, 0x00, //   Unit (None)
+    0x45, 0x00, //   Physical Maximum (0)
+    0x09, 0x54, //   Usage (Contact Count)
+    0x25, 0x7F, //   Logical Maximum (127)
+    0x75, 0x08, //   Report Size (8)
+    0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x05, 0x09, //   Usage Page (Button)
+    0x09, 0x01, //   Usage (0x01)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x95, 0x07, //   Report Count (7)
+    0x81, 0x03, //   Input (Const,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
     0xC0  // End Collection
 ];
 
+/// The HID functions of the USB gadget. Each function shows up as `/dev/hidgN` in the order of `gadget_functions`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HidFunction {
+    Keyboard,
+    Mouse,
+    ConsumerDevice,
+    SystemControl,
+    Gamepad,
+    Pen,
+    Touchpad
+}
+
+impl HidFunction {
+    const DEFAULT: [HidFunction; 4] = [
+        HidFunction::Keyboard,
+        HidFunction::Mouse,
+        HidFunction::ConsumerDevice,
+        HidFunction::SystemControl
+    ];
+
+    fn protocol(self) -> &'static str {
+        match self {
+            HidFunction::Keyboard | HidFunction::Mouse | HidFunction::ConsumerDevice => "1",
+            HidFunction::SystemControl | HidFunction::Gamepad | HidFunction::Pen | HidFunction::Touchpad => "0"
+        }
+    }
+
+    fn subclass(self) -> &'static str {
+        self.protocol()
+    }
+
+    fn report_length(self) -> usize {
+        match self {
+            HidFunction::Keyboard => 8,
+            HidFunction::Mouse => 9,
+            HidFunction::ConsumerDevice => 8,
+            HidFunction::SystemControl => 1,
+            HidFunction::Gamepad => 9,
+            HidFunction::Pen => 9,
+            HidFunction::Touchpad => 35
+        }
+    }
+
+    fn report_desc(self) -> &'static [u8] {
+        match self {
+            HidFunction::Keyboard => KEYBOARD_REPORT_DESC,
+            HidFunction::Mouse => MOUSE_REPORT_DESC,
+            HidFunction::ConsumerDevice => CONSUMER_REPORT_DESC,
+            HidFunction::SystemControl => SYSTEM_CONTROL_REPORT_DESC,
+            HidFunction::Gamepad => GAMEPAD_REPORT_DESC,
+            HidFunction::Pen => PEN_REPORT_DESC,
+            HidFunction::Touchpad => TOUCHPAD_REPORT_DESC
+        }
+    }
+}
+
+#[derive(Debug)]
+struct GadgetConfig {
+    optional_functions: Vec<HidFunction>,
+    mass_storage: Option<PathBuf>,
+    serial: bool,
+    stand_in: Option<PathBuf>
+}
+
+static GADGET_CONFIG: Mutex<GadgetConfig> = Mutex::new(GadgetConfig {
+    optional_functions: Vec::new(),
+    mass_storage: None,
+    serial: false,
+    stand_in: None
+});
+
+/// Adds an optional function to the gadget. Has to be called before the first device gets opened.
+pub fn enable_function(function: HidFunction) {
+    let mut config = GADGET_CONFIG.lock().expect("Could not acquire lock");
+    if !HidFunction::DEFAULT.contains(&function) && !config.optional_functions.contains(&function) {
+        config.optional_functions.push(function);
+    }
+}
+
+/// Adds a mass storage function backed by `image` to the gadget. Has to be called before the first device gets opened.
+/// The image doesn't have to exist yet; the drive shows up without a medium in that case.
+pub fn enable_mass_storage(image: &Path) -> Result<()> {
+    let image = env::current_dir()?.join(image);
+    GADGET_CONFIG.lock().expect("Could not acquire lock").mass_storage = Some(image);
+    Ok(())
+}
+
+/// Adds a CDC-ACM serial function to the gadget that shows up as `/dev/ttyGS0`. Has to be called before the first device gets opened.
+pub fn enable_serial() {
+    GADGET_CONFIG.lock().expect("Could not acquire lock").serial = true;
+}
+
+fn serial_enabled() -> bool {
+    GADGET_CONFIG.lock().expect("Could not acquire lock").serial
+}
+
+/// Writes all reports into `hidgN` files in `dir` instead of configuring a USB gadget
+pub fn use_stand_in(dir: PathBuf) {
+    GADGET_CONFIG.lock().expect("Could not acquire lock").stand_in = Some(dir);
+}
+
+fn mass_storage_image() -> Option<PathBuf> {
+    GADGET_CONFIG
+        .lock()
+        .expect("Could not acquire lock")
+        .mass_storage
+        .clone()
+}
+
+fn stand_in_dir() -> Option<PathBuf> {
+    GADGET_CONFIG
+        .lock()
+        .expect("Could not acquire lock")
+        .stand_in
+        .clone()
+}
+
+fn gadget_functions() -> Vec<HidFunction> {
+    let config = GADGET_CONFIG.lock().expect("Could not acquire lock");
+    HidFunction::DEFAULT
+        .into_iter()
+        .chain(config.optional_functions.iter().copied())
+        .collect()
+}
+
+async fn open_device(function: HidFunction) -> Result<(Option<ConfigFsHandle>, File)> {
+    let index = gadget_functions()
+        .iter()
+        .position(|f| *f == function)
+        .ok_or_else(|| anyhow!("The {:?} function is not enabled", function))?;
+    match stand_in_dir() {
+        Some(dir) => Ok((None, File::create(dir.join(format!("hidg{}", index))).await?)),
+        None => {
+            let handle = asyncify(ConfigFsHandle::new).await?;
+            let device = OpenOptions::new()
+                .write(true)
+                .append(true)
+                .open(format!("/dev/hidg{}", index))
+                .await?;
+            Ok((Some(handle), device))
+        }
+    }
+}
+
 fn enable_hid() -> Result<()> {
     tracing::debug!("Enabling HID device");
 
@@ -148,44 +585,69 @@ fn enable_hid() -> Result<()> {
     fs::write("configs/c.1/bmAttributes", "0x80")?;
     fs::write("configs/c.1/MaxPower", "250")?;
 
-    fs::create_dir_all("functions/hid.usb0")?;
-    fs::write("functions/hid.usb0/protocol", "1")?;
-    fs::write("functions/hid.usb0/subclass", "1")?;
-    fs::write("functions/hid.usb0/report_length", "8")?;
-    fs::write("functions/hid.usb0/report_desc", KEYBOARD_REPORT_DESC)?;
-    unix::fs::symlink("functions/hid.usb0", "configs/c.1/hid.usb0")?;
-
-    fs::create_dir_all("functions/hid.usb1")?;
-    fs::write("functions/hid.usb1/protocol", "1")?;
-    fs::write("functions/hid.usb1/subclass", "1")?;
-    fs::write("functions/hid.usb1/report_length", "7")?;
-    fs::write("functions/hid.usb1/report_desc", MOUSE_REPORT_DESC)?;
-    unix::fs::symlink("functions/hid.usb1", "configs/c.1/hid.usb1")?;
-
-    fs::create_dir_all("functions/hid.usb2")?;
-    fs::write("functions/hid.usb2/protocol", "1")?;
-    fs::write("functions/hid.usb2/subclass", "1")?;
-    fs::write("functions/hid.usb2/report_length", "2")?;
-    fs::write("functions/hid.usb2/report_desc", CONSUMER_REPORT_DESC)?;
-    unix::fs::symlink("functions/hid.usb2", "configs/c.1/hid.usb2")?;
+    for (i, function) in gadget_functions().into_iter().enumerate() {
+        let name = format!("hid.usb{}", i);
+        fs::create_dir_all(format!("functions/{}", name))?;
+        fs::write(format!("functions/{}/protocol", name), function.protocol())?;
+        fs::write(format!("functions/{}/subclass", name), function.subclass())?;
+        fs::write(format!("functions/{}/report_length", name), function.report_length().to_string())?;
+        fs::write(format!("functions/{}/report_desc", name), function.report_desc())?;
+        unix::fs::symlink(format!("functions/{}", name), format!("configs/c.1/{}", name))?;
+    }
+
+    if let Some(image) = mass_storage_image() {
+        fs::create_dir_all(format!("functions/{}", MASS_STORAGE_FUNCTION))?;
+        fs::write(format!("functions/{}/stall", MASS_STORAGE_FUNCTION), "1")?;
+        fs::write(format!("functions/{}/lun.0/removable", MASS_STORAGE_FUNCTION), "1")?;
+        fs::write(format!("functions/{}/lun.0/ro", MASS_STORAGE_FUNCTION), "0")?;
+        if image.exists() {
+            fs::write(format!("functions/{}/lun.0/file", MASS_STORAGE_FUNCTION), image.to_string_lossy().as_bytes())?;
+        }
+        unix::fs::symlink(format!("functions/{}", MASS_STORAGE_FUNCTION), format!("configs/c.1/{}", MASS_STORAGE_FUNCTION))?;
+    }
+
+    if serial_enabled() {
+        fs::create_dir_all(format!("functions/{}", SERIAL_FUNCTION))?;
+        unix::fs::symlink(format!("functions/{}", SERIAL_FUNCTION), format!("configs/c.1/{}", SERIAL_FUNCTION))?;
+    }
 
     fs::write("os_desc/use", "1")?;
     fs::write("os_desc/b_vendor_code", "0xcd")?;
     fs::write("os_desc/qw_sign", "MSFT100")?;
     unix::fs::symlink("configs/c.1", "os_desc/c.1")?;
 
-    let udc_name = Path::new("/sys/class/udc/")
+    fs::write("UDC", find_udc()?)?;
+
+    Ok(())
+}
+
+fn find_udc() -> Result<String> {
+    Ok(Path::new("/sys/class/udc/")
         .read_dir()?
         .filter_map(|r| r.map(|e| e.file_name()).ok())
         .next()
         .ok_or_else(|| anyhow!("No UDC found"))?
         .to_str()
         .ok_or_else(|| anyhow!("UDC has an invalid name"))?
-        .to_string();
-
-    fs::write("UDC", udc_name)?;
+        .to_string())
+}
 
-    Ok(())
+/// Unbinds the gadget from the USB device controller and binds it again, which makes the host enumerate it from scratch
+pub async fn rebind_gadget() -> Result<()> {
+    if stand_in_dir().is_some() {
+        return Ok(());
+    }
+    asyncify(|| {
+        let path = Path::new("/sys/kernel/config/usb_gadget/g1/UDC");
+        if !path.exists() {
+            return Ok(());
+        }
+        tracing::debug!("Rebinding the USB gadget");
+        fs::write(path, "")?;
+        fs::write(path, find_udc()?)?;
+        Ok(())
+    })
+    .await
 }
 
 fn disable_hid() -> Result<()> {
@@ -196,15 +658,30 @@ fn disable_hid() -> Result<()> {
     fs::write("UDC", "")?;
 
     fs::remove_file("os_desc/c.1")?;
-    fs::remove_file("configs/c.1/hid.usb0")?;
-    fs::remove_file("configs/c.1/hid.usb1")?;
-    fs::remove_file("configs/c.1/hid.usb2")?;
+    let functions = gadget_functions().len();
+    for i in 0..functions {
+        fs::remove_file(format!("configs/c.1/hid.usb{}", i))?;
+    }
+    let mass_storage = mass_storage_image().is_some();
+    if mass_storage {
+        fs::remove_file(format!("configs/c.1/{}", MASS_STORAGE_FUNCTION))?;
+    }
+    let serial = serial_enabled();
+    if serial {
+        fs::remove_file(format!("configs/c.1/{}", SERIAL_FUNCTION))?;
+    }
 
     fs::remove_dir("configs/c.1/strings/0x409")?;
     fs::remove_dir("configs/c.1")?;
-    fs::remove_dir("functions/hid.usb0")?;
-    fs::remove_dir("functions/hid.usb1")?;
-    fs::remove_dir("functions/hid.usb2")?;
+    for i in 0..functions {
+        fs::remove_dir(format!("functions/hid.usb{}", i))?;
+    }
+    if mass_storage {
+        fs::remove_dir(format!("functions/{}", MASS_STORAGE_FUNCTION))?;
+    }
+    if serial {
+        fs::remove_dir(format!("functions/{}", SERIAL_FUNCTION))?;
+    }
     fs::remove_dir("strings/0x409")?;
 
     env::set_current_dir("..")?;
@@ -213,6 +690,95 @@ fn disable_hid() -> Result<()> {
     Ok(())
 }
 
+/// The USB device state of the controller that the gadget is bound to like "configured", "suspended" or "not attached"
+pub async fn udc_state() -> Result<Option<String>> {
+    if stand_in_dir().is_some() {
+        return Ok(None);
+    }
+    let udc = tokio::fs::read_to_string("/sys/kernel/config/usb_gadget/g1/UDC").await?;
+    let udc = udc.trim();
+    if udc.is_empty() {
+        return Ok(None);
+    }
+    let state = tokio::fs::read_to_string(Path::new("/sys/class/udc").join(udc).join("state")).await?;
+    Ok(Some(state.trim().to_string()))
+}
+
+const MASS_STORAGE_FUNCTION: &str = "mass_storage.usb0";
+
+/// The drive of the optional mass storage function
+#[derive(Debug)]
+pub struct MassStorage {
+    _handle: Option<ConfigFsHandle>,
+    lun: PathBuf,
+    image: PathBuf
+}
+
+impl MassStorage {
+    pub async fn new() -> Result<Self> {
+        let image = mass_storage_image().ok_or_else(|| anyhow!("The mass storage function is not enabled"))?;
+        match stand_in_dir() {
+            Some(dir) => {
+                let lun = dir.join("lun.0");
+                tokio::fs::create_dir_all(&lun).await?;
+                let storage = Self { _handle: None, lun, image };
+                storage.insert().await?;
+                Ok(storage)
+            }
+            None => Ok(Self {
+                _handle: Some(asyncify(ConfigFsHandle::new).await?),
+                lun: PathBuf::from(format!("/sys/kernel/config/usb_gadget/g1/functions/{}/lun.0", MASS_STORAGE_FUNCTION)),
+                image
+            })
+        }
+    }
+
+    pub fn image(&self) -> &Path {
+        &self.image
+    }
+
+    /// Detaches the image from the host. The host may prevent the removal of the medium, so a forced eject is used when the kernel supports it.
+    pub async fn eject(&self) -> Result<()> {
+        let forced = self.lun.join("forced_eject");
+        match forced.exists() {
+            true => tokio::fs::write(forced, "1").await?,
+            false => tokio::fs::write(self.lun.join("file"), "").await?
+        }
+        Ok(())
+    }
+
+    /// Attaches the image to the host again
+    pub async fn insert(&self) -> Result<()> {
+        if !self.image.exists() {
+            tracing::debug!("Not inserting {} because it doesn't exist", self.image.display());
+            return Ok(());
+        }
+        tokio::fs::write(self.lun.join("file"), self.image.to_string_lossy().as_bytes()).await?;
+        Ok(())
+    }
+}
+
+const SERIAL_FUNCTION: &str = "acm.usb0";
+
+/// Keeps the gadget with the optional serial function alive
+#[derive(Debug)]
+pub struct SerialFunction {
+    _handle: Option<ConfigFsHandle>
+}
+
+impl SerialFunction {
+    pub async fn new() -> Result<Self> {
+        if !serial_enabled() {
+            bail!("The serial function is not enabled");
+        }
+        let _handle = match stand_in_dir() {
+            Some(_) => None,
+            None => Some(asyncify(ConfigFsHandle::new).await?)
+        };
+        Ok(Self { _handle })
+    }
+}
+
 static CONFIG_FS_REF_COUNT: Mutex<u32> = Mutex::new(0);
 
 #[derive(Debug)]
@@ -259,27 +825,30 @@ where
 }
 
 #[derive(Debug)]
-pub struct Keyboard {
-    _handle: ConfigFsHandle,
-    device: File,
+pub struct Keyboard<W = File> {
+    _handle: Option<ConfigFsHandle>,
+    device: W,
     pressed_keys: Vec<HidKeyCode>,
     pressed_modifiers: HidModifierKeys
 }
 
 impl Keyboard {
     pub async fn new() -> Result<Self> {
-        let _handle = asyncify(ConfigFsHandle::new).await?;
-        let device = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .open("/dev/hidg0")
-            .await?;
-        Ok(Self {
-            _handle,
+        let (_handle, device) = open_device(HidFunction::Keyboard).await?;
+        let mut keyboard = Self::with_device(device);
+        keyboard._handle = _handle;
+        Ok(keyboard)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Keyboard<W> {
+    pub fn with_device(device: W) -> Self {
+        Self {
+            _handle: None,
             device,
             pressed_keys: Vec::new(),
             pressed_modifiers: HidModifierKeys::empty()
-        })
+        }
     }
 
     async fn send_report(&mut self) -> Result<()> {
@@ -318,41 +887,103 @@ impl Keyboard {
 }
 
 #[derive(Debug)]
-pub struct ConsumerDevice {
-    _handle: ConfigFsHandle,
-    device: File,
-    pressed_keys: ConsumerDeviceButtons
+pub struct ConsumerDevice<W = File> {
+    _handle: Option<ConfigFsHandle>,
+    device: W,
+    pressed_keys: Vec<u16>
 }
 
 impl ConsumerDevice {
     pub async fn new() -> Result<Self> {
-        let _handle = asyncify(ConfigFsHandle::new).await?;
-        let device = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .open("/dev/hidg2")
-            .await?;
-        Ok(Self {
-            _handle,
+        let (_handle, device) = open_device(HidFunction::ConsumerDevice).await?;
+        let mut consumer_device = Self::with_device(device);
+        consumer_device._handle = _handle;
+        Ok(consumer_device)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> ConsumerDevice<W> {
+    pub fn with_device(device: W) -> Self {
+        Self {
+            _handle: None,
             device,
-            pressed_keys: ConsumerDeviceButtons::empty()
-        })
+            pressed_keys: Vec::new()
+        }
     }
 
     async fn send_report(&mut self) -> Result<()> {
-        tracing::trace!("Wring consumer device report: {:?}", &self.pressed_keys.bits().to_le_bytes());
-        self.device
-            .write_all(&self.pressed_keys.bits().to_le_bytes())
-            .await?;
+        let mut report = [0u8; 8];
+        for (i, usage) in self.pressed_keys.iter().enumerate().take(4) {
+            report[2 * i..2 * i + 2].copy_from_slice(&usage.to_le_bytes());
+        }
+        tracing::trace!("Wring consumer device report: {:?}", &report);
+        self.device.write_all(&report).await?;
         Ok(())
     }
 
     pub async fn reset(&mut self) -> Result<()> {
-        self.pressed_keys = ConsumerDeviceButtons::empty();
+        self.pressed_keys.clear();
         self.send_report().await
     }
 
     pub async fn press_key(&mut self, key: ConsumerDeviceCode) -> Result<()> {
+        match consumer_usage(key) {
+            Some(usage) if !self.pressed_keys.contains(&usage) => {
+                self.pressed_keys.push(usage);
+                self.send_report().await
+            }
+            _ => Ok(())
+        }
+    }
+
+    pub async fn release_key(&mut self, key: ConsumerDeviceCode) -> Result<()> {
+        match consumer_usage(key) {
+            Some(usage) => {
+                self.pressed_keys.retain(|k| *k != usage);
+                self.send_report().await
+            }
+            None => Ok(())
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SystemControl<W = File> {
+    _handle: Option<ConfigFsHandle>,
+    device: W,
+    pressed_keys: SystemControlButtons
+}
+
+impl SystemControl {
+    pub async fn new() -> Result<Self> {
+        let (_handle, device) = open_device(HidFunction::SystemControl).await?;
+        let mut system_control = Self::with_device(device);
+        system_control._handle = _handle;
+        Ok(system_control)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> SystemControl<W> {
+    pub fn with_device(device: W) -> Self {
+        Self {
+            _handle: None,
+            device,
+            pressed_keys: SystemControlButtons::empty()
+        }
+    }
+
+    async fn send_report(&mut self) -> Result<()> {
+        tracing::trace!("Wring system control report: {:?}", self.pressed_keys.bits());
+        self.device.write_all(&[self.pressed_keys.bits()]).await?;
+        Ok(())
+    }
+
+    pub async fn reset(&mut self) -> Result<()> {
+        self.pressed_keys = SystemControlButtons::empty();
+        self.send_report().await
+    }
+
+    pub async fn press_key(&mut self, key: SystemControlCode) -> Result<()> {
         match key.try_into() {
             Ok(key) => {
                 self.pressed_keys.insert(key);
@@ -362,7 +993,7 @@ impl ConsumerDevice {
         }
     }
 
-    pub async fn release_key(&mut self, key: ConsumerDeviceCode) -> Result<()> {
+    pub async fn release_key(&mut self, key: SystemControlCode) -> Result<()> {
         match key.try_into() {
             Ok(key) => {
                 self.pressed_keys.remove(key);
@@ -374,51 +1005,264 @@ impl ConsumerDevice {
 }
 
 #[derive(Debug)]
-pub struct Mouse {
-    _handle: ConfigFsHandle,
-    device: File,
+pub struct Gamepad<W = File> {
+    _handle: Option<ConfigFsHandle>,
+    device: W,
+    buttons: u16,
+    hat: GamepadHat,
+    axes: [u8; GamepadAxis::ALL.len()]
+}
+
+impl Gamepad {
+    pub async fn new() -> Result<Self> {
+        let (_handle, device) = open_device(HidFunction::Gamepad).await?;
+        let mut gamepad = Self::with_device(device);
+        gamepad._handle = _handle;
+        Ok(gamepad)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Gamepad<W> {
+    pub fn with_device(device: W) -> Self {
+        Self {
+            _handle: None,
+            device,
+            buttons: 0,
+            hat: GamepadHat::Centered,
+            axes: GamepadAxis::ALL.map(GamepadAxis::neutral)
+        }
+    }
+
+    async fn send_report(&mut self) -> Result<()> {
+        let mut report = [0u8; 9];
+        report[0..=1].copy_from_slice(&self.buttons.to_le_bytes());
+        report[2] = self.hat.into();
+        report[3..].copy_from_slice(&self.axes);
+        tracing::trace!("Wring gamepad report: {:?}", &report);
+        self.device.write_all(&report).await?;
+        Ok(())
+    }
+
+    pub async fn reset(&mut self) -> Result<()> {
+        self.buttons = 0;
+        self.hat = GamepadHat::Centered;
+        self.axes = GamepadAxis::ALL.map(GamepadAxis::neutral);
+        self.send_report().await
+    }
+
+    pub async fn press_button(&mut self, button: GamepadButton) -> Result<()> {
+        match gamepad_button_mask(button) {
+            Some(mask) => {
+                self.buttons |= mask;
+                self.send_report().await
+            }
+            None => Ok(())
+        }
+    }
+
+    pub async fn release_button(&mut self, button: GamepadButton) -> Result<()> {
+        match gamepad_button_mask(button) {
+            Some(mask) => {
+                self.buttons &= !mask;
+                self.send_report().await
+            }
+            None => Ok(())
+        }
+    }
+
+    pub async fn set_hat(&mut self, hat: GamepadHat) -> Result<()> {
+        self.hat = hat;
+        self.send_report().await
+    }
+
+    pub async fn set_axis(&mut self, axis: GamepadAxis, value: u8) -> Result<()> {
+        self.axes[u8::from(axis) as usize] = value;
+        self.send_report().await
+    }
+}
+
+fn gamepad_button_mask(button: GamepadButton) -> Option<u16> {
+    match u8::from(button) {
+        0 => None,
+        n => 1u16.checked_shl(u32::from(n) - 1)
+    }
+}
+
+#[derive(Debug)]
+pub struct Pen<W = File> {
+    _handle: Option<ConfigFsHandle>,
+    device: W
+}
+
+impl Pen {
+    pub async fn new() -> Result<Self> {
+        let (_handle, device) = open_device(HidFunction::Pen).await?;
+        let mut pen = Self::with_device(device);
+        pen._handle = _handle;
+        Ok(pen)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Pen<W> {
+    pub fn with_device(device: W) -> Self {
+        Self { _handle: None, device }
+    }
+
+    pub async fn send_sample(&mut self, sample: &PenSample) -> Result<()> {
+        let report = pen_report(sample);
+        tracing::trace!("Wring pen report: {:?}", &report);
+        self.device.write_all(&report).await?;
+        Ok(())
+    }
+
+    /// Moves the pen out of range
+    pub async fn reset(&mut self) -> Result<()> {
+        self.send_sample(&PenSample::default()).await
+    }
+}
+
+/// The tip of an inverted pen reports as eraser instead of tip switch
+fn pen_report(sample: &PenSample) -> [u8; 9] {
+    let in_range = sample.in_range || sample.tip;
+    let flags = (sample.tip && !sample.eraser) as u8
+        | (sample.barrel as u8) << 1
+        | ((sample.tip && sample.eraser) as u8) << 2
+        | ((in_range && sample.eraser) as u8) << 3
+        | (in_range as u8) << 4;
+    let mut report = [0u8; 9];
+    report[0] = flags;
+    report[1..=2].copy_from_slice(&sample.x.min(PenSample::MAX_POSITION).to_le_bytes());
+    report[3..=4].copy_from_slice(&sample.y.min(PenSample::MAX_POSITION).to_le_bytes());
+    report[5..=6].copy_from_slice(&sample.pressure.min(PenSample::MAX_PRESSURE).to_le_bytes());
+    report[7] = sample.tilt_x.clamp(-90, 90) as u8;
+    report[8] = sample.tilt_y.clamp(-90, 90) as u8;
+    report
+}
+
+#[derive(Debug)]
+pub struct Touchpad<W = File> {
+    _handle: Option<ConfigFsHandle>,
+    device: W
+}
+
+impl Touchpad {
+    pub async fn new() -> Result<Self> {
+        let (_handle, device) = open_device(HidFunction::Touchpad).await?;
+        let mut touchpad = Self::with_device(device);
+        touchpad._handle = _handle;
+        Ok(touchpad)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Touchpad<W> {
+    pub fn with_device(device: W) -> Self {
+        Self { _handle: None, device }
+    }
+
+    pub async fn send_frame(&mut self, frame: &TouchFrame) -> Result<()> {
+        let report = touchpad_report(frame);
+        tracing::trace!("Wring touchpad report: {:?}", &report);
+        self.device.write_all(&report).await?;
+        Ok(())
+    }
+
+    /// Lifts all fingers and releases the button
+    pub async fn reset(&mut self) -> Result<()> {
+        self.send_frame(&TouchFrame::default()).await
+    }
+}
+
+fn touchpad_report(frame: &TouchFrame) -> [u8; 35] {
+    let mut report = [0u8; 35];
+    report[0] = 0x01;
+    for (contact, finger) in frame.contacts().iter().zip(report[1..31].chunks_exact_mut(6)) {
+        finger[0] = contact.confidence as u8 | (contact.tip as u8) << 1;
+        finger[1] = contact.id;
+        finger[2..=3].copy_from_slice(&contact.x.min(TouchFrame::MAX_X).to_le_bytes());
+        finger[4..=5].copy_from_slice(&contact.y.min(TouchFrame::MAX_Y).to_le_bytes());
+    }
+    report[31..=32].copy_from_slice(&frame.scan_time.to_le_bytes());
+    report[33] = frame.contacts().len() as u8;
+    report[34] = frame.button as u8;
+    report
+}
+
+const MAX_MOVE_INTERVAL: Duration = Duration::from_millis(50);
+const MIN_WRITE_LATENCY: Duration = Duration::from_micros(100);
+
+#[derive(Debug)]
+pub struct Mouse<W = File> {
+    _handle: Option<ConfigFsHandle>,
+    device: W,
     pressed_buttons: HidMouseButtons,
-    tess_factor: i16
+    tess_factor: u32,
+    write_latency: Duration,
+    move_interval: Duration,
+    last_move: Option<Instant>,
+    pending: (i32, i32),
+    remaining_steps: u32,
+    step_interval: Duration,
+    next_step: Option<Instant>,
+    /// The high resolution units that didn't add up to a whole detent yet
+    scroll_remainder: (i32, i32)
 }
 
 impl Mouse {
     pub async fn new(tess_factor: NonZeroU8) -> Result<Self> {
-        let _handle = asyncify(ConfigFsHandle::new).await?;
-        let device = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .open("/dev/hidg1")
-            .await?;
-        Ok(Self {
-            _handle,
+        let (_handle, device) = open_device(HidFunction::Mouse).await?;
+        let mut mouse = Self::with_device(device, tess_factor);
+        mouse._handle = _handle;
+        Ok(mouse)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Mouse<W> {
+    pub fn with_device(device: W, tess_factor: NonZeroU8) -> Self {
+        Self {
+            _handle: None,
             device,
             pressed_buttons: HidMouseButtons::empty(),
-            tess_factor: i16::from(tess_factor.get())
-        })
+            tess_factor: u32::from(tess_factor.get()),
+            write_latency: Duration::ZERO,
+            move_interval: MAX_MOVE_INTERVAL,
+            last_move: None,
+            pending: (0, 0),
+            remaining_steps: 0,
+            step_interval: Duration::ZERO,
+            next_step: None,
+            scroll_remainder: (0, 0)
+        }
     }
 
-    async fn send_report(&mut self, dx: i16, dy: i16, dv: i8, dh: i8) -> Result<()> {
-        let mut report = [0u8; 7];
+    async fn send_report(&mut self, dx: i16, dy: i16, dv: i16, dh: i16) -> Result<()> {
+        let mut report = [0u8; 9];
         report[0] = self.pressed_buttons.bits();
 
         report[1..=2].copy_from_slice(&dx.to_le_bytes());
         report[3..=4].copy_from_slice(&dy.to_le_bytes());
-        report[5..=5].copy_from_slice(&dv.to_le_bytes());
-        report[6..=6].copy_from_slice(&dh.to_le_bytes());
+        report[5..=6].copy_from_slice(&dv.to_le_bytes());
+        report[7..=8].copy_from_slice(&dh.to_le_bytes());
 
         tracing::trace!("Wring mouse report: {:?}", &report);
+        let start = Instant::now();
         self.device.write_all(&report).await?;
+        self.write_latency = (self.write_latency * 7 + start.elapsed()) / 8;
         Ok(())
     }
 
     pub async fn reset(&mut self) -> Result<()> {
         self.pressed_buttons = HidMouseButtons::empty();
+        self.pending = (0, 0);
+        self.remaining_steps = 0;
+        self.next_step = None;
+        self.scroll_remainder = (0, 0);
         self.send_report(0, 0, 0, 0).await
     }
 
     pub async fn press_button(&mut self, button: HidButtonCode) -> Result<()> {
         match button.try_into() {
             Ok(button) => {
+                self.flush_movement().await?;
                 self.pressed_buttons.insert(button);
                 self.send_report(0, 0, 0, 0).await
             }
@@ -429,6 +1273,7 @@ impl Mouse {
     pub async fn release_button(&mut self, button: HidButtonCode) -> Result<()> {
         match button.try_into() {
             Ok(button) => {
+                self.flush_movement().await?;
                 self.pressed_buttons.remove(button);
                 self.send_report(0, 0, 0, 0).await
             }
@@ -436,38 +1281,109 @@ impl Mouse {
         }
     }
 
-    pub async fn move_by(&mut self, mut dx: i16, mut dy: i16) -> Result<()> {
-        let sx = abs_max(dx / self.tess_factor, dx.signum());
-        let sy = abs_max(dy / self.tess_factor, dy.signum());
-        while dx != 0 || dy != 0 {
-            let tx = abs_min(dx, sx);
-            let ty = abs_min(dy, sy);
-            self.send_report(tx, ty, 0, 0).await?;
-            dx -= tx;
-            dy -= ty;
+    pub fn device(&self) -> &W {
+        &self.device
+    }
+
+    pub fn write_latency(&self) -> Duration {
+        self.write_latency
+    }
+
+    /// Whether writing the reports takes longer than the time between two consecutive movements.
+    pub fn is_behind(&self) -> bool {
+        self.write_latency * self.remaining_steps.max(1) > self.move_interval
+    }
+
+    /// The point in time at which `send_next_step` should be called to continue the current movement.
+    pub fn next_step(&self) -> Option<Instant> {
+        self.next_step
+    }
+
+    /// Queues a relative movement and spreads it across multiple reports until the next movement is expected.
+    ///
+    /// The number of reports is limited by both the tesselation factor and the observed write latency.
+    pub async fn move_by(&mut self, dx: i32, dy: i32) -> Result<()> {
+        let now = Instant::now();
+        if let Some(last) = self.last_move.replace(now) {
+            let interval = now.duration_since(last).min(MAX_MOVE_INTERVAL);
+            self.move_interval = (self.move_interval * 3 + interval) / 4;
+        }
+        self.pending.0 += dx;
+        self.pending.1 += dy;
+
+        let affordable = self.move_interval.as_micros() / self.write_latency.max(MIN_WRITE_LATENCY).as_micros();
+        self.remaining_steps = (affordable as u32).clamp(1, self.tess_factor);
+        self.step_interval = self.move_interval / self.remaining_steps;
+        self.send_next_step().await
+    }
+
+    pub async fn send_next_step(&mut self) -> Result<()> {
+        if self.pending == (0, 0) {
+            self.remaining_steps = 0;
+            self.next_step = None;
+            return Ok(());
+        }
+        let steps = self.remaining_steps.max(1) as i32;
+        let sx = clamp_i16(abs_max(self.pending.0 / steps, self.pending.0.signum()));
+        let sy = clamp_i16(abs_max(self.pending.1 / steps, self.pending.1.signum()));
+        self.send_report(sx, sy, 0, 0).await?;
+        self.pending.0 -= i32::from(sx);
+        self.pending.1 -= i32::from(sy);
+        self.remaining_steps = self.remaining_steps.saturating_sub(1);
+        self.next_step = match self.pending == (0, 0) {
+            true => None,
+            false => Some(Instant::now() + self.step_interval)
+        };
+        Ok(())
+    }
+
+    /// Immediately sends all outstanding movement
+    pub async fn flush_movement(&mut self) -> Result<()> {
+        self.remaining_steps = 1;
+        while self.pending != (0, 0) {
+            self.send_next_step().await?;
         }
         Ok(())
     }
 
-    pub async fn scroll_vertical(&mut self, amount: i8) -> Result<()> {
-        self.send_report(0, 0, amount, 0).await
+    /// Scrolls by `amount` high resolution units (see `SCROLL_RESOLUTION`)
+    pub async fn scroll_vertical(&mut self, amount: i32) -> Result<()> {
+        match take_wheel_delta(&mut self.scroll_remainder.0, amount) {
+            0 => Ok(()),
+            delta => self.send_report(0, 0, delta, 0).await
+        }
     }
 
-    pub async fn scroll_horizontal(&mut self, amount: i8) -> Result<()> {
-        self.send_report(0, 0, 0, amount).await
+    /// Scrolls by `amount` high resolution units (see `SCROLL_RESOLUTION`)
+    pub async fn scroll_horizontal(&mut self, amount: i32) -> Result<()> {
+        match take_wheel_delta(&mut self.scroll_remainder.1, amount) {
+            0 => Ok(()),
+            delta => self.send_report(0, 0, 0, delta).await
+        }
     }
 }
 
-fn abs_max(a: i16, b: i16) -> i16 {
-    if a.abs() >= b.abs() { a } else { b }
+/// Converts `amount` into whole detents and keeps whatever is too small to be reported in `remainder`.
+/// The gadget can't tell which resolution multiplier a host would pick, so the wheel only reports detents.
+fn take_wheel_delta(remainder: &mut i32, amount: i32) -> i16 {
+    let total = remainder.saturating_add(amount);
+    *remainder = total % SCROLL_RESOLUTION;
+    clamp_i16(total / SCROLL_RESOLUTION)
 }
 
-fn abs_min(a: i16, b: i16) -> i16 {
-    if a.abs() <= b.abs() { a } else { b }
+fn clamp_i16(v: i32) -> i16 {
+    v.clamp(i32::from(i16::MIN), i32::from(i16::MAX)) as i16
 }
 
-pub use flags::{ConsumerDeviceButtons, HidModifierKeys, HidMouseButtons};
-use inputshare_common::{ConsumerDeviceCode, HidButtonCode, HidKeyCode};
+fn abs_max(a: i32, b: i32) -> i32 {
+    if a.abs() >= b.abs() { a } else { b }
+}
+
+pub use flags::{HidModifierKeys, HidMouseButtons, SystemControlButtons};
+use inputshare_common::{
+    ConsumerDeviceCode, GamepadAxis, GamepadButton, GamepadHat, HidButtonCode, HidKeyCode, PenSample, SystemControlCode,
+    TouchFrame, SCROLL_RESOLUTION
+};
 
 //#[allow(non_upper_case_globals)]
 pub mod flags {
@@ -495,49 +1411,68 @@ pub mod flags {
         }
 
         #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        pub struct ConsumerDeviceButtons: u16 {
-            const NextTrack        = 0x0001;
-            const PreviousTrack    = 0x0002;
-            const Stop             = 0x0004;
-            const PlayPause        = 0x0008;
-            const Mute             = 0x0010;
-            const VolumeUp         = 0x0020;
-            const VolumeDown       = 0x0040;
-            const BrowserHome      = 0x0080;
-            const MyComputer       = 0x0100;
-            const Calculator       = 0x0200;
-            const BrowserFavorites = 0x0400;
-            const BrowserSearch    = 0x0800;
-            const BrowserStop      = 0x1000;
-            const BrowserBack      = 0x2000;
-            const MediaSelect      = 0x4000;
-            const Mail             = 0x8000;
-        }
-    }
-}
-
-impl TryFrom<ConsumerDeviceCode> for ConsumerDeviceButtons {
+        pub struct SystemControlButtons: u8 {
+            const PowerDown = 0x01;
+            const Sleep     = 0x02;
+            const WakeUp    = 0x04;
+        }
+    }
+}
+
+/// The usage id of the key on the consumer usage page (0x0C)
+fn consumer_usage(code: ConsumerDeviceCode) -> Option<u16> {
+    match code {
+        ConsumerDeviceCode::None => None,
+        ConsumerDeviceCode::NextTrack => Some(0x00B5),
+        ConsumerDeviceCode::PreviousTrack => Some(0x00B6),
+        ConsumerDeviceCode::Stop => Some(0x00B7),
+        ConsumerDeviceCode::PlayPause => Some(0x00CD),
+        ConsumerDeviceCode::Mute => Some(0x00E2),
+        ConsumerDeviceCode::BassBoost => Some(0x00E5),
+        ConsumerDeviceCode::Loudness => Some(0x00E7),
+        ConsumerDeviceCode::VolumeUp => Some(0x00E9),
+        ConsumerDeviceCode::VolumeDown => Some(0x00EA),
+        ConsumerDeviceCode::BassUp => Some(0x0152),
+        ConsumerDeviceCode::BassDown => Some(0x0153),
+        ConsumerDeviceCode::TrebleUp => Some(0x0154),
+        ConsumerDeviceCode::TrebleDown => Some(0x0155),
+        ConsumerDeviceCode::MediaSelect => Some(0x0183),
+        ConsumerDeviceCode::Mail => Some(0x018A),
+        ConsumerDeviceCode::Calculator => Some(0x0192),
+        ConsumerDeviceCode::MyComputer => Some(0x0194),
+        ConsumerDeviceCode::BrowserSearch => Some(0x0221),
+        ConsumerDeviceCode::BrowserHome => Some(0x0223),
+        ConsumerDeviceCode::BrowserBack => Some(0x0224),
+        ConsumerDeviceCode::BrowserForward => Some(0x0225),
+        ConsumerDeviceCode::BrowserStop => Some(0x0226),
+        ConsumerDeviceCode::BrowserRefresh => Some(0x0227),
+        ConsumerDeviceCode::BrowserFavorites => Some(0x022A),
+        ConsumerDeviceCode::FastForward => Some(0x00B3),
+        ConsumerDeviceCode::Rewind => Some(0x00B4),
+        ConsumerDeviceCode::Eject => Some(0x00B8),
+        ConsumerDeviceCode::RandomPlay => Some(0x00B9),
+        ConsumerDeviceCode::Repeat => Some(0x00BC),
+        ConsumerDeviceCode::BrightnessUp => Some(0x006F),
+        ConsumerDeviceCode::BrightnessDown => Some(0x0070),
+        ConsumerDeviceCode::ScreenSaver => Some(0x019E),
+        ConsumerDeviceCode::ControlPanel => Some(0x019F),
+        ConsumerDeviceCode::TaskManager => Some(0x01A6),
+        ConsumerDeviceCode::InternetBrowser => Some(0x0196),
+        ConsumerDeviceCode::ZoomIn => Some(0x022D),
+        ConsumerDeviceCode::ZoomOut => Some(0x022E),
+        ConsumerDeviceCode::Play => Some(0x00B0)
+    }
+}
+
+impl TryFrom<SystemControlCode> for SystemControlButtons {
     type Error = ();
 
-    fn try_from(value: ConsumerDeviceCode) -> std::result::Result<Self, Self::Error> {
+    fn try_from(value: SystemControlCode) -> std::result::Result<Self, Self::Error> {
         match value {
-            ConsumerDeviceCode::NextTrack => Ok(ConsumerDeviceButtons::NextTrack),
-            ConsumerDeviceCode::PreviousTrack => Ok(ConsumerDeviceButtons::PreviousTrack),
-            ConsumerDeviceCode::Stop => Ok(ConsumerDeviceButtons::Stop),
-            ConsumerDeviceCode::PlayPause => Ok(ConsumerDeviceButtons::PlayPause),
-            ConsumerDeviceCode::Mute => Ok(ConsumerDeviceButtons::Mute),
-            ConsumerDeviceCode::VolumeUp => Ok(ConsumerDeviceButtons::VolumeUp),
-            ConsumerDeviceCode::VolumeDown => Ok(ConsumerDeviceButtons::VolumeDown),
-            ConsumerDeviceCode::MediaSelect => Ok(ConsumerDeviceButtons::MediaSelect),
-            ConsumerDeviceCode::Mail => Ok(ConsumerDeviceButtons::Mail),
-            ConsumerDeviceCode::Calculator => Ok(ConsumerDeviceButtons::Calculator),
-            ConsumerDeviceCode::MyComputer => Ok(ConsumerDeviceButtons::MyComputer),
-            ConsumerDeviceCode::BrowserSearch => Ok(ConsumerDeviceButtons::BrowserSearch),
-            ConsumerDeviceCode::BrowserHome => Ok(ConsumerDeviceButtons::BrowserHome),
-            ConsumerDeviceCode::BrowserBack => Ok(ConsumerDeviceButtons::BrowserBack),
-            ConsumerDeviceCode::BrowserStop => Ok(ConsumerDeviceButtons::BrowserStop),
-            ConsumerDeviceCode::BrowserFavorites => Ok(ConsumerDeviceButtons::BrowserFavorites),
-            _ => Err(())
+            SystemControlCode::None => Err(()),
+            SystemControlCode::PowerDown => Ok(SystemControlButtons::PowerDown),
+            SystemControlCode::Sleep => Ok(SystemControlButtons::Sleep),
+            SystemControlCode::WakeUp => Ok(SystemControlButtons::WakeUp)
         }
     }
 }
@@ -574,3 +1509,201 @@ impl TryFrom<HidKeyCode> for HidModifierKeys {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use inputshare_common::{TouchContact, TouchFrame};
+
+    use super::*;
+
+    fn contact(id: u8, x: u16, y: u16) -> TouchContact {
+        TouchContact {
+            id,
+            tip: true,
+            confidence: true,
+            x,
+            y
+        }
+    }
+
+    #[test]
+    fn touchpad_report_layout() {
+        let lifted = TouchContact {
+            tip: false,
+            confidence: false,
+            ..contact(9, 0x0102, 0x0304)
+        };
+        let report = touchpad_report(&TouchFrame::new(0xABCD, true, &[contact(3, 1000, 2000), lifted]));
+        assert_eq!(report[0], 0x01);
+        assert_eq!(report[1..7], [0b11, 3, 0xE8, 0x03, 0xD0, 0x07]);
+        assert_eq!(report[7..13], [0b00, 9, 0x02, 0x01, 0x04, 0x03]);
+        assert!(report[13..31].iter().all(|b| *b == 0));
+        assert_eq!(report[31..=32], [0xCD, 0xAB]);
+        assert_eq!(report[33], 2);
+        assert_eq!(report[34], 1);
+    }
+
+    #[test]
+    fn touchpad_report_clamps_positions() {
+        let report = touchpad_report(&TouchFrame::new(0, false, &[contact(0, u16::MAX, u16::MAX)]));
+        assert_eq!(u16::from_le_bytes([report[3], report[4]]), TouchFrame::MAX_X);
+        assert_eq!(u16::from_le_bytes([report[5], report[6]]), TouchFrame::MAX_Y);
+        assert_eq!(report[34], 0);
+    }
+
+    #[test]
+    fn touchpad_report_limits_contacts() {
+        let contacts: Vec<TouchContact> = (0..8).map(|id| contact(id, 1, 1)).collect();
+        let report = touchpad_report(&TouchFrame::new(0, false, &contacts));
+        assert_eq!(report[33] as usize, TouchFrame::MAX_CONTACTS);
+        assert_eq!(report[26], 4);
+    }
+
+    #[test]
+    fn empty_touchpad_report() {
+        let report = touchpad_report(&TouchFrame::default());
+        assert_eq!(report[0], 0x01);
+        assert!(report[1..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn pen_report_layout() {
+        let sample = PenSample {
+            in_range: true,
+            tip: true,
+            barrel: true,
+            eraser: false,
+            x: 0x1234,
+            y: 0x5678,
+            pressure: 0x0ABC,
+            tilt_x: 45,
+            tilt_y: -30
+        };
+        // There is no report id because the pen is the only report of its function
+        assert_eq!(pen_report(&sample), [0b10011, 0x34, 0x12, 0x78, 0x56, 0xBC, 0x0A, 45, (-30i8) as u8]);
+    }
+
+    #[test]
+    fn pen_report_flags() {
+        let flags = |in_range, tip, barrel, eraser| {
+            pen_report(&PenSample {
+                in_range,
+                tip,
+                barrel,
+                eraser,
+                ..Default::default()
+            })[0]
+        };
+        assert_eq!(flags(false, false, false, false), 0);
+        assert_eq!(flags(true, false, false, false), 0b10000);
+        assert_eq!(flags(true, true, false, false), 0b10001);
+        assert_eq!(flags(true, false, true, false), 0b10010);
+        assert_eq!(flags(true, false, false, true), 0b11000);
+        assert_eq!(flags(true, true, false, true), 0b11100);
+        // A pressed tip is always in range
+        assert_eq!(flags(false, true, false, false), 0b10001);
+    }
+
+    #[test]
+    fn pen_report_clamps_values() {
+        let report = pen_report(&PenSample {
+            x: u16::MAX,
+            y: PenSample::MAX_POSITION + 1,
+            pressure: u16::MAX,
+            tilt_x: i8::MIN,
+            tilt_y: i8::MAX,
+            ..Default::default()
+        });
+        assert_eq!(report[1..=2], PenSample::MAX_POSITION.to_le_bytes());
+        assert_eq!(report[3..=4], PenSample::MAX_POSITION.to_le_bytes());
+        assert_eq!(report[5..=6], PenSample::MAX_PRESSURE.to_le_bytes());
+        assert_eq!(report[7] as i8, -90);
+        assert_eq!(report[8] as i8, 90);
+    }
+
+    #[test]
+    fn partial_detents_add_up() {
+        let mut remainder = 0;
+        assert_eq!(take_wheel_delta(&mut remainder, 50), 0);
+        assert_eq!(take_wheel_delta(&mut remainder, 50), 0);
+        assert_eq!(take_wheel_delta(&mut remainder, 50), 1);
+        assert_eq!(remainder, 30);
+        assert_eq!(take_wheel_delta(&mut remainder, -60), 0);
+        assert_eq!(take_wheel_delta(&mut remainder, -100), -1);
+        assert_eq!(remainder, -10);
+        assert_eq!(take_wheel_delta(&mut remainder, 3 * SCROLL_RESOLUTION + 10), 3);
+        assert_eq!(remainder, 0);
+        assert_eq!(take_wheel_delta(&mut remainder, i32::MAX), i16::MAX);
+    }
+
+    #[tokio::test]
+    async fn consumer_report_holds_four_keys() {
+        let mut consumer = ConsumerDevice::with_device(Vec::new());
+        let keys = [
+            ConsumerDeviceCode::VolumeUp,
+            ConsumerDeviceCode::Mute,
+            ConsumerDeviceCode::PlayPause,
+            ConsumerDeviceCode::NextTrack,
+            ConsumerDeviceCode::ZoomIn
+        ];
+        for key in keys {
+            consumer.press_key(key).await.unwrap();
+        }
+        // Pressing a held key again changes nothing
+        consumer.press_key(ConsumerDeviceCode::Mute).await.unwrap();
+        consumer.release_key(ConsumerDeviceCode::Mute).await.unwrap();
+        consumer.release_key(ConsumerDeviceCode::VolumeUp).await.unwrap();
+        consumer.reset().await.unwrap();
+
+        let report = |usages: &[u16]| {
+            let mut report = [0u8; 8];
+            for (i, usage) in usages.iter().enumerate() {
+                report[2 * i..2 * i + 2].copy_from_slice(&usage.to_le_bytes());
+            }
+            report
+        };
+        let expected = [
+            report(&[0x00E9]),
+            report(&[0x00E9, 0x00E2]),
+            report(&[0x00E9, 0x00E2, 0x00CD]),
+            report(&[0x00E9, 0x00E2, 0x00CD, 0x00B5]),
+            // The fifth key waits for a free slot
+            report(&[0x00E9, 0x00E2, 0x00CD, 0x00B5]),
+            // The remaining keys keep their order
+            report(&[0x00E9, 0x00CD, 0x00B5, 0x022D]),
+            report(&[0x00CD, 0x00B5, 0x022D]),
+            report(&[])
+        ];
+        assert_eq!(consumer.device, expected.concat());
+    }
+
+    #[tokio::test]
+    async fn gamepad_writes_to_stand_in() {
+        let dir = std::env::temp_dir().join(format!("inputshare-stand-in-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        use_stand_in(dir.clone());
+        enable_function(HidFunction::Gamepad);
+
+        let mut gamepad = Gamepad::new().await.unwrap();
+        gamepad.press_button(GamepadButton::East).await.unwrap();
+        gamepad.set_hat(GamepadHat::Left).await.unwrap();
+        gamepad.set_axis(GamepadAxis::LeftTrigger, 0xFF).await.unwrap();
+        gamepad.reset().await.unwrap();
+        gamepad.device.flush().await.unwrap();
+
+        let index = gadget_functions()
+            .iter()
+            .position(|function| *function == HidFunction::Gamepad)
+            .unwrap();
+        let reports = std::fs::read(dir.join(format!("hidg{}", index))).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let neutral = [0x80, 0x80, 0x80, 0x80, 0x00, 0x00];
+        let expected = [
+            [[0b10, 0x00, 8].as_slice(), &neutral].concat(),
+            [[0b10, 0x00, 6].as_slice(), &neutral].concat(),
+            [[0b10, 0x00, 6].as_slice(), &[0x80, 0x80, 0x80, 0x80, 0xFF, 0x00]].concat(),
+            [[0x00, 0x00, 8].as_slice(), &neutral].concat()
+        ];
+        assert_eq!(reports, expected.concat());
+    }
+}
@@ -0,0 +1,98 @@
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{anyhow, bail, Result};
+use inputshare_common::{ControlMessage, DeviceStatus, PROTOCOL_VERSION};
+use quinn::{RecvStream, SendStream};
+use tokio::io::AsyncReadExt;
+use tokio::select;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::watch;
+use tracing::instrument;
+
+use crate::queue::InputQueue;
+use crate::receiver::InputEvent;
+
+/// Handles the control stream of a single connection until the client finishes it.
+/// Sets `handshake` once the client agreed on the protocol version.
+#[instrument(skip_all)]
+pub async fn handle_control(
+    processor: &InputQueue, mut status: watch::Receiver<Option<DeviceStatus>>, handshake: &AtomicBool, mut send: SendStream,
+    mut recv: RecvStream
+) -> Result<()> {
+    match read_message(&mut recv).await? {
+        Some(ControlMessage::Hello { protocol }) if protocol == PROTOCOL_VERSION => {
+            // Set before answering, as the client opens its other streams right after the answer
+            handshake.store(true, Ordering::Release);
+            send.write_all(&ControlMessage::Hello { protocol: PROTOCOL_VERSION }.to_frame())
+                .await?;
+        }
+        Some(ControlMessage::Hello { protocol }) => {
+            let msg = format!("Unsupported protocol version {} (expected {})", protocol, PROTOCOL_VERSION);
+            send.write_all(&ControlMessage::Error(msg.clone()).to_frame())
+                .await?;
+            send.finish().await?;
+            bail!(msg);
+        }
+        msg => bail!("Expected a handshake but got {:?}", msg)
+    }
+    tracing::debug!("Completed handshake");
+
+    {
+        let (replies, mut pending) = unbounded_channel();
+        let commands = async {
+            while let Some(msg) = read_message(&mut recv).await? {
+                tracing::debug!("Received {:?}", msg);
+                match msg {
+                    ControlMessage::Shutdown => processor.send(InputEvent::Shutdown).await?,
+                    msg => {
+                        tracing::warn!("Unexpected control message: {:?}", msg);
+                        let _ = replies.send(ControlMessage::Error(format!("Unexpected control message: {:?}", msg)));
+                    }
+                }
+            }
+            anyhow::Ok(())
+        };
+        // Reading a frame is not cancel safe, so everything for the client is written here
+        let notifications = async {
+            let mut current = *status.borrow_and_update();
+            loop {
+                if let Some(current) = current.take() {
+                    send.write_all(&ControlMessage::Status(current).to_frame())
+                        .await?;
+                }
+                select! {
+                    Some(msg) = pending.recv() => send.write_all(&msg.to_frame()).await?,
+                    changed = status.changed() => match changed {
+                        Ok(()) => current = *status.borrow_and_update(),
+                        Err(_) => break
+                    }
+                }
+            }
+            anyhow::Ok(())
+        };
+        select! {
+            result = commands => result?,
+            result = notifications => result?
+        }
+    }
+    // The client usually closes the connection right after finishing its side
+    if let Err(err) = send.finish().await {
+        tracing::debug!("Could not finish the control stream: {}", err);
+    }
+    Ok(())
+}
+
+/// Returns `None` when the stream is finished or the connection is gone
+async fn read_message(recv: &mut RecvStream) -> Result<Option<ControlMessage>> {
+    let len = match recv.read_u16_le().await {
+        Ok(len) => len,
+        Err(err) if matches!(err.kind(), ErrorKind::UnexpectedEof | ErrorKind::NotConnected) => return Ok(None),
+        Err(err) => return Err(err.into())
+    };
+    let mut payload = vec![0u8; len as usize];
+    recv.read_exact(&mut payload).await?;
+    ControlMessage::from_payload(&payload)
+        .map(Some)
+        .ok_or_else(|| anyhow!("Invalid control message"))
+}
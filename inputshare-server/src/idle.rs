@@ -0,0 +1,180 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Error, Result};
+use chrono::NaiveTime;
+use clap::ValueEnum;
+use inputshare_common::{HidKeyCode, SCROLL_RESOLUTION};
+use rand::Rng;
+use tokio::io::AsyncWrite;
+
+use crate::configfs::{Keyboard, Mouse};
+
+const JIGGLE_DISTANCE: i32 = 10;
+const IDLE_KEY: HidKeyCode = HidKeyCode::F15;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum IdlePattern {
+    /// Move the mouse back and forth so that the cursor ends up where it started
+    Jiggle,
+    /// Tap F15, which is ignored by most applications
+    Key,
+    /// Scroll one click down and back up again
+    Scroll
+}
+
+impl IdlePattern {
+    pub async fn perform<W: AsyncWrite + Unpin>(self, keyboard: &mut Keyboard<W>, mouse: &mut Mouse<W>) -> Result<()> {
+        tracing::trace!("Performing idle activity: {:?}", self);
+        match self {
+            IdlePattern::Jiggle => {
+                mouse.move_by(JIGGLE_DISTANCE, 0).await?;
+                mouse.move_by(-JIGGLE_DISTANCE, 0).await
+            }
+            IdlePattern::Key => {
+                keyboard.press_key(IDLE_KEY).await?;
+                keyboard.release_key(IDLE_KEY).await
+            }
+            IdlePattern::Scroll => {
+                mouse.scroll_vertical(-SCROLL_RESOLUTION).await?;
+                mouse.scroll_vertical(SCROLL_RESOLUTION).await
+            }
+        }
+    }
+}
+
+/// A daily time window in local time. Windows where the end lies before the start wrap around midnight.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TimeWindow {
+    start: NaiveTime,
+    end: NaiveTime
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        match self.start <= self.end {
+            true => self.start <= time && time < self.end,
+            false => self.start <= time || time < self.end
+        }
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow!("Expected a time window like 09:00-17:00"))?;
+        let parse = |t: &str| NaiveTime::parse_from_str(t.trim(), "%H:%M").with_context(|| format!("Invalid time: {}", t));
+        Ok(Self {
+            start: parse(start)?,
+            end: parse(end)?
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IdleActivity {
+    pub pattern: IdlePattern,
+    pub interval: Duration,
+    pub jitter: Duration,
+    pub window: Option<TimeWindow>,
+    pub pause_while_connected: bool,
+    pub clients: Arc<AtomicUsize>
+}
+
+impl IdleActivity {
+    pub fn next_delay(&self, rng: &mut impl Rng) -> Duration {
+        let jitter = match self.jitter.is_zero() {
+            true => Duration::ZERO,
+            false => rng.gen_range(Duration::ZERO..=self.jitter)
+        };
+        self.interval + jitter
+    }
+
+    pub fn is_active(&self, now: NaiveTime) -> bool {
+        let connected = self.clients.load(Ordering::Relaxed) > 0;
+        if self.pause_while_connected && connected {
+            return false;
+        }
+        match self.window {
+            Some(window) => window.contains(now),
+            None => true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn time(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    fn activity(window: Option<&str>) -> IdleActivity {
+        IdleActivity {
+            pattern: IdlePattern::Jiggle,
+            interval: Duration::from_secs(60),
+            jitter: Duration::from_secs(10),
+            window: window.map(|w| w.parse().unwrap()),
+            pause_while_connected: true,
+            clients: Arc::new(AtomicUsize::new(0))
+        }
+    }
+
+    #[test]
+    fn active_inside_window() {
+        let activity = activity(Some("09:00-17:00"));
+        assert!(!activity.is_active(time(8, 59)));
+        assert!(activity.is_active(time(9, 0)));
+        assert!(activity.is_active(time(16, 59)));
+        assert!(!activity.is_active(time(17, 0)));
+    }
+
+    #[test]
+    fn active_inside_window_across_midnight() {
+        let activity = activity(Some("22:00-06:00"));
+        assert!(activity.is_active(time(22, 0)));
+        assert!(activity.is_active(time(23, 30)));
+        assert!(activity.is_active(time(0, 0)));
+        assert!(activity.is_active(time(5, 59)));
+        assert!(!activity.is_active(time(6, 0)));
+        assert!(!activity.is_active(time(12, 0)));
+    }
+
+    #[test]
+    fn paused_while_connected() {
+        let activity = activity(None);
+        assert!(activity.is_active(time(12, 0)));
+        activity.clients.store(1, Ordering::Relaxed);
+        assert!(!activity.is_active(time(12, 0)));
+    }
+
+    #[test]
+    fn delay_stays_within_jitter() {
+        let activity = activity(None);
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut same = StdRng::seed_from_u64(7);
+        for _ in 0..100 {
+            let delay = activity.next_delay(&mut rng);
+            assert!(delay >= activity.interval && delay <= activity.interval + activity.jitter);
+            assert_eq!(delay, activity.next_delay(&mut same));
+        }
+    }
+
+    #[test]
+    fn delay_without_jitter() {
+        let activity = IdleActivity {
+            jitter: Duration::ZERO,
+            ..activity(None)
+        };
+        assert_eq!(activity.next_delay(&mut StdRng::seed_from_u64(1)), activity.interval);
+    }
+}
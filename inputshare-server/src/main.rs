@@ -1,42 +1,138 @@
+mod bench;
 mod configfs;
+mod control;
+mod idle;
+mod processor;
+mod queue;
 mod receiver;
+mod recording;
+mod serial;
+mod status;
+mod storage;
+mod streams;
+mod typing;
 mod util;
 
-use std::future::pending;
-use std::num::NonZeroU8;
+use std::env;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::{bail, ensure, Context, Result};
+use anyhow::{anyhow, ensure, Result};
 use bytes::Bytes;
-use clap::{arg, command, Parser};
+use clap::{Parser, Subcommand};
 use mdns_sd::Error;
 use quinn::{Connecting, ConnectionError, Endpoint};
-use tokio::process::Command;
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 use tokio::time::sleep;
-use tokio::{select, spawn};
+use tokio::spawn;
 use tracing::{instrument, Instrument, Span};
 use tracing_subscriber::filter::{LevelFilter, Targets};
 use tracing_subscriber::fmt::layer;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
-use crate::receiver::{InputEvent, InputReceiver};
+use crate::configfs::{MassStorage, SerialFunction};
+use crate::idle::{IdleActivity, IdlePattern, TimeWindow};
+use crate::processor::{configfs_input_processor, log_input_processor, DeviceOptions};
+use crate::queue::InputQueue;
+use crate::receiver::InputReceiver;
+use crate::serial::SerialBridge;
+use crate::status::{watch_udc, StatusSender};
+use crate::storage::ImageStore;
+use crate::streams::{accept_streams, StreamServices};
+use crate::typing::Typist;
 use crate::util::{quit, self_signed_config, start_mdns};
 
 /// The server for inputshare
 #[derive(Parser, Debug)]
 #[command(about, version, author)]
 struct Args {
-    /// When set automatically moves the mouse every x seconds without input
+    #[command(subcommand)]
+    mode: Option<Mode>,
+
+    /// When set automatically generates some input every x seconds without input
     #[arg(short, long)]
     auto_movement_timeout: Option<u64>,
 
+    /// The kind of input that is generated when idle
+    #[arg(long, value_enum, default_value_t = IdlePattern::Jiggle)]
+    idle_pattern: IdlePattern,
+
+    /// Randomly extend each idle interval by up to x seconds
+    #[arg(long, default_value_t = 0)]
+    idle_jitter: u64,
+
+    /// Only generate idle input within this daily time window in local time (e.g. 09:00-17:00)
+    #[arg(long)]
+    idle_hours: Option<TimeWindow>,
+
+    /// Don't generate idle input while a client is connected
+    #[arg(long)]
+    idle_pause_connected: bool,
+
     /// Split each mouse movement command in up to x usb packets
-    /// Higher values mean smoother movement but carry a higher risk of saturating the usb connection
+    /// The packets are spread out until the next movement is expected and fewer packets are used when the usb connection can't keep up
     #[arg(short, long, default_value_t = 5)]
     mouse_tesselation_factor: u8,
 
+    /// Add a gamepad to the USB gadget
+    #[arg(long)]
+    gamepad: bool,
+
+    /// Add a pen digitizer to the USB gadget
+    #[arg(long)]
+    pen: bool,
+
+    /// Add a multi-touch digitizer to the USB gadget for clients that send touch frames
+    /// Only hosts with a generic multi-touch driver like Linux accept it, Windows expects a precision touchpad
+    #[arg(long)]
+    multi_touch: bool,
+
+    /// Write the HID reports into hidgN files in this directory instead of configuring the USB gadget
+    /// Useful for testing without a USB device controller
+    #[arg(long)]
+    stand_in: Option<PathBuf>,
+
+    /// Add a mass storage device that is backed by this image file
+    /// Clients can replace the image or push a single file which requires mkfs.vfat and mcopy
+    #[arg(long)]
+    mass_storage: Option<PathBuf>,
+
+    /// The maximum size of an uploaded image or file in MiB
+    #[arg(long, default_value_t = 256)]
+    mass_storage_limit: u64,
+
+    /// Allow this client to change the mass storage device. Can be repeated and is required by `--mass-storage`
+    #[arg(long)]
+    mass_storage_allow: Vec<IpAddr>,
+
+    /// Add a serial port to the USB gadget that clients can connect to
+    #[arg(long)]
+    acm: bool,
+
+    /// The tty that is bridged to the clients
+    /// Can be pointed at one side of a pty pair for testing
+    #[arg(long, default_value = "/dev/ttyGS0")]
+    acm_device: PathBuf,
+
+    /// Allow this client to use the serial port. Can be repeated and is required by `--acm`
+    #[arg(long)]
+    acm_allow: Vec<IpAddr>,
+
+    /// The pause between the key presses and releases when typing text sent by a client in milliseconds
+    /// Increase it if the host drops characters
+    #[arg(long, default_value_t = 10)]
+    typing_interval: u64,
+
+    /// Keep the certificate in this directory instead of generating a new one on every start
+    /// Clients can only pin the fingerprint of a certificate that is kept
+    #[arg(long)]
+    identity: Option<PathBuf>,
+
     /// The interface that should be bound
     #[arg(short, long, default_value = "0.0.0.0:60067")]
     interface: String,
@@ -48,7 +144,69 @@ struct Args {
 
     /// Disabled the mDNS service that is use for service discovery
     #[arg(short, long)]
-    no_mdns: bool
+    no_mdns: bool,
+
+    /// The maximum number of input events that can be waiting for the input processor
+    #[arg(long, default_value_t = 64)]
+    queue_size: usize,
+
+    /// Record all received input events into this file
+    #[arg(long)]
+    record: Option<PathBuf>
+}
+
+#[derive(Subcommand, Debug)]
+enum Mode {
+    /// Feed a recorded session back into the input processor instead of running the server
+    Replay {
+        /// The recording to replay
+        file: PathBuf,
+
+        /// The playback speed relative to the original session (0.01 - 100)
+        #[arg(short, long, default_value_t = 1.0)]
+        speed: f64
+    },
+    /// Print the contents of a recorded session
+    Inspect {
+        /// The recording to inspect
+        file: PathBuf
+    },
+    /// Measure how the mouse tesselation copes with a slow file-backed stand-in for /dev/hidg1
+    BenchMouse {
+        /// The file that receives the mouse reports
+        #[arg(short, long, default_value = "hidg1-bench.bin")]
+        output: PathBuf,
+
+        /// How long the simulated host takes to accept a single report in microseconds
+        #[arg(short, long, default_value_t = 1000)]
+        report_delay: u64,
+
+        /// How many movements per second the simulated client sends
+        #[arg(short, long, default_value_t = 100)]
+        packet_rate: u32,
+
+        /// The duration of the benchmark in seconds
+        #[arg(short, long, default_value_t = 5)]
+        duration: u64
+    },
+    /// Compare the size of the compact packet encoding with the previous fixed size layout on a simulated session
+    BenchPackets {
+        /// How many packets per second the simulated client sends
+        #[arg(short, long, default_value_t = 100)]
+        packet_rate: u32,
+
+        /// The duration of the simulated session in seconds
+        #[arg(short, long, default_value_t = 10)]
+        duration: u64,
+
+        /// The share of lost packets in each direction in percent
+        #[arg(short, long, default_value_t = 0.0)]
+        loss: f64,
+
+        /// The one way latency in milliseconds
+        #[arg(long, default_value_t = 20)]
+        latency: u64
+    }
 }
 
 #[instrument]
@@ -66,9 +224,47 @@ async fn main() -> Result<()> {
         .try_init()?;
 
     let args = Args::parse();
+    match &args.mode {
+        Some(Mode::Inspect { file }) => return recording::inspect(file),
+        Some(Mode::BenchMouse {
+            output,
+            report_delay,
+            packet_rate,
+            duration
+        }) => {
+            let report_delay = Duration::from_micros(*report_delay);
+            let duration = Duration::from_secs(*duration);
+            return bench::bench_mouse(args.mouse_tesselation_factor.try_into()?, output, report_delay, *packet_rate, duration).await;
+        }
+        Some(Mode::BenchPackets {
+            packet_rate,
+            duration,
+            loss,
+            latency
+        }) => {
+            ensure!((0.0..100.0).contains(loss), "The loss has to be between 0 and 100 percent");
+            let latency = Duration::from_millis(*latency);
+            bench::bench_packets(*packet_rate, Duration::from_secs(*duration), loss / 100.0, latency);
+            return Ok(());
+        }
+        Some(Mode::Replay { file, speed }) => {
+            // Enabling the gadget changes the working directory
+            let file = env::current_dir()?.join(file);
+            let (processor, task) = input_processor(&args, None, StatusSender::default()).await?;
+            recording::replay(processor, &file, *speed).await?;
+            return Ok(task.await?);
+        }
+        None => {}
+    }
+    ensure!(!args.acm || !args.acm_allow.is_empty(), "--acm requires at least one client that is allowed with --acm-allow");
+    ensure!(
+        args.mass_storage.is_none() || !args.mass_storage_allow.is_empty(),
+        "--mass-storage requires at least one client that is allowed with --mass-storage-allow"
+    );
+
     let interface = args.interface.parse()?;
     tracing::debug!("Attempting to bind {}", interface);
-    let endpoint = Endpoint::server(self_signed_config(1)?, interface)?;
+    let endpoint = Endpoint::server(self_signed_config(1, args.identity.as_deref())?, interface)?;
 
     let mdns = match args.no_mdns {
         true => None,
@@ -86,20 +282,44 @@ async fn main() -> Result<()> {
         }
     });
 
-    let processor = match args.console {
-        true => log_input_processor().await?,
-        false => configfs_input_processor(args.mouse_tesselation_factor.try_into()?, args.auto_movement_timeout).await?
+    let clients = Arc::new(AtomicUsize::new(0));
+    let idle = args.auto_movement_timeout.map(|timeout| IdleActivity {
+        pattern: args.idle_pattern,
+        interval: Duration::from_secs(timeout),
+        jitter: Duration::from_secs(args.idle_jitter),
+        window: args.idle_hours,
+        pause_while_connected: args.idle_pause_connected,
+        clients: clients.clone()
+    });
+
+    let status = StatusSender::default();
+    // Enabling the gadget changes the working directory
+    let record = match &args.record {
+        Some(path) => Some(env::current_dir()?.join(path)),
+        None => None
     };
+    let (mut processor, _) = input_processor(&args, idle, status.clone()).await?;
+    if let Some(path) = &record {
+        processor = recording::record(processor, path, args.queue_size).await?;
+    }
+    if !args.console {
+        spawn(watch_udc(status.clone()));
+    }
+    let services = Arc::new(stream_services(&args, processor.clone(), status).await?);
 
     while let Some(conn) = endpoint.accept().await {
         let processor = processor.clone();
+        let clients = clients.clone();
+        let services = services.clone();
         spawn(async move {
-            handle_connection(processor, conn)
-                .await
-                .unwrap_or_else(|err| tracing::error!("Connection crashed!\n{:?}", err))
+            clients.fetch_add(1, Ordering::Relaxed);
+            let result = handle_connection(processor, services, conn).await;
+            clients.fetch_sub(1, Ordering::Relaxed);
+            result.unwrap_or_else(|err| tracing::error!("Connection crashed!\n{:?}", err))
         });
     }
     drop(processor);
+    drop(services);
     tracing::debug!("Stopping server");
 
     if let Some(mdns) = mdns {
@@ -112,11 +332,14 @@ async fn main() -> Result<()> {
 }
 
 #[instrument(skip_all, fields(addrs = tracing::field::Empty))]
-async fn handle_connection(processor: UnboundedSender<InputEvent>, connecting: Connecting) -> Result<()> {
+async fn handle_connection(processor: InputQueue, services: Arc<StreamServices>, connecting: Connecting) -> Result<()> {
     let connection = connecting.await?;
     let span = Span::current();
     span.record("addrs", connection.remote_address().to_string());
     tracing::debug!("Established connection");
+    // Datagrams are only accepted once the control stream agreed on the protocol version
+    let handshake = Arc::new(AtomicBool::new(false));
+    spawn(accept_streams(connection.clone(), processor.clone(), services, handshake.clone()).instrument(span));
     let mut receiver = InputReceiver::new();
 
     loop {
@@ -132,104 +355,65 @@ async fn handle_connection(processor: UnboundedSender<InputEvent>, connecting: C
             }
             Err(err) => return Err(err.into())
         };
+        if !handshake.load(Ordering::Acquire) {
+            tracing::trace!("Dropping a datagram that arrived before the handshake");
+            continue;
+        }
         if let Some(packet) = receiver.process_packet(&msg)? {
             ensure!(packet.len() <= connection.max_datagram_size().unwrap());
             connection.send_datagram(Bytes::copy_from_slice(packet))?;
         }
         while let Some(event) = receiver.get_event() {
-            processor
-                .send(event)
-                .context("The input processor seems to be gone")?;
+            processor.send(event).await?;
         }
     }
 }
 
-#[instrument]
-async fn log_input_processor() -> Result<UnboundedSender<InputEvent>> {
-    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
-    tracing::debug!("Starting print processor");
-    spawn(
-        async move {
-            while let Some(event) = receiver.recv().await {
-                tracing::info!("New input event: {:?}", event);
+async fn input_processor(args: &Args, idle: Option<IdleActivity>, status: StatusSender) -> Result<(InputQueue, JoinHandle<()>)> {
+    match args.console {
+        true => log_input_processor(args.queue_size).await,
+        false => {
+            if let Some(dir) = &args.stand_in {
+                configfs::use_stand_in(dir.clone());
             }
-            tracing::debug!("Stopping print processor");
-        }
-        .instrument(Span::current())
-    );
-    Ok(sender)
-}
-
-#[instrument]
-async fn configfs_input_processor(tess_factor: NonZeroU8, timeout: Option<u64>) -> Result<UnboundedSender<InputEvent>> {
-    use configfs::*;
-    let mut keyboard = Keyboard::new().await?;
-    let mut mouse = Mouse::new(tess_factor).await?;
-    let mut consumer_device = ConsumerDevice::new().await?;
-    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
-    tracing::debug!("Starting configfs processor");
-    spawn(
-        async move {
-            let mut idle_move_x = -10;
-            loop {
-                let timeout = async move {
-                    match timeout {
-                        Some(timeout) => sleep(Duration::from_secs(timeout)).await,
-                        None => pending().await
-                    };
-                };
-                select! {
-                    event = receiver.recv() => match event {
-                        Some(event) => {
-                            let result = match event {
-                                InputEvent::MouseMove(x, y) => mouse.move_by(x as i16, y as i16).await,
-                                InputEvent::KeyPress(key) => keyboard.press_key(key).await,
-                                InputEvent::KeyRelease(key) => keyboard.release_key(key).await,
-                                InputEvent::MouseButtonPress(button) => mouse.press_button(button).await,
-                                InputEvent::MouseButtonRelease(button) => mouse.release_button(button).await,
-                                InputEvent::ConsumerDevicePress(button) => consumer_device.press_key(button).await,
-                                InputEvent::ConsumerDeviceRelease(button) => consumer_device.release_key(button).await,
-                                InputEvent::HorizontalScrolling(amount) => mouse.scroll_horizontal(amount).await,
-                                InputEvent::VerticalScrolling(amount) => mouse.scroll_vertical(amount).await,
-                                InputEvent::Reset => {
-                                    async {
-                                        keyboard.reset().await?;
-                                        mouse.reset().await?;
-                                        consumer_device.reset().await?;
-                                        Ok(())
-                                    }
-                                    .await
-                                },
-                                InputEvent::Shutdown => run_command("shutdown", &["-r", "now"]).await,
-                            };
-                            if let Err(err) = result {
-                                tracing::error!("Could not write hid command: {}", err);
-                                break;
-                            }
-                        },
-                        None => break
-                    },
-                    _ = timeout => {
-                        if let Err(err) = mouse.move_by(idle_move_x, 0).await {
-                             tracing::error!("Could not write hid command: {}", err);
-                             break;
-                        }
-                        idle_move_x *= -1;
-                    }
-                };
+            if let Some(image) = &args.mass_storage {
+                configfs::enable_mass_storage(image)?;
+            }
+            if args.acm {
+                configfs::enable_serial();
             }
-            tracing::debug!("Stopping configfs processor");
+            let options = DeviceOptions {
+                tess_factor: args.mouse_tesselation_factor.try_into()?,
+                gamepad: args.gamepad,
+                pen: args.pen,
+                touchpad: args.multi_touch
+            };
+            configfs_input_processor(options, idle, status, args.queue_size).await
         }
-        .instrument(Span::current())
-    );
-    Ok(sender)
+    }
 }
 
-#[instrument]
-async fn run_command(command: &str, args: &[&str]) -> Result<()> {
-    let output = Command::new(command).args(args).output().await?;
-    if output.status.success() && output.stderr.is_empty() {
-        return Ok(());
+async fn stream_services(args: &Args, processor: InputQueue, status: StatusSender) -> Result<StreamServices> {
+    let mut services = StreamServices {
+        status,
+        serial_clients: args.acm_allow.clone(),
+        storage_clients: args.mass_storage_allow.clone(),
+        typist: Some(Mutex::new(Typist::new(processor, Duration::from_millis(args.typing_interval)))),
+        ..Default::default()
+    };
+    if args.console {
+        return Ok(services);
+    }
+    if args.mass_storage.is_some() {
+        let limit = args
+            .mass_storage_limit
+            .checked_mul(1024 * 1024)
+            .ok_or_else(|| anyhow!("--mass-storage-limit is too large"))?;
+        let storage = ImageStore::new(MassStorage::new().await?, limit);
+        services.storage = Some(Mutex::new(storage));
+    }
+    if args.acm {
+        services.serial = Some(SerialBridge::open(SerialFunction::new().await?, &args.acm_device).await?);
     }
-    bail!("{}", String::from_utf8_lossy(&output.stderr));
+    Ok(services)
 }
@@ -0,0 +1,541 @@
+use std::future::{pending, Future};
+use std::num::NonZeroU8;
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::Local;
+use inputshare_common::DeviceStatus;
+use tokio::fs::File;
+use tokio::io::AsyncWrite;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Instant};
+use tokio::{select, spawn};
+use tracing::{instrument, Instrument, Span};
+
+use crate::configfs::{ConsumerDevice, Gamepad, HidFunction, Keyboard, Mouse, Pen, SystemControl, Touchpad};
+use crate::configfs;
+use crate::idle::IdleActivity;
+use crate::queue::{input_queue, InputQueue, InputQueueReceiver};
+use crate::receiver::InputEvent;
+use crate::status::StatusSender;
+use crate::util::run_command;
+
+#[instrument]
+pub async fn log_input_processor(queue_size: usize) -> Result<(InputQueue, JoinHandle<()>)> {
+    let (sender, mut receiver) = input_queue(queue_size);
+    tracing::debug!("Starting print processor");
+    let task = spawn(
+        async move {
+            while let Some(event) = receiver.recv().await {
+                tracing::info!("New input event: {:?}", event);
+            }
+            tracing::debug!("Stopping print processor ({})", receiver.metrics());
+        }
+        .instrument(Span::current())
+    );
+    Ok((sender, task))
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct DeviceOptions {
+    pub tess_factor: NonZeroU8,
+    pub gamepad: bool,
+    pub pen: bool,
+    pub touchpad: bool
+}
+
+#[derive(Debug)]
+struct HidDevices<W = File> {
+    keyboard: Keyboard<W>,
+    mouse: Mouse<W>,
+    consumer_device: ConsumerDevice<W>,
+    system_control: SystemControl<W>,
+    gamepad: Option<Gamepad<W>>,
+    pen: Option<Pen<W>>,
+    touchpad: Option<Touchpad<W>>
+}
+
+impl HidDevices {
+    async fn new(options: DeviceOptions) -> Result<Self> {
+        if options.gamepad {
+            configfs::enable_function(HidFunction::Gamepad);
+        }
+        if options.pen {
+            configfs::enable_function(HidFunction::Pen);
+        }
+        if options.touchpad {
+            configfs::enable_function(HidFunction::Touchpad);
+        }
+        Ok(Self {
+            keyboard: Keyboard::new().await?,
+            mouse: Mouse::new(options.tess_factor).await?,
+            consumer_device: ConsumerDevice::new().await?,
+            system_control: SystemControl::new().await?,
+            gamepad: match options.gamepad {
+                true => Some(Gamepad::new().await?),
+                false => None
+            },
+            pen: match options.pen {
+                true => Some(Pen::new().await?),
+                false => None
+            },
+            touchpad: match options.touchpad {
+                true => Some(Touchpad::new().await?),
+                false => None
+            }
+        })
+    }
+}
+
+impl<W: AsyncWrite + Unpin> HidDevices<W> {
+    fn gamepad(&mut self) -> Option<&mut Gamepad<W>> {
+        if self.gamepad.is_none() {
+            tracing::trace!("Ignoring gamepad input because the gamepad function is disabled");
+        }
+        self.gamepad.as_mut()
+    }
+
+    async fn process(&mut self, event: InputEvent) -> Result<()> {
+        match event {
+            InputEvent::MouseMove(x, y) => self.mouse.move_by(x as i32, y as i32).await,
+            InputEvent::KeyPress(key) => self.keyboard.press_key(key).await,
+            InputEvent::KeyRelease(key) => self.keyboard.release_key(key).await,
+            InputEvent::MouseButtonPress(button) => self.mouse.press_button(button).await,
+            InputEvent::MouseButtonRelease(button) => self.mouse.release_button(button).await,
+            InputEvent::ConsumerDevicePress(button) => self.consumer_device.press_key(button).await,
+            InputEvent::ConsumerDeviceRelease(button) => self.consumer_device.release_key(button).await,
+            InputEvent::SystemControlPress(button) => self.system_control.press_key(button).await,
+            InputEvent::SystemControlRelease(button) => self.system_control.release_key(button).await,
+            InputEvent::GamepadButtonPress(button) => match self.gamepad() {
+                Some(gamepad) => gamepad.press_button(button).await,
+                None => Ok(())
+            },
+            InputEvent::GamepadButtonRelease(button) => match self.gamepad() {
+                Some(gamepad) => gamepad.release_button(button).await,
+                None => Ok(())
+            },
+            InputEvent::GamepadHat(hat) => match self.gamepad() {
+                Some(gamepad) => gamepad.set_hat(hat).await,
+                None => Ok(())
+            },
+            InputEvent::GamepadAxis(axis, value) => match self.gamepad() {
+                Some(gamepad) => gamepad.set_axis(axis, value).await,
+                None => Ok(())
+            },
+            InputEvent::PenSample(sample) => match &mut self.pen {
+                Some(pen) => pen.send_sample(&sample).await,
+                None => {
+                    tracing::trace!("Ignoring pen input because the pen function is disabled");
+                    Ok(())
+                }
+            },
+            InputEvent::TouchFrame(frame) => match &mut self.touchpad {
+                Some(touchpad) => touchpad.send_frame(&frame).await,
+                None => {
+                    tracing::trace!("Ignoring touchpad input because the touchpad function is disabled");
+                    Ok(())
+                }
+            },
+            InputEvent::HorizontalScrolling(amount) => self.mouse.scroll_horizontal(amount).await,
+            InputEvent::VerticalScrolling(amount) => self.mouse.scroll_vertical(amount).await,
+            InputEvent::Reset => {
+                self.keyboard.reset().await?;
+                self.mouse.reset().await?;
+                self.consumer_device.reset().await?;
+                self.system_control.reset().await?;
+                if let Some(gamepad) = &mut self.gamepad {
+                    gamepad.reset().await?;
+                }
+                if let Some(pen) = &mut self.pen {
+                    pen.reset().await?;
+                }
+                match &mut self.touchpad {
+                    Some(touchpad) => touchpad.reset().await,
+                    None => Ok(())
+                }
+            }
+            InputEvent::Shutdown => run_command("shutdown", &["-r", "now"]).await
+        }
+    }
+}
+
+#[instrument(skip(status))]
+pub async fn configfs_input_processor(
+    options: DeviceOptions, idle: Option<IdleActivity>, status: StatusSender, queue_size: usize
+) -> Result<(InputQueue, JoinHandle<()>)> {
+    let devices = HidDevices::new(options).await?;
+    let (sender, receiver) = input_queue(queue_size);
+    tracing::debug!("Starting configfs processor");
+    let open = move |rebind| open_gadget(options, rebind);
+    let task = spawn(run_processor(devices, receiver, idle, status, open).instrument(Span::current()));
+    Ok((sender, task))
+}
+
+async fn open_gadget(options: DeviceOptions, rebind: bool) -> Result<HidDevices> {
+    if rebind {
+        if let Err(err) = configfs::rebind_gadget().await {
+            tracing::warn!("Could not rebind the USB gadget: {}", err);
+        }
+    }
+    HidDevices::new(options).await
+}
+
+/// Forwards the events to the devices and replaces them with the ones from `open` after write errors.
+/// `open` is told when the gadget should also be re-enumerated on the host.
+async fn run_processor<W, O, F>(
+    mut devices: HidDevices<W>, mut receiver: InputQueueReceiver, idle: Option<IdleActivity>, status: StatusSender, mut open: O
+) where
+    W: AsyncWrite + Unpin,
+    O: FnMut(bool) -> F,
+    F: Future<Output = Result<HidDevices<W>>>
+{
+    let mut backoff = Backoff::default();
+    loop {
+        let err = match process_events(&mut devices, &mut receiver, idle.as_ref()).await {
+            Ok(()) => break,
+            Err(err) => err
+        };
+        tracing::error!("Could not write hid command: {}", err);
+        status.send(DeviceStatus::HidWriteFailed);
+        // Dropping the devices first allows the gadget to be recreated when nothing else uses it
+        drop(devices);
+        devices = match reopen_devices(&mut open, &mut receiver, &mut backoff).await {
+            Some(devices) => devices,
+            None => break
+        };
+        status.send(DeviceStatus::ProcessorRestarted);
+    }
+    tracing::debug!("Stopping configfs processor ({})", receiver.metrics());
+}
+
+/// Returns once the queue is closed or a device can't be written to
+async fn process_events<W: AsyncWrite + Unpin>(
+    devices: &mut HidDevices<W>, receiver: &mut InputQueueReceiver, idle: Option<&IdleActivity>
+) -> Result<()> {
+    let next_idle = || idle.map(|idle| Instant::now() + idle.next_delay(&mut rand::thread_rng()));
+    let mut idle_deadline = next_idle();
+    loop {
+        let timeout = async move {
+            match idle_deadline {
+                Some(deadline) => sleep_until(deadline).await,
+                None => pending().await
+            };
+        };
+        let next_step = devices.mouse.next_step();
+        let step = async move {
+            match next_step {
+                Some(next_step) => sleep_until(next_step).await,
+                None => pending().await
+            };
+        };
+        let result = select! {
+            event = receiver.recv() => match event {
+                Some(event) => {
+                    idle_deadline = next_idle();
+                    devices.process(event).await
+                },
+                None => return Ok(())
+            },
+            _ = step => devices.mouse.send_next_step().await,
+            _ = timeout => {
+                idle_deadline = next_idle();
+                match idle {
+                    Some(idle) if idle.is_active(Local::now().time()) => idle
+                        .pattern
+                        .perform(&mut devices.keyboard, &mut devices.mouse)
+                        .await,
+                    _ => Ok(())
+                }
+            }
+        };
+        result?;
+    }
+}
+
+/// Input that arrives while the devices are unavailable is discarded because it would be stale once they are back.
+/// Returns `None` when the queue is closed.
+#[instrument(skip_all)]
+// `is_multiple_of` would require a much newer toolchain
+#[allow(clippy::manual_is_multiple_of)]
+async fn reopen_devices<W, O, F>(open: &mut O, receiver: &mut InputQueueReceiver, backoff: &mut Backoff) -> Option<HidDevices<W>>
+where
+    O: FnMut(bool) -> F,
+    F: Future<Output = Result<HidDevices<W>>>
+{
+    loop {
+        let delay = backoff.next_delay();
+        tracing::info!("Reopening the HID devices in {:?}", delay);
+        let deadline = Instant::now() + delay;
+        loop {
+            select! {
+                event = receiver.recv() => match event {
+                    Some(event) => tracing::trace!("Discarding {:?}", event),
+                    None => return None
+                },
+                _ = sleep_until(deadline) => break
+            }
+        }
+        match open(backoff.failures % Backoff::REBIND_AFTER == 0).await {
+            Ok(devices) => return Some(devices),
+            Err(err) => tracing::warn!("Could not reopen the HID devices: {}", err)
+        }
+    }
+}
+
+/// Doubles the delay with every failure. Failures that are far enough apart start over with the shortest delay.
+#[derive(Debug, Default)]
+struct Backoff {
+    failures: u32,
+    last_failure: Option<Instant>
+}
+
+impl Backoff {
+    const MIN_DELAY: Duration = Duration::from_millis(500);
+    const MAX_DELAY: Duration = Duration::from_secs(30);
+    const RESET_AFTER: Duration = Duration::from_secs(60);
+    /// Every n-th consecutive failure also re-enumerates the gadget on the host
+    const REBIND_AFTER: u32 = 3;
+
+    fn next_delay(&mut self) -> Duration {
+        let now = Instant::now();
+        if self
+            .last_failure
+            .is_some_and(|last| now.duration_since(last) > Self::RESET_AFTER + Self::MAX_DELAY)
+        {
+            self.failures = 0;
+        }
+        self.last_failure = Some(now);
+        self.failures += 1;
+        Self::MIN_DELAY
+            .saturating_mul(1 << (self.failures - 1).min(16))
+            .min(Self::MAX_DELAY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::ready;
+    use std::io::{Error, ErrorKind};
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+
+    use anyhow::anyhow;
+    use inputshare_common::HidKeyCode;
+    use tokio::time::sleep;
+
+    use super::*;
+    use crate::idle::IdlePattern;
+
+    /// Records every report and fails all writes while it is broken
+    #[derive(Debug, Clone, Default)]
+    struct TestDevice {
+        reports: Arc<Mutex<Vec<Vec<u8>>>>,
+        broken: Arc<AtomicBool>
+    }
+
+    impl TestDevice {
+        fn broken() -> Self {
+            let device = Self::default();
+            device.broken.store(true, Ordering::Relaxed);
+            device
+        }
+
+        fn reports(&self) -> Vec<Vec<u8>> {
+            self.reports.lock().unwrap().clone()
+        }
+    }
+
+    impl AsyncWrite for TestDevice {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+            match self.broken.load(Ordering::Relaxed) {
+                true => Poll::Ready(Err(Error::from(ErrorKind::BrokenPipe))),
+                false => {
+                    self.reports.lock().unwrap().push(buf.to_vec());
+                    Poll::Ready(Ok(buf.len()))
+                }
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn devices(device: &TestDevice) -> HidDevices<TestDevice> {
+        HidDevices {
+            keyboard: Keyboard::with_device(device.clone()),
+            mouse: Mouse::with_device(device.clone(), NonZeroU8::new(1).unwrap()),
+            consumer_device: ConsumerDevice::with_device(device.clone()),
+            system_control: SystemControl::with_device(device.clone()),
+            gamepad: None,
+            pen: None,
+            touchpad: None
+        }
+    }
+
+    fn key_report(key: HidKeyCode) -> Vec<u8> {
+        vec![0, 0, key.into(), 0, 0, 0, 0, 0]
+    }
+
+    fn idle_activity(clients: &Arc<AtomicUsize>) -> IdleActivity {
+        IdleActivity {
+            pattern: IdlePattern::Key,
+            interval: Duration::from_secs(60),
+            jitter: Duration::ZERO,
+            window: None,
+            pause_while_connected: true,
+            clients: clients.clone()
+        }
+    }
+
+    /// Runs a processor on `device` that never has to reopen it
+    fn idle_processor(device: &TestDevice, idle: IdleActivity) -> (InputQueue, JoinHandle<()>) {
+        let open = |_| ready(Err::<HidDevices<TestDevice>, _>(anyhow!("Should not be reopened")));
+        let (sender, receiver) = input_queue(16);
+        let task = spawn(run_processor(devices(device), receiver, Some(idle), StatusSender::default(), open));
+        (sender, task)
+    }
+
+    fn idle_reports() -> Vec<Vec<u8>> {
+        vec![key_report(HidKeyCode::F15), vec![0; 8]]
+    }
+
+    async fn wait_for_status(status: &StatusSender, expected: DeviceStatus) {
+        let mut receiver = status.subscribe();
+        while *receiver.borrow_and_update() != Some(expected) {
+            receiver.changed().await.unwrap();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn write_error_replaces_devices() {
+        let status = StatusSender::default();
+        let replacement = TestDevice::default();
+        let opened = Arc::new(Mutex::new(Vec::new()));
+        let open = {
+            let (status, replacement, opened) = (status.clone(), replacement.clone(), opened.clone());
+            move |rebind| {
+                opened.lock().unwrap().push((rebind, *status.subscribe().borrow()));
+                ready(Ok(devices(&replacement)))
+            }
+        };
+        let (sender, receiver) = input_queue(16);
+        let task = spawn(run_processor(devices(&TestDevice::broken()), receiver, None, status.clone(), open));
+
+        sender.send(InputEvent::KeyPress(HidKeyCode::KeyA)).await.unwrap();
+        wait_for_status(&status, DeviceStatus::ProcessorRestarted).await;
+        sender.send(InputEvent::KeyPress(HidKeyCode::KeyB)).await.unwrap();
+        drop(sender);
+        task.await.unwrap();
+
+        assert_eq!(*opened.lock().unwrap(), [(false, Some(DeviceStatus::HidWriteFailed))]);
+        assert_eq!(replacement.reports(), [key_report(HidKeyCode::KeyB)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_reopens_back_off() {
+        let status = StatusSender::default();
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let open = {
+            let attempts = attempts.clone();
+            move |rebind| {
+                let mut attempts = attempts.lock().unwrap();
+                attempts.push((Instant::now(), rebind));
+                ready(match attempts.len() {
+                    4 => Ok(devices(&TestDevice::default())),
+                    _ => Err(anyhow!("The gadget is not ready"))
+                })
+            }
+        };
+        let (sender, receiver) = input_queue(16);
+        let task = spawn(run_processor(devices(&TestDevice::broken()), receiver, None, status.clone(), open));
+
+        let start = Instant::now();
+        sender.send(InputEvent::KeyPress(HidKeyCode::KeyA)).await.unwrap();
+        wait_for_status(&status, DeviceStatus::ProcessorRestarted).await;
+        drop(sender);
+        task.await.unwrap();
+
+        let attempts = attempts.lock().unwrap();
+        let delays = attempts
+            .iter()
+            .scan(start, |last, (time, _)| Some(time.duration_since(std::mem::replace(last, *time))))
+            .map(|delay| delay.as_millis())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [500, 1000, 2000, 4000]);
+        let rebinds = attempts.iter().map(|(_, rebind)| *rebind).collect::<Vec<_>>();
+        assert_eq!(rebinds, [false, false, true, false]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closed_queue_stops_reopening() {
+        let status = StatusSender::default();
+        let open = |_| ready(Err::<HidDevices<TestDevice>, _>(anyhow!("Should not be reopened")));
+        let (sender, receiver) = input_queue(16);
+        let task = spawn(run_processor(devices(&TestDevice::broken()), receiver, None, status.clone(), open));
+
+        sender.send(InputEvent::KeyPress(HidKeyCode::KeyA)).await.unwrap();
+        wait_for_status(&status, DeviceStatus::HidWriteFailed).await;
+        drop(sender);
+        task.await.unwrap();
+
+        assert_eq!(*status.subscribe().borrow(), Some(DeviceStatus::HidWriteFailed));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_input_follows_the_interval() {
+        let device = TestDevice::default();
+        let (sender, task) = idle_processor(&device, idle_activity(&Arc::default()));
+
+        sleep(Duration::from_secs(59)).await;
+        assert_eq!(device.reports(), Vec::<Vec<u8>>::new());
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!(device.reports(), idle_reports());
+        sleep(Duration::from_secs(60)).await;
+        assert_eq!(device.reports(), [idle_reports(), idle_reports()].concat());
+
+        drop(sender);
+        task.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn input_restarts_the_idle_interval() {
+        let device = TestDevice::default();
+        let (sender, task) = idle_processor(&device, idle_activity(&Arc::default()));
+
+        sleep(Duration::from_secs(40)).await;
+        sender.send(InputEvent::KeyPress(HidKeyCode::KeyA)).await.unwrap();
+        sender.send(InputEvent::KeyRelease(HidKeyCode::KeyA)).await.unwrap();
+        let typed = vec![key_report(HidKeyCode::KeyA), vec![0; 8]];
+        sleep(Duration::from_secs(59)).await;
+        assert_eq!(device.reports(), typed);
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!(device.reports(), [typed, idle_reports()].concat());
+
+        drop(sender);
+        task.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn no_idle_input_while_connected() {
+        let device = TestDevice::default();
+        let clients = Arc::new(AtomicUsize::new(1));
+        let (sender, task) = idle_processor(&device, idle_activity(&clients));
+
+        sleep(Duration::from_secs(200)).await;
+        assert_eq!(device.reports(), Vec::<Vec<u8>>::new());
+        clients.store(0, Ordering::Relaxed);
+        // The interval that was running during the connection still counts
+        sleep(Duration::from_secs(41)).await;
+        assert_eq!(device.reports(), idle_reports());
+
+        drop(sender);
+        task.await.unwrap();
+    }
+}
@@ -0,0 +1,228 @@
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use tokio::spawn;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::time::interval;
+
+use crate::receiver::InputEvent;
+
+const METRICS_LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Default)]
+pub struct QueueMetrics {
+    received: AtomicU64,
+    merged: AtomicU64,
+    blocked: AtomicU64,
+    max_depth: AtomicUsize
+}
+
+impl QueueMetrics {
+    fn snapshot(&self) -> (u64, u64, u64, usize) {
+        (
+            self.received.load(Ordering::Relaxed),
+            self.merged.load(Ordering::Relaxed),
+            self.blocked.load(Ordering::Relaxed),
+            self.max_depth.load(Ordering::Relaxed)
+        )
+    }
+}
+
+impl Display for QueueMetrics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let (received, merged, blocked, max_depth) = self.snapshot();
+        write!(f, "received: {} merged: {} blocked: {} max depth: {}", received, merged, blocked, max_depth)
+    }
+}
+
+/// Creates a bounded queue between the connections and the input processor.
+///
+/// Senders have to wait once the queue is full, which in turn stops the connections from reading new packets.
+pub fn input_queue(capacity: usize) -> (InputQueue, InputQueueReceiver) {
+    let (sender, receiver) = tokio::sync::mpsc::channel(capacity);
+    let metrics = Arc::new(QueueMetrics::default());
+    spawn(log_metrics(Arc::downgrade(&metrics)));
+    (
+        InputQueue {
+            sender,
+            metrics: metrics.clone()
+        },
+        InputQueueReceiver {
+            receiver,
+            stashed: None,
+            metrics
+        }
+    )
+}
+
+async fn log_metrics(metrics: Weak<QueueMetrics>) {
+    let mut ticker = interval(METRICS_LOG_INTERVAL);
+    let mut last = None;
+    loop {
+        ticker.tick().await;
+        let Some(metrics) = metrics.upgrade() else { break };
+        let current = Some(metrics.snapshot());
+        if current != last {
+            tracing::debug!("Input queue {}", metrics);
+            last = current;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InputQueue {
+    sender: Sender<InputEvent>,
+    metrics: Arc<QueueMetrics>
+}
+
+impl InputQueue {
+    pub async fn send(&self, event: InputEvent) -> Result<()> {
+        let closed = || anyhow!("The input processor seems to be gone");
+        match self.sender.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(event)) => {
+                self.metrics.blocked.fetch_add(1, Ordering::Relaxed);
+                self.sender.send(event).await.map_err(|_| closed())?;
+            }
+            Err(TrySendError::Closed(_)) => return Err(closed())
+        }
+        let depth = self.sender.max_capacity() - self.sender.capacity();
+        self.metrics.received.fetch_add(1, Ordering::Relaxed);
+        self.metrics.max_depth.fetch_max(depth, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct InputQueueReceiver {
+    receiver: Receiver<InputEvent>,
+    stashed: Option<InputEvent>,
+    metrics: Arc<QueueMetrics>
+}
+
+impl InputQueueReceiver {
+    /// Receives the next event and merges all directly following events of the same kind into it.
+    ///
+    /// This function is cancellation safe.
+    pub async fn recv(&mut self) -> Option<InputEvent> {
+        let mut event = match self.stashed.take() {
+            Some(event) => event,
+            None => self.receiver.recv().await?
+        };
+        while let Ok(next) = self.receiver.try_recv() {
+            match merge(event, next) {
+                Ok(merged) => {
+                    self.metrics.merged.fetch_add(1, Ordering::Relaxed);
+                    event = merged;
+                }
+                Err(next) => {
+                    self.stashed = Some(next);
+                    break;
+                }
+            }
+        }
+        Some(event)
+    }
+
+    /// Receives the next event exactly as it was sent.
+    ///
+    /// This function is cancellation safe.
+    pub async fn recv_unmerged(&mut self) -> Option<InputEvent> {
+        match self.stashed.take() {
+            Some(event) => Some(event),
+            None => self.receiver.recv().await
+        }
+    }
+
+    pub fn metrics(&self) -> &QueueMetrics {
+        &self.metrics
+    }
+}
+
+fn merge(current: InputEvent, next: InputEvent) -> std::result::Result<InputEvent, InputEvent> {
+    match (current, next) {
+        (InputEvent::MouseMove(x1, y1), InputEvent::MouseMove(x2, y2)) => Ok(InputEvent::MouseMove(x1.saturating_add(x2), y1.saturating_add(y2))),
+        (InputEvent::VerticalScrolling(a), InputEvent::VerticalScrolling(b)) => a
+            .checked_add(b)
+            .map(InputEvent::VerticalScrolling)
+            .ok_or(next),
+        (InputEvent::HorizontalScrolling(a), InputEvent::HorizontalScrolling(b)) => a
+            .checked_add(b)
+            .map(InputEvent::HorizontalScrolling)
+            .ok_or(next),
+        (InputEvent::GamepadAxis(a, _), InputEvent::GamepadAxis(b, value)) if a == b => Ok(InputEvent::GamepadAxis(b, value)),
+        (InputEvent::PenSample(a), InputEvent::PenSample(b)) if a.same_buttons(&b) => Ok(next),
+        _ => Err(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use inputshare_common::{HidButtonCode, HidKeyCode};
+
+    use super::*;
+
+    async fn queue_of(events: &[InputEvent]) -> InputQueueReceiver {
+        let (queue, receiver) = input_queue(events.len());
+        for event in events {
+            queue.send(*event).await.unwrap();
+        }
+        receiver
+    }
+
+    #[tokio::test]
+    async fn movement_is_not_merged_across_keys_and_buttons() {
+        let mut receiver = queue_of(&[
+            InputEvent::MouseMove(1, 1),
+            InputEvent::MouseMove(2, 2),
+            InputEvent::KeyPress(HidKeyCode::KeyA),
+            InputEvent::MouseMove(3, 3),
+            InputEvent::MouseButtonPress(HidButtonCode::LButton),
+            InputEvent::MouseMove(4, 4),
+            InputEvent::MouseMove(5, 5)
+        ])
+        .await;
+        assert_eq!(receiver.recv().await, Some(InputEvent::MouseMove(3, 3)));
+        assert_eq!(receiver.recv().await, Some(InputEvent::KeyPress(HidKeyCode::KeyA)));
+        assert_eq!(receiver.recv().await, Some(InputEvent::MouseMove(3, 3)));
+        assert_eq!(receiver.recv().await, Some(InputEvent::MouseButtonPress(HidButtonCode::LButton)));
+        assert_eq!(receiver.recv().await, Some(InputEvent::MouseMove(9, 9)));
+        assert_eq!(receiver.metrics().snapshot().1, 2);
+    }
+
+    #[tokio::test]
+    async fn overflowing_scrolls_are_stashed() {
+        let mut receiver = queue_of(&[
+            InputEvent::VerticalScrolling(i32::MAX - 1),
+            InputEvent::VerticalScrolling(1),
+            InputEvent::VerticalScrolling(1),
+            InputEvent::VerticalScrolling(2),
+            InputEvent::HorizontalScrolling(i32::MIN),
+            InputEvent::HorizontalScrolling(-1)
+        ])
+        .await;
+        assert_eq!(receiver.recv().await, Some(InputEvent::VerticalScrolling(i32::MAX)));
+        assert_eq!(receiver.recv().await, Some(InputEvent::VerticalScrolling(3)));
+        assert_eq!(receiver.recv().await, Some(InputEvent::HorizontalScrolling(i32::MIN)));
+        assert_eq!(receiver.recv().await, Some(InputEvent::HorizontalScrolling(-1)));
+    }
+
+    #[tokio::test]
+    async fn unmerged_events_start_with_the_stash() {
+        let mut receiver = queue_of(&[
+            InputEvent::MouseMove(1, 1),
+            InputEvent::KeyPress(HidKeyCode::KeyA),
+            InputEvent::MouseMove(2, 2),
+            InputEvent::MouseMove(3, 3)
+        ])
+        .await;
+        assert_eq!(receiver.recv().await, Some(InputEvent::MouseMove(1, 1)));
+        assert_eq!(receiver.recv_unmerged().await, Some(InputEvent::KeyPress(HidKeyCode::KeyA)));
+        assert_eq!(receiver.recv_unmerged().await, Some(InputEvent::MouseMove(2, 2)));
+        assert_eq!(receiver.recv_unmerged().await, Some(InputEvent::MouseMove(3, 3)));
+    }
+}
@@ -1,11 +1,14 @@
 use std::collections::VecDeque;
 use std::convert::TryFrom;
-use std::io::Result;
+use std::io::{ErrorKind, Result};
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use inputshare_common::{ConsumerDeviceCode, HidButtonCode, HidKeyCode, MessageType, MouseType, Vec2};
+use byteorder::ReadBytesExt;
+use inputshare_common::{
+    is_newer_sequence, AckPacket, ConsumerDeviceCode, GamepadAxis, GamepadButton, GamepadHat, HidButtonCode, HidKeyCode, InputHeader, MessageType,
+    MouseType, PenSample, SystemControlCode, TouchFrame, Vec2, SCROLL_RESOLUTION
+};
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum InputEvent {
     MouseMove(MouseType, MouseType),
     KeyPress(HidKeyCode),
@@ -14,18 +17,37 @@ pub enum InputEvent {
     MouseButtonRelease(HidButtonCode),
     ConsumerDevicePress(ConsumerDeviceCode),
     ConsumerDeviceRelease(ConsumerDeviceCode),
-    HorizontalScrolling(i8),
-    VerticalScrolling(i8),
+    SystemControlPress(SystemControlCode),
+    SystemControlRelease(SystemControlCode),
+    GamepadButtonPress(GamepadButton),
+    GamepadButtonRelease(GamepadButton),
+    GamepadHat(GamepadHat),
+    GamepadAxis(GamepadAxis, u8),
+    PenSample(PenSample),
+    TouchFrame(TouchFrame),
+    /// Scroll amounts in high resolution units (see `SCROLL_RESOLUTION`)
+    HorizontalScrolling(i32),
+    VerticalScrolling(i32),
     Reset,
     Shutdown
 }
 
+/// How many of the recently applied mouse positions the client can refer to
+const POSITION_HISTORY: usize = 64;
+/// How many of the replaced sessions are remembered to ignore their late packets
+const SESSION_HISTORY: usize = 16;
+
 #[derive(Debug)]
 pub struct InputReceiver {
+    /// Chosen by the client. A new one means that the client restarted, so all indices start over.
+    session: u32,
+    replaced_sessions: VecDeque<u32>,
     local_sequence: u64,
     remote_sequence: u64,
     packet_buffer: Vec<u8>,
     local_mouse_pos: Vec2<MouseType>,
+    /// The sequence and the resulting position of the recently applied packets
+    positions: VecDeque<(u64, Vec2<MouseType>)>,
     last_message: u64,
     events: VecDeque<InputEvent>
 }
@@ -33,8 +55,11 @@ pub struct InputReceiver {
 impl InputReceiver {
     pub fn new() -> Self {
         Self {
+            session: 0,
+            replaced_sessions: VecDeque::new(),
             local_sequence: 1,
             local_mouse_pos: Vec2::new(0, 0),
+            positions: VecDeque::from([(0, Vec2::new(0, 0))]),
             packet_buffer: Vec::new(),
             events: VecDeque::new(),
             last_message: 0,
@@ -47,13 +72,55 @@ impl InputReceiver {
     }
 
     pub fn process_packet(&mut self, mut packet: &[u8]) -> Result<Option<&[u8]>> {
-        let sequence = packet.read_u64::<LittleEndian>()?;
-        if sequence <= self.remote_sequence {
+        let header = InputHeader::read(&mut packet).ok_or(ErrorKind::UnexpectedEof)?;
+        if header.start_message.checked_add(header.message_count.into()).is_none() {
+            return Err(ErrorKind::InvalidData.into());
+        }
+        if self.replaced_sessions.contains(&header.session) {
+            tracing::debug!("Ignoring a late packet of a replaced session");
+            return Ok(None);
+        }
+        if header.session != self.session {
+            self.start_session(header.session);
+        }
+        if !is_newer_sequence(header.sequence, self.remote_sequence) {
             return Ok(None);
         }
-        self.remote_sequence = sequence;
-        let remote_mouse_pos = Vec2::new(packet.read_i64::<LittleEndian>()?, packet.read_i64::<LittleEndian>()?);
+        let base_sequence = header.sequence.wrapping_sub(header.base_distance);
+        match self.positions.iter().find(|(sequence, _)| *sequence == base_sequence) {
+            // Only the acknowledgement tells the client to use a newer base
+            None => tracing::debug!("Ignoring packet {} because its base {} is unknown", header.sequence, base_sequence),
+            Some((_, base)) => {
+                let remote_mouse_pos = match (base.x.checked_add(header.mouse_x), base.y.checked_add(header.mouse_y)) {
+                    (Some(x), Some(y)) => Vec2::new(x, y),
+                    _ => return Err(ErrorKind::InvalidData.into())
+                };
+                if remote_mouse_pos.x.checked_sub(self.local_mouse_pos.x).is_none()
+                    || remote_mouse_pos.y.checked_sub(self.local_mouse_pos.y).is_none()
+                {
+                    return Err(ErrorKind::InvalidData.into());
+                }
+                self.remote_sequence = header.sequence;
+                self.apply_packet(&header, remote_mouse_pos, packet)?;
+            }
+        }
 
+        self.packet_buffer.clear();
+        AckPacket {
+            session: self.session,
+            sequence: self.local_sequence,
+            applied_sequence: self.remote_sequence,
+            mouse_x: self.local_mouse_pos.x,
+            mouse_y: self.local_mouse_pos.y,
+            last_message: self.last_message
+        }
+        .write(&mut self.packet_buffer);
+        self.local_sequence = self.local_sequence.wrapping_add(1);
+        Ok(Some(self.packet_buffer.as_slice()))
+    }
+
+    /// The caller has to check that neither the mouse movement nor the message indices overflow
+    fn apply_packet(&mut self, header: &InputHeader, remote_mouse_pos: Vec2<MouseType>, mut packet: &[u8]) -> Result<()> {
         if remote_mouse_pos != self.local_mouse_pos {
             self.events.push_back(InputEvent::MouseMove(
                 remote_mouse_pos.x - self.local_mouse_pos.x,
@@ -61,56 +128,176 @@ impl InputReceiver {
             ));
             self.local_mouse_pos = remote_mouse_pos;
         }
+        if self.positions.len() == POSITION_HISTORY {
+            self.positions.pop_front();
+        }
+        self.positions
+            .push_back((header.sequence, remote_mouse_pos));
 
-        let start_message = packet.read_u64::<LittleEndian>()?;
+        let start_message = header.start_message;
         let diff = self.last_message.saturating_sub(start_message);
-        let len = packet.read_u8()? as u64;
-        packet = &packet[(2 * diff as usize)..];
-        for i in diff..len {
-            let msg_id = packet.read_u8()?;
-            let msg_arg = packet.read_u8()?;
-            match MessageType::try_from(msg_id) {
-                Ok(MessageType::KeyPress) => self
-                    .events
-                    .push_back(InputEvent::KeyPress(HidKeyCode::from(msg_arg))),
-                Ok(MessageType::KeyRelease) => self
-                    .events
-                    .push_back(InputEvent::KeyRelease(HidKeyCode::from(msg_arg))),
-                Ok(MessageType::MouseButtonPress) => self
-                    .events
-                    .push_back(InputEvent::MouseButtonPress(HidButtonCode::from(msg_arg))),
-                Ok(MessageType::MouseButtonRelease) => self
-                    .events
-                    .push_back(InputEvent::MouseButtonRelease(HidButtonCode::from(msg_arg))),
-                Ok(MessageType::ConsumerDevicePress) => self
-                    .events
-                    .push_back(InputEvent::ConsumerDevicePress(ConsumerDeviceCode::from(msg_arg))),
-                Ok(MessageType::ConsumerDeviceRelease) => self
-                    .events
-                    .push_back(InputEvent::ConsumerDeviceRelease(ConsumerDeviceCode::from(msg_arg))),
-                Ok(MessageType::HorizontalScrolling) => self
-                    .events
-                    .push_back(InputEvent::HorizontalScrolling(msg_arg as i8)),
-                Ok(MessageType::VerticalScrolling) => self
-                    .events
-                    .push_back(InputEvent::VerticalScrolling(msg_arg as i8)),
-                Ok(MessageType::Reset) => self.events.push_back(InputEvent::Reset),
-                Ok(MessageType::Shutdown) => self.events.push_back(InputEvent::Shutdown),
-                Err(e) => tracing::warn!("Invalid message: {}", e)
+        let len = u64::from(header.message_count);
+        for i in 0..len {
+            let msg_type = match MessageType::try_from(packet.read_u8()?) {
+                Ok(msg_type) => msg_type,
+                Err(e) => {
+                    // Without knowing the size of the message the rest of the packet can't be parsed
+                    tracing::warn!("Invalid message: {}", e);
+                    self.last_message = self.last_message.max(start_message + len);
+                    break;
+                }
+            };
+            let size = msg_type.payload_size();
+            if packet.len() < size {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            let (payload, rest) = packet.split_at(size);
+            packet = rest;
+            if i < diff {
+                continue;
+            }
+            match parse_message(msg_type, payload) {
+                // The client replaces messages that it gave up on with resets, so they tend to come in long runs
+                InputEvent::Reset if matches!(self.events.back(), Some(InputEvent::Reset)) => {}
+                event => self.events.push_back(event)
             }
             self.last_message = start_message + i + 1;
         }
+        Ok(())
+    }
 
-        self.packet_buffer.clear();
-        self.packet_buffer
-            .write_u64::<LittleEndian>(self.local_sequence)?;
-        self.packet_buffer
-            .write_i64::<LittleEndian>(self.local_mouse_pos.x)?;
-        self.packet_buffer
-            .write_i64::<LittleEndian>(self.local_mouse_pos.y)?;
-        self.packet_buffer
-            .write_u64::<LittleEndian>(self.last_message)?;
-        self.local_sequence += 1;
-        Ok(Some(self.packet_buffer.as_slice()))
+    /// Releases everything the previous session left pressed and takes over the origin of the new mouse position
+    fn start_session(&mut self, session: u32) {
+        if self.session != 0 {
+            tracing::info!("The client started a new session");
+            self.events.push_back(InputEvent::Reset);
+            if self.replaced_sessions.len() == SESSION_HISTORY {
+                self.replaced_sessions.pop_front();
+            }
+            self.replaced_sessions.push_back(self.session);
+        }
+        self.session = session;
+        self.remote_sequence = 0;
+        self.last_message = 0;
+        self.local_mouse_pos = Vec2::new(0, 0);
+        self.positions.clear();
+        self.positions.push_back((0, self.local_mouse_pos));
+    }
+}
+
+fn parse_message(msg_type: MessageType, payload: &[u8]) -> InputEvent {
+    let msg_arg = payload[0];
+    match msg_type {
+        MessageType::KeyPress => InputEvent::KeyPress(HidKeyCode::from(msg_arg)),
+        MessageType::KeyRelease => InputEvent::KeyRelease(HidKeyCode::from(msg_arg)),
+        MessageType::MouseButtonPress => InputEvent::MouseButtonPress(HidButtonCode::from(msg_arg)),
+        MessageType::MouseButtonRelease => InputEvent::MouseButtonRelease(HidButtonCode::from(msg_arg)),
+        MessageType::ConsumerDevicePress => InputEvent::ConsumerDevicePress(ConsumerDeviceCode::from(msg_arg)),
+        MessageType::ConsumerDeviceRelease => InputEvent::ConsumerDeviceRelease(ConsumerDeviceCode::from(msg_arg)),
+        MessageType::HorizontalScrolling => InputEvent::HorizontalScrolling(i32::from(msg_arg as i8) * SCROLL_RESOLUTION),
+        MessageType::VerticalScrolling => InputEvent::VerticalScrolling(i32::from(msg_arg as i8) * SCROLL_RESOLUTION),
+        MessageType::HighResHorizontalScrolling => InputEvent::HorizontalScrolling(i32::from(msg_arg as i8)),
+        MessageType::HighResVerticalScrolling => InputEvent::VerticalScrolling(i32::from(msg_arg as i8)),
+        MessageType::SystemControlPress => InputEvent::SystemControlPress(SystemControlCode::from(msg_arg)),
+        MessageType::SystemControlRelease => InputEvent::SystemControlRelease(SystemControlCode::from(msg_arg)),
+        MessageType::GamepadButtonPress => InputEvent::GamepadButtonPress(GamepadButton::from(msg_arg)),
+        MessageType::GamepadButtonRelease => InputEvent::GamepadButtonRelease(GamepadButton::from(msg_arg)),
+        MessageType::GamepadHat => InputEvent::GamepadHat(GamepadHat::from(msg_arg)),
+        MessageType::GamepadLeftStickX => InputEvent::GamepadAxis(GamepadAxis::LeftStickX, msg_arg),
+        MessageType::GamepadLeftStickY => InputEvent::GamepadAxis(GamepadAxis::LeftStickY, msg_arg),
+        MessageType::GamepadRightStickX => InputEvent::GamepadAxis(GamepadAxis::RightStickX, msg_arg),
+        MessageType::GamepadRightStickY => InputEvent::GamepadAxis(GamepadAxis::RightStickY, msg_arg),
+        MessageType::GamepadLeftTrigger => InputEvent::GamepadAxis(GamepadAxis::LeftTrigger, msg_arg),
+        MessageType::GamepadRightTrigger => InputEvent::GamepadAxis(GamepadAxis::RightTrigger, msg_arg),
+        MessageType::Reset => InputEvent::Reset,
+        MessageType::Shutdown => InputEvent::Shutdown,
+        MessageType::PenSample => InputEvent::PenSample(PenSample::from_bytes(payload.try_into().expect("Invalid payload size"))),
+        MessageType::TouchFrame => InputEvent::TouchFrame(TouchFrame::from_bytes(payload.try_into().expect("Invalid payload size")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(header: InputHeader, messages: &[[u8; 2]]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        InputHeader {
+            message_count: messages.len() as u8,
+            ..header
+        }
+        .write(&mut packet);
+        messages
+            .iter()
+            .for_each(|message| packet.extend_from_slice(message));
+        packet
+    }
+
+    fn events(receiver: &mut InputReceiver) -> Vec<InputEvent> {
+        std::iter::from_fn(|| receiver.get_event()).collect()
+    }
+
+    fn key_press(key: HidKeyCode) -> [u8; 2] {
+        [MessageType::KeyPress.into(), key.into()]
+    }
+
+    #[test]
+    fn overflowing_packets_are_rejected() {
+        let mut receiver = InputReceiver::new();
+        let header = InputHeader {
+            session: 1,
+            sequence: 1,
+            base_distance: 1,
+            mouse_x: 1,
+            ..Default::default()
+        };
+        assert!(receiver.process_packet(&packet(header, &[])).unwrap().is_some());
+
+        let far = InputHeader {
+            sequence: 2,
+            mouse_x: MouseType::MAX,
+            ..header
+        };
+        assert_eq!(receiver.process_packet(&packet(far, &[])).unwrap_err().kind(), ErrorKind::InvalidData);
+        let last = InputHeader {
+            sequence: 2,
+            start_message: u64::MAX,
+            ..header
+        };
+        let result = receiver.process_packet(&packet(last, &[key_press(HidKeyCode::KeyA)]));
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(matches!(events(&mut receiver)[..], [InputEvent::MouseMove(1, 0)]));
+    }
+
+    #[test]
+    fn late_packets_of_replaced_sessions_are_ignored() {
+        let mut receiver = InputReceiver::new();
+        let old = InputHeader {
+            session: 1,
+            sequence: 1,
+            base_distance: 1,
+            ..Default::default()
+        };
+        receiver.process_packet(&packet(old, &[key_press(HidKeyCode::KeyA)])).unwrap();
+        let new = InputHeader { session: 2, ..old };
+        receiver.process_packet(&packet(new, &[key_press(HidKeyCode::KeyB)])).unwrap();
+        let late = InputHeader { sequence: 2, ..old };
+        assert!(receiver.process_packet(&packet(late, &[])).unwrap().is_none());
+        let next = InputHeader {
+            sequence: 2,
+            start_message: 1,
+            ..new
+        };
+        receiver.process_packet(&packet(next, &[key_press(HidKeyCode::KeyC)])).unwrap();
+
+        assert!(matches!(
+            events(&mut receiver)[..],
+            [
+                InputEvent::KeyPress(HidKeyCode::KeyA),
+                InputEvent::Reset,
+                InputEvent::KeyPress(HidKeyCode::KeyB),
+                InputEvent::KeyPress(HidKeyCode::KeyC)
+            ]
+        ));
     }
 }
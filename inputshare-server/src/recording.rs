@@ -0,0 +1,329 @@
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{ensure, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use inputshare_common::{
+    ConsumerDeviceCode, GamepadAxis, GamepadButton, GamepadHat, HidButtonCode, HidKeyCode, PenSample, SystemControlCode,
+    TouchFrame, SCROLL_RESOLUTION
+};
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::time::{interval, sleep_until, Instant, MissedTickBehavior};
+use tokio::{select, spawn};
+use tracing::{instrument, Instrument, Span};
+
+use crate::queue::{input_queue, InputQueue};
+use crate::receiver::InputEvent;
+
+const MAGIC: &[u8; 8] = b"ISUBREC\0";
+const VERSION: u16 = 2;
+/// Bounds how much of the recording is lost when the server is killed
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// Keeps the scaled timestamps of a replay within the range of a `Duration`
+const MIN_SPEED: f64 = 0.01;
+const MAX_SPEED: f64 = 100.0;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+enum EventTag {
+    MouseMove,
+    KeyPress,
+    KeyRelease,
+    MouseButtonPress,
+    MouseButtonRelease,
+    ConsumerDevicePress,
+    ConsumerDeviceRelease,
+    HorizontalScrolling,
+    VerticalScrolling,
+    Reset,
+    Shutdown,
+    SystemControlPress,
+    SystemControlRelease,
+    GamepadButtonPress,
+    GamepadButtonRelease,
+    GamepadHat,
+    GamepadAxis,
+    PenSample,
+    TouchFrame
+}
+
+fn write_entry<W: Write>(mut w: W, time: Duration, event: &InputEvent) -> std::io::Result<()> {
+    w.write_u64::<LittleEndian>(time.as_micros() as u64)?;
+    match *event {
+        InputEvent::MouseMove(x, y) => {
+            w.write_u8(EventTag::MouseMove.into())?;
+            w.write_i64::<LittleEndian>(x)?;
+            w.write_i64::<LittleEndian>(y)
+        }
+        InputEvent::KeyPress(key) => w.write_all(&[EventTag::KeyPress.into(), key.into()]),
+        InputEvent::KeyRelease(key) => w.write_all(&[EventTag::KeyRelease.into(), key.into()]),
+        InputEvent::MouseButtonPress(button) => w.write_all(&[EventTag::MouseButtonPress.into(), button.into()]),
+        InputEvent::MouseButtonRelease(button) => w.write_all(&[EventTag::MouseButtonRelease.into(), button.into()]),
+        InputEvent::ConsumerDevicePress(button) => w.write_all(&[EventTag::ConsumerDevicePress.into(), button.into()]),
+        InputEvent::ConsumerDeviceRelease(button) => w.write_all(&[EventTag::ConsumerDeviceRelease.into(), button.into()]),
+        InputEvent::SystemControlPress(button) => w.write_all(&[EventTag::SystemControlPress.into(), button.into()]),
+        InputEvent::SystemControlRelease(button) => w.write_all(&[EventTag::SystemControlRelease.into(), button.into()]),
+        InputEvent::GamepadButtonPress(button) => w.write_all(&[EventTag::GamepadButtonPress.into(), button.into()]),
+        InputEvent::GamepadButtonRelease(button) => w.write_all(&[EventTag::GamepadButtonRelease.into(), button.into()]),
+        InputEvent::GamepadHat(hat) => w.write_all(&[EventTag::GamepadHat.into(), hat.into()]),
+        InputEvent::GamepadAxis(axis, value) => w.write_all(&[EventTag::GamepadAxis.into(), axis.into(), value]),
+        InputEvent::PenSample(sample) => {
+            w.write_u8(EventTag::PenSample.into())?;
+            w.write_all(&sample.to_bytes())
+        }
+        InputEvent::TouchFrame(frame) => {
+            w.write_u8(EventTag::TouchFrame.into())?;
+            w.write_all(&frame.to_bytes())
+        }
+        InputEvent::HorizontalScrolling(amount) => {
+            w.write_u8(EventTag::HorizontalScrolling.into())?;
+            w.write_i32::<LittleEndian>(amount)
+        }
+        InputEvent::VerticalScrolling(amount) => {
+            w.write_u8(EventTag::VerticalScrolling.into())?;
+            w.write_i32::<LittleEndian>(amount)
+        }
+        InputEvent::Reset => w.write_u8(EventTag::Reset.into()),
+        InputEvent::Shutdown => w.write_u8(EventTag::Shutdown.into())
+    }
+}
+
+fn read_scroll_amount<R: Read>(mut r: R, version: u16) -> Result<i32> {
+    Ok(match version {
+        1 => i32::from(r.read_i8()?) * SCROLL_RESOLUTION,
+        _ => r.read_i32::<LittleEndian>()?
+    })
+}
+
+fn read_entry<R: Read>(mut r: R, version: u16) -> Result<Option<(Duration, InputEvent)>> {
+    let time = match r.read_u64::<LittleEndian>() {
+        Ok(time) => Duration::from_micros(time),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into())
+    };
+    let event = match EventTag::try_from(r.read_u8()?)? {
+        EventTag::MouseMove => InputEvent::MouseMove(r.read_i64::<LittleEndian>()?, r.read_i64::<LittleEndian>()?),
+        EventTag::KeyPress => InputEvent::KeyPress(HidKeyCode::from(r.read_u8()?)),
+        EventTag::KeyRelease => InputEvent::KeyRelease(HidKeyCode::from(r.read_u8()?)),
+        EventTag::MouseButtonPress => InputEvent::MouseButtonPress(HidButtonCode::from(r.read_u8()?)),
+        EventTag::MouseButtonRelease => InputEvent::MouseButtonRelease(HidButtonCode::from(r.read_u8()?)),
+        EventTag::ConsumerDevicePress => InputEvent::ConsumerDevicePress(ConsumerDeviceCode::from(r.read_u8()?)),
+        EventTag::ConsumerDeviceRelease => InputEvent::ConsumerDeviceRelease(ConsumerDeviceCode::from(r.read_u8()?)),
+        EventTag::SystemControlPress => InputEvent::SystemControlPress(SystemControlCode::from(r.read_u8()?)),
+        EventTag::SystemControlRelease => InputEvent::SystemControlRelease(SystemControlCode::from(r.read_u8()?)),
+        EventTag::GamepadButtonPress => InputEvent::GamepadButtonPress(GamepadButton::from(r.read_u8()?)),
+        EventTag::GamepadButtonRelease => InputEvent::GamepadButtonRelease(GamepadButton::from(r.read_u8()?)),
+        EventTag::GamepadHat => InputEvent::GamepadHat(GamepadHat::from(r.read_u8()?)),
+        EventTag::GamepadAxis => InputEvent::GamepadAxis(GamepadAxis::try_from(r.read_u8()?)?, r.read_u8()?),
+        EventTag::PenSample => {
+            let mut bytes = [0u8; PenSample::SIZE];
+            r.read_exact(&mut bytes)?;
+            InputEvent::PenSample(PenSample::from_bytes(bytes))
+        }
+        EventTag::TouchFrame => {
+            let mut bytes = [0u8; TouchFrame::SIZE];
+            r.read_exact(&mut bytes)?;
+            InputEvent::TouchFrame(TouchFrame::from_bytes(bytes))
+        }
+        EventTag::HorizontalScrolling => InputEvent::HorizontalScrolling(read_scroll_amount(&mut r, version)?),
+        EventTag::VerticalScrolling => InputEvent::VerticalScrolling(read_scroll_amount(&mut r, version)?),
+        EventTag::Reset => InputEvent::Reset,
+        EventTag::Shutdown => InputEvent::Shutdown
+    };
+    Ok(Some((time, event)))
+}
+
+fn read_recording(path: &Path) -> Result<(u16, Vec<(Duration, InputEvent)>)> {
+    let file = std::fs::read(path).with_context(|| format!("Could not read {}", path.display()))?;
+    let mut data = file.as_slice();
+    let mut magic = [0u8; MAGIC.len()];
+    data.read_exact(&mut magic)?;
+    ensure!(&magic == MAGIC, "{} is not a recording", path.display());
+    let version = data.read_u16::<LittleEndian>()?;
+    ensure!((1..=VERSION).contains(&version), "Unsupported recording version: {} (expected up to {})", version, VERSION);
+    let mut entries = Vec::new();
+    while let Some(entry) = read_entry(&mut data, version)? {
+        entries.push(entry);
+    }
+    Ok((version, entries))
+}
+
+/// Records the events as they arrive, so only the queue of `processor` merges them
+#[instrument(skip(processor))]
+pub async fn record(processor: InputQueue, path: &Path, queue_size: usize) -> Result<InputQueue> {
+    let mut file = BufWriter::new(File::create(path).await?);
+    file.write_all(MAGIC).await?;
+    file.write_all(&VERSION.to_le_bytes()).await?;
+    let (sender, mut receiver) = input_queue(queue_size);
+    tracing::debug!("Starting recorder");
+    spawn(
+        async move {
+            let start = Instant::now();
+            let mut buffer = Vec::new();
+            let mut flush = interval(FLUSH_INTERVAL);
+            flush.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                select! {
+                    event = receiver.recv_unmerged() => {
+                        let Some(event) = event else { break };
+                        buffer.clear();
+                        write_entry(&mut buffer, start.elapsed(), &event).expect("Writing to a vec can't fail");
+                        if let Err(err) = file.write_all(&buffer).await {
+                            tracing::error!("Could not write recording: {}", err);
+                            break;
+                        }
+                        if processor.send(event).await.is_err() {
+                            break;
+                        }
+                    },
+                    _ = flush.tick() => if let Err(err) = file.flush().await {
+                        tracing::error!("Could not write recording: {}", err);
+                        break;
+                    }
+                }
+            }
+            if let Err(err) = file.flush().await {
+                tracing::error!("Could not write recording: {}", err);
+            }
+            tracing::debug!("Stopping recorder");
+        }
+        .instrument(Span::current())
+    );
+    Ok(sender)
+}
+
+#[instrument(skip(processor))]
+pub async fn replay(processor: InputQueue, path: &Path, speed: f64) -> Result<()> {
+    ensure!((MIN_SPEED..=MAX_SPEED).contains(&speed), "The replay speed must be between {} and {}", MIN_SPEED, MAX_SPEED);
+    let (_, entries) = read_recording(path)?;
+    tracing::info!("Replaying {} events", entries.len());
+    let start = Instant::now();
+    for (time, event) in entries {
+        if let InputEvent::Shutdown = event {
+            tracing::warn!("Skipping recorded shutdown command");
+            continue;
+        }
+        sleep_until(start + time.div_f64(speed)).await;
+        processor.send(event).await?;
+    }
+    Ok(())
+}
+
+pub fn inspect(path: &Path) -> Result<()> {
+    let (version, entries) = read_recording(path)?;
+    for (time, event) in &entries {
+        println!("{:>12.6}s  {:?}", time.as_secs_f64(), event);
+    }
+    let duration = entries.last().map_or(Duration::ZERO, |(time, _)| *time);
+    println!("Version {}: {} events over {:.3}s", version, entries.len(), duration.as_secs_f64());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use inputshare_common::{TouchContact, TouchFrame};
+
+    use super::*;
+
+    fn events() -> Vec<InputEvent> {
+        let contact = TouchContact {
+            id: 2,
+            tip: true,
+            confidence: true,
+            x: 100,
+            y: 200
+        };
+        let sample = PenSample {
+            in_range: true,
+            tip: true,
+            x: 1234,
+            y: 5678,
+            pressure: 99,
+            tilt_x: -10,
+            ..Default::default()
+        };
+        vec![
+            InputEvent::MouseMove(-5, i64::MAX),
+            InputEvent::KeyPress(HidKeyCode::KeyA),
+            InputEvent::KeyRelease(HidKeyCode::KeyA),
+            InputEvent::MouseButtonPress(HidButtonCode::RButton),
+            InputEvent::MouseButtonRelease(HidButtonCode::RButton),
+            InputEvent::ConsumerDevicePress(ConsumerDeviceCode::VolumeUp),
+            InputEvent::ConsumerDeviceRelease(ConsumerDeviceCode::VolumeUp),
+            InputEvent::SystemControlPress(SystemControlCode::Sleep),
+            InputEvent::SystemControlRelease(SystemControlCode::Sleep),
+            InputEvent::GamepadButtonPress(GamepadButton::South),
+            InputEvent::GamepadButtonRelease(GamepadButton::South),
+            InputEvent::GamepadHat(GamepadHat::UpLeft),
+            InputEvent::GamepadAxis(GamepadAxis::RightTrigger, 200),
+            InputEvent::PenSample(sample),
+            InputEvent::TouchFrame(TouchFrame::new(77, true, &[contact])),
+            InputEvent::HorizontalScrolling(-300),
+            InputEvent::VerticalScrolling(i32::MAX),
+            InputEvent::Reset,
+            InputEvent::Shutdown
+        ]
+    }
+
+    #[test]
+    fn entries_roundtrip() {
+        let mut buffer = Vec::new();
+        for (i, event) in events().iter().enumerate() {
+            write_entry(&mut buffer, Duration::from_micros(i as u64 * 1500), event).unwrap();
+        }
+        let mut data = buffer.as_slice();
+        for (i, event) in events().into_iter().enumerate() {
+            let entry = read_entry(&mut data, VERSION).unwrap();
+            assert_eq!(entry, Some((Duration::from_micros(i as u64 * 1500), event)));
+        }
+        assert_eq!(read_entry(&mut data, VERSION).unwrap(), None);
+    }
+
+    #[test]
+    fn first_version_scrolls_in_detents() {
+        let data = [0u8; 8]
+            .into_iter()
+            .chain([EventTag::VerticalScrolling.into(), (-2i8) as u8])
+            .collect::<Vec<_>>();
+        let entry = read_entry(data.as_slice(), 1).unwrap();
+        assert_eq!(entry, Some((Duration::ZERO, InputEvent::VerticalScrolling(-2 * SCROLL_RESOLUTION))));
+    }
+
+    #[tokio::test]
+    async fn records_events_unmerged() {
+        let path = std::env::temp_dir().join(format!("inputshare-recording-{}", std::process::id()));
+        let (processor, mut processed) = input_queue(16);
+        let recorder = record(processor, &path, 16).await.unwrap();
+        let moves = [InputEvent::MouseMove(1, 2), InputEvent::MouseMove(3, 4)];
+        for event in moves {
+            recorder.send(event).await.unwrap();
+        }
+        drop(recorder);
+        // The recorder flushes before it lets go of the processor
+        while processed.recv().await.is_some() {}
+
+        let (version, entries) = read_recording(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(version, VERSION);
+        let recorded = entries
+            .into_iter()
+            .map(|(_, event)| event)
+            .collect::<Vec<_>>();
+        assert_eq!(recorded, moves);
+    }
+
+    #[tokio::test]
+    async fn replay_rejects_extreme_speeds() {
+        let path = std::env::temp_dir().join(format!("inputshare-replay-{}", std::process::id()));
+        std::fs::write(&path, MAGIC.iter().copied().chain(VERSION.to_le_bytes()).collect::<Vec<_>>()).unwrap();
+        let (processor, _processed) = input_queue(16);
+        for speed in [0.0, 1e-300, -1.0, 1e300, f64::NAN, f64::INFINITY] {
+            assert!(replay(processor.clone(), &path, speed).await.is_err(), "{} was accepted", speed);
+        }
+        assert!(replay(processor, &path, MIN_SPEED).await.is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
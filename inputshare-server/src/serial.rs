@@ -0,0 +1,186 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::{channel, Sender};
+use tokio::sync::MutexGuard;
+use tokio::{select, spawn};
+use tracing::{instrument, Instrument, Span};
+
+use crate::configfs::SerialFunction;
+use crate::util::run_command;
+
+/// Connects a single client at a time to a tty like the `/dev/ttyGS0` of the serial function
+#[derive(Debug)]
+pub struct SerialBridge {
+    _function: Option<SerialFunction>,
+    writer: tokio::sync::Mutex<File>,
+    session: Mutex<Option<Sender<Bytes>>>
+}
+
+impl SerialBridge {
+    /// The tty stays open for the lifetime of the bridge, so nothing that the target sends is swallowed by a dangling read
+    pub async fn open(function: SerialFunction, device: &Path) -> Result<Arc<Self>> {
+        Self::open_tty(Some(function), device).await
+    }
+
+    #[instrument(skip(function))]
+    async fn open_tty(function: Option<SerialFunction>, device: &Path) -> Result<Arc<Self>> {
+        run_command("stty", &["-F", &device.to_string_lossy(), "raw", "-echo"]).await?;
+        let reader = File::open(device).await?;
+        let writer = OpenOptions::new().write(true).open(device).await?;
+        let bridge = Arc::new(Self {
+            _function: function,
+            writer: tokio::sync::Mutex::new(writer),
+            session: Mutex::new(None)
+        });
+        spawn(forward_output(reader, Arc::downgrade(&bridge)).instrument(Span::current()));
+        Ok(bridge)
+    }
+
+    /// Reserves the tty for a single client
+    pub fn connect(&self) -> Result<SerialSession<'_>> {
+        let writer = self
+            .writer
+            .try_lock()
+            .map_err(|_| anyhow!("The serial port is already in use"))?;
+        Ok(SerialSession { bridge: self, writer })
+    }
+}
+
+#[derive(Debug)]
+pub struct SerialSession<'a> {
+    bridge: &'a SerialBridge,
+    writer: MutexGuard<'a, File>
+}
+
+impl SerialSession<'_> {
+    /// Copies `input` to the tty and everything the tty receives to `output` until `input` ends
+    #[instrument(skip_all)]
+    pub async fn run<R, W>(mut self, mut input: R, mut output: W) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin
+    {
+        let (sender, mut receiver) = channel(16);
+        *self.bridge.session.lock().expect("Could not acquire lock") = Some(sender);
+        tracing::debug!("Attached serial session");
+
+        let writer = &mut *self.writer;
+        let upstream = async {
+            tokio::io::copy(&mut input, writer).await?;
+            writer.flush().await?;
+            Ok(())
+        };
+        let downstream = async {
+            while let Some(bytes) = receiver.recv().await {
+                output.write_all(&bytes).await?;
+            }
+            Err(anyhow!("The serial port was closed"))
+        };
+        let result = select! {
+            result = upstream => result,
+            result = downstream => result
+        };
+
+        *self.bridge.session.lock().expect("Could not acquire lock") = None;
+        tracing::debug!("Detached serial session");
+        result
+    }
+}
+
+async fn forward_output(mut reader: File, bridge: Weak<SerialBridge>) {
+    let mut buffer = vec![0u8; 4096];
+    loop {
+        let read = match reader.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) => {
+                tracing::warn!("Could not read from the serial port: {}", err);
+                break;
+            }
+        };
+        let session = match bridge.upgrade() {
+            Some(bridge) => bridge
+                .session
+                .lock()
+                .expect("Could not acquire lock")
+                .clone(),
+            None => break
+        };
+        match session {
+            Some(session) => {
+                if session.send(Bytes::copy_from_slice(&buffer[..read])).await.is_err() {
+                    tracing::trace!("Dropped {} bytes because the session ended", read);
+                }
+            }
+            None => tracing::trace!("Dropped {} bytes because no session is attached", read)
+        }
+    }
+    if let Some(bridge) = bridge.upgrade() {
+        bridge.session.lock().expect("Could not acquire lock").take();
+    }
+    tracing::debug!("Stopped reading from the serial port");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+    use std::os::unix::io::FromRawFd;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use tokio::io::duplex;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    /// Returns the master side, the path of the slave side and the slave side itself
+    fn open_pty() -> (File, PathBuf, std::fs::File) {
+        let (mut master, mut slave) = (0, 0);
+        let result = unsafe { libc::openpty(&mut master, &mut slave, std::ptr::null_mut(), std::ptr::null(), std::ptr::null()) };
+        assert_eq!(result, 0, "Could not open a pty pair");
+        let name = unsafe { CStr::from_ptr(libc::ttyname(slave)) };
+        let path = PathBuf::from(name.to_str().unwrap());
+        // Keeping the slave side open prevents a hangup on the master side between sessions
+        let slave = unsafe { std::fs::File::from_raw_fd(slave) };
+        let master = File::from_std(unsafe { std::fs::File::from_raw_fd(master) });
+        (master, path, slave)
+    }
+
+    async fn read_exactly(reader: &mut (impl AsyncRead + Unpin), len: usize) -> Vec<u8> {
+        let mut buffer = vec![0u8; len];
+        timeout(Duration::from_secs(5), reader.read_exact(&mut buffer))
+            .await
+            .expect("Timed out")
+            .unwrap();
+        buffer
+    }
+
+    #[tokio::test]
+    async fn bridges_both_directions() {
+        let (mut master, path, _slave) = open_pty();
+        let bridge = SerialBridge::open_tty(None, &path).await.unwrap();
+
+        let (mut client_input, input) = duplex(64);
+        let (output, mut client_output) = duplex(64);
+        let session = bridge.connect().unwrap();
+        assert!(bridge.connect().is_err(), "A second client got the serial port");
+
+        let task = async move { session.run(input, output).await };
+        let test = async {
+            client_input.write_all(b"ping").await.unwrap();
+            assert_eq!(read_exactly(&mut master, 4).await, b"ping");
+            master.write_all(b"pong").await.unwrap();
+            master.flush().await.unwrap();
+            assert_eq!(read_exactly(&mut client_output, 4).await, b"pong");
+            drop(client_input);
+        };
+        let (result, ()) = tokio::join!(task, test);
+        result.unwrap();
+        assert!(bridge.connect().is_ok(), "The serial port is still reserved");
+    }
+}
@@ -0,0 +1,57 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use inputshare_common::DeviceStatus;
+use tokio::sync::watch;
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::instrument;
+
+use crate::configfs::udc_state;
+
+/// Keeps the latest `DeviceStatus` for the control streams of all clients
+#[derive(Debug, Clone)]
+pub struct StatusSender(Arc<watch::Sender<Option<DeviceStatus>>>);
+
+impl Default for StatusSender {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(None).0))
+    }
+}
+
+impl StatusSender {
+    pub fn send(&self, status: DeviceStatus) {
+        tracing::info!("Device status changed to {:?}", status);
+        self.0.send_replace(Some(status));
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Option<DeviceStatus>> {
+        self.0.subscribe()
+    }
+}
+
+/// Polls the state of the USB device controller and reports suspends and reconnects
+#[instrument(skip_all)]
+pub async fn watch_udc(status: StatusSender) {
+    let mut poll = interval(Duration::from_secs(1));
+    poll.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut last = None;
+    loop {
+        poll.tick().await;
+        let state = udc_state()
+            .await
+            .map_err(|err| tracing::debug!("Could not read the UDC state: {}", err))
+            .ok()
+            .flatten();
+        if state == last {
+            continue;
+        }
+        tracing::debug!("UDC state changed from {:?} to {:?}", last, state);
+        match (last.as_deref(), state.as_deref()) {
+            (_, Some("suspended")) => status.send(DeviceStatus::HostSuspended),
+            (_, Some("not attached")) => status.send(DeviceStatus::HostDisconnected),
+            (Some(_), Some("configured")) => status.send(DeviceStatus::GadgetReconnected),
+            _ => {}
+        }
+        last = state;
+    }
+}
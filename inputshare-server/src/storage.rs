@@ -0,0 +1,100 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use tokio::fs::{remove_file, rename, File};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tracing::instrument;
+
+use crate::configfs::MassStorage;
+use crate::util::run_command;
+
+/// Replaces the image of the mass storage function with uploads from the clients
+#[derive(Debug)]
+pub struct ImageStore {
+    storage: MassStorage,
+    max_size: u64
+}
+
+impl ImageStore {
+    pub fn new(storage: MassStorage, max_size: u64) -> Self {
+        Self { storage, max_size }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn eject(&self) -> Result<()> {
+        self.storage.eject().await
+    }
+
+    #[instrument(skip(self))]
+    pub async fn insert(&self) -> Result<()> {
+        self.storage.insert().await
+    }
+
+    #[instrument(skip(self, content))]
+    pub async fn replace_image<R: AsyncRead + Unpin>(&self, content: R) -> Result<()> {
+        let upload = self.sibling("upload");
+        let size = self.receive(content, &upload).await?;
+        tracing::debug!("Received a new image with {} bytes", size);
+        self.swap(&upload).await
+    }
+
+    /// Creates a new FAT image that only contains `name`. Requires `mkfs.vfat` and `mcopy` (dosfstools and mtools)
+    #[instrument(skip(self, content))]
+    pub async fn push_file<R: AsyncRead + Unpin>(&self, name: &str, content: R) -> Result<()> {
+        ensure!(
+            !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\', ':']),
+            "\"{}\" is not a valid file name",
+            name
+        );
+        let upload = self.sibling("upload");
+        let size = self.receive(content, &upload).await?;
+        tracing::debug!("Received {} with {} bytes", name, size);
+
+        let image = self.sibling("new");
+        if image.exists() {
+            remove_file(&image).await?;
+        }
+        let result = async {
+            run_command("mkfs.vfat", &["-C", "-n", "INPUTSHARE", &image.to_string_lossy(), &image_size_kib(size).to_string()])
+                .await
+                .context("Could not create the file system")?;
+            run_command("mcopy", &["-i", &image.to_string_lossy(), &upload.to_string_lossy(), &format!("::{}", name)])
+                .await
+                .context("Could not copy the file into the image")
+        }
+        .await;
+        remove_file(&upload).await?;
+        result?;
+        self.swap(&image).await
+    }
+
+    fn sibling(&self, extension: &str) -> PathBuf {
+        self.storage.image().with_extension(extension)
+    }
+
+    async fn receive<R: AsyncRead + Unpin>(&self, content: R, path: &Path) -> Result<u64> {
+        let mut file = File::create(path).await?;
+        let size = tokio::io::copy(&mut content.take(self.max_size + 1), &mut file).await?;
+        file.flush().await?;
+        if size > self.max_size {
+            drop(file);
+            remove_file(path).await?;
+            bail!("The upload exceeds the limit of {} bytes", self.max_size);
+        }
+        Ok(size)
+    }
+
+    async fn swap(&self, image: &Path) -> Result<()> {
+        self.storage.eject().await?;
+        rename(image, self.storage.image()).await?;
+        self.storage.insert().await
+    }
+}
+
+/// Leaves room for the file system structures next to the file.
+/// Whole MiBs keep mtools from complaining about partial tracks.
+fn image_size_kib(content_size: u64) -> u64 {
+    let content = content_size.div_ceil(1024);
+    let size = content + content / 16 + 1024;
+    size.div_ceil(1024).max(2) * 1024
+}
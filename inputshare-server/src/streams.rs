@@ -0,0 +1,142 @@
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, ensure, Result};
+use inputshare_common::StreamType;
+use quinn::{Connection, RecvStream, SendStream};
+use tokio::io::AsyncReadExt;
+use tokio::spawn;
+use tokio::sync::Mutex;
+use tracing::{instrument, Instrument, Span};
+
+use crate::control::handle_control;
+use crate::queue::InputQueue;
+use crate::serial::SerialBridge;
+use crate::status::StatusSender;
+use crate::storage::ImageStore;
+use crate::typing::Typist;
+
+/// The services that clients can reach through bidirectional streams
+#[derive(Debug, Default)]
+pub struct StreamServices {
+    pub status: StatusSender,
+    pub storage: Option<Mutex<ImageStore>>,
+    pub serial: Option<Arc<SerialBridge>>,
+    /// The clients that are allowed to use the serial port
+    pub serial_clients: Vec<IpAddr>,
+    /// The clients that are allowed to change the mass storage device
+    pub storage_clients: Vec<IpAddr>,
+    pub typist: Option<Mutex<Typist>>
+}
+
+impl StreamServices {
+    fn storage(&self, client: IpAddr) -> Result<&Mutex<ImageStore>> {
+        let storage = self
+            .storage
+            .as_ref()
+            .ok_or_else(|| anyhow!("The mass storage function is disabled"))?;
+        ensure!(
+            self.storage_clients.contains(&client),
+            "{} is not allowed to change the mass storage device",
+            client
+        );
+        Ok(storage)
+    }
+
+    fn serial(&self, client: IpAddr) -> Result<&SerialBridge> {
+        let serial = self
+            .serial
+            .as_ref()
+            .ok_or_else(|| anyhow!("The serial function is disabled"))?;
+        ensure!(
+            self.serial_clients.contains(&client),
+            "{} is not allowed to use the serial port",
+            client
+        );
+        Ok(serial)
+    }
+
+    fn typist(&self) -> Result<&Mutex<Typist>> {
+        self.typist
+            .as_ref()
+            .ok_or_else(|| anyhow!("Typing is not supported"))
+    }
+}
+
+/// `handshake` is set once a control stream completed the handshake
+pub async fn accept_streams(connection: Connection, processor: InputQueue, services: Arc<StreamServices>, handshake: Arc<AtomicBool>) {
+    let client = connection.remote_address().ip();
+    while let Ok((send, recv)) = connection.accept_bi().await {
+        let processor = processor.clone();
+        let services = services.clone();
+        let handshake = handshake.clone();
+        spawn(
+            async move {
+                if let Err(err) = handle_stream(&processor, &services, &handshake, client, send, recv).await {
+                    tracing::warn!("Stream failed: {:?}", err);
+                }
+            }
+            .instrument(Span::current())
+        );
+    }
+}
+
+#[instrument(skip(processor, services, handshake, send, recv))]
+async fn handle_stream(
+    processor: &InputQueue, services: &StreamServices, handshake: &AtomicBool, client: IpAddr, mut send: SendStream, mut recv: RecvStream
+) -> Result<()> {
+    let stream_type = StreamType::try_from(recv.read_u8().await?)?;
+    tracing::debug!("Received {:?} request", stream_type);
+    let result = match stream_type {
+        StreamType::Control => return handle_control(processor, services.status.subscribe(), handshake, send, recv).await,
+        // Clients with an unsupported protocol version don't get to use any service
+        _ if !handshake.load(Ordering::Acquire) => Err(anyhow!("The control stream has to complete the handshake first")),
+        StreamType::Serial => match services.serial(client).and_then(|serial| serial.connect()) {
+            Ok(session) => {
+                send.write_all(&[0]).await?;
+                return session.run(recv, send).await;
+            }
+            Err(err) => Err(err)
+        },
+        StreamType::TypeText => match services.typist().and_then(|typist| typist.try_lock().map_err(|_| anyhow!("Already typing"))) {
+            Ok(typist) => {
+                send.write_all(&[0]).await?;
+                match typist.type_text(recv, &mut send).await {
+                    Ok(()) => send.finish().await?,
+                    Err(err) => tracing::debug!("Stopped typing early: {:#}", err)
+                }
+                return Ok(());
+            }
+            Err(err) => Err(err)
+        },
+        _ => handle_storage(services, client, stream_type, &mut recv).await
+    };
+    match result {
+        Ok(()) => send.write_all(&[0]).await?,
+        Err(err) => {
+            tracing::warn!("{:?} request failed: {:?}", stream_type, err);
+            send.write_all(&[1]).await?;
+            send.write_all(format!("{:#}", err).as_bytes()).await?;
+        }
+    }
+    send.finish().await?;
+    Ok(())
+}
+
+async fn handle_storage(services: &StreamServices, client: IpAddr, stream_type: StreamType, recv: &mut RecvStream) -> Result<()> {
+    let storage = services.storage(client)?.lock().await;
+    match stream_type {
+        StreamType::EjectStorage => storage.eject().await,
+        StreamType::InsertStorage => storage.insert().await,
+        StreamType::ReplaceStorageImage => storage.replace_image(recv).await,
+        StreamType::PushStorageFile => {
+            let mut name = vec![0u8; recv.read_u16_le().await? as usize];
+            recv.read_exact(&mut name).await?;
+            storage
+                .push_file(&String::from_utf8_lossy(&name), recv)
+                .await
+        }
+        StreamType::Serial | StreamType::TypeText | StreamType::Control => bail!("{:?} is not a storage request", stream_type)
+    }
+}
@@ -0,0 +1,141 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use inputshare_common::HidKeyCode;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::sleep;
+use tracing::instrument;
+
+use crate::queue::InputQueue;
+use crate::receiver::InputEvent;
+
+/// Small reads keep the typing responsive to a cancellation of the stream
+const CHUNK_SIZE: usize = 16;
+
+/// Types text as if it was entered on a keyboard with a US layout
+#[derive(Debug)]
+pub struct Typist {
+    processor: InputQueue,
+    interval: Duration
+}
+
+impl Typist {
+    /// `interval` is the pause after every key event. Too short intervals can make the host drop characters.
+    pub fn new(processor: InputQueue, interval: Duration) -> Self {
+        Self { processor, interval }
+    }
+
+    /// Types the UTF-8 text of `input` and reports the number of processed characters to `progress` after every chunk
+    #[instrument(skip_all)]
+    pub async fn type_text<R, W>(&self, mut input: R, mut progress: W) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin
+    {
+        let mut buffer = Vec::new();
+        let mut chunk = [0u8; CHUNK_SIZE];
+        let mut processed = 0u32;
+        loop {
+            let read = input.read(&mut chunk).await?;
+            if read == 0 {
+                break;
+            }
+            buffer.extend_from_slice(&chunk[..read]);
+            let valid = match std::str::from_utf8(&buffer) {
+                Ok(text) => text.len(),
+                Err(err) => match err.error_len() {
+                    Some(_) => anyhow::bail!("The text is not valid UTF-8"),
+                    None => err.valid_up_to()
+                }
+            };
+            let text = String::from_utf8(buffer.drain(..valid).collect())?;
+            for c in text.chars() {
+                match key_for_char(c) {
+                    Some((key, shift)) => self.type_key(key, shift).await?,
+                    None => tracing::debug!("Skipping {:?} because it can't be typed", c)
+                }
+                processed += 1;
+            }
+            progress.write_all(&processed.to_le_bytes()).await?;
+        }
+        tracing::debug!("Typed {} characters", processed);
+        Ok(())
+    }
+
+    async fn type_key(&self, key: HidKeyCode, shift: bool) -> Result<()> {
+        if shift {
+            self.processor
+                .send(InputEvent::KeyPress(HidKeyCode::LeftShift))
+                .await?;
+        }
+        self.processor.send(InputEvent::KeyPress(key)).await?;
+        sleep(self.interval).await;
+        self.processor.send(InputEvent::KeyRelease(key)).await?;
+        if shift {
+            self.processor
+                .send(InputEvent::KeyRelease(HidKeyCode::LeftShift))
+                .await?;
+        }
+        sleep(self.interval).await;
+        Ok(())
+    }
+}
+
+/// The key and whether shift has to be held for a US keyboard layout
+fn key_for_char(c: char) -> Option<(HidKeyCode, bool)> {
+    let key = match c.to_ascii_lowercase() {
+        'a' => HidKeyCode::KeyA,
+        'b' => HidKeyCode::KeyB,
+        'c' => HidKeyCode::KeyC,
+        'd' => HidKeyCode::KeyD,
+        'e' => HidKeyCode::KeyE,
+        'f' => HidKeyCode::KeyF,
+        'g' => HidKeyCode::KeyG,
+        'h' => HidKeyCode::KeyH,
+        'i' => HidKeyCode::KeyI,
+        'j' => HidKeyCode::KeyJ,
+        'k' => HidKeyCode::KeyK,
+        'l' => HidKeyCode::KeyL,
+        'm' => HidKeyCode::KeyM,
+        'n' => HidKeyCode::KeyN,
+        'o' => HidKeyCode::KeyO,
+        'p' => HidKeyCode::KeyP,
+        'q' => HidKeyCode::KeyQ,
+        'r' => HidKeyCode::KeyR,
+        's' => HidKeyCode::KeyS,
+        't' => HidKeyCode::KeyT,
+        'u' => HidKeyCode::KeyU,
+        'v' => HidKeyCode::KeyV,
+        'w' => HidKeyCode::KeyW,
+        'x' => HidKeyCode::KeyX,
+        'y' => HidKeyCode::KeyY,
+        'z' => HidKeyCode::KeyZ,
+        '1' | '!' => HidKeyCode::Key1,
+        '2' | '@' => HidKeyCode::Key2,
+        '3' | '#' => HidKeyCode::Key3,
+        '4' | '$' => HidKeyCode::Key4,
+        '5' | '%' => HidKeyCode::Key5,
+        '6' | '^' => HidKeyCode::Key6,
+        '7' | '&' => HidKeyCode::Key7,
+        '8' | '*' => HidKeyCode::Key8,
+        '9' | '(' => HidKeyCode::Key9,
+        '0' | ')' => HidKeyCode::Key0,
+        '\n' => HidKeyCode::Enter,
+        '\t' => HidKeyCode::Tab,
+        ' ' => HidKeyCode::Space,
+        '-' | '_' => HidKeyCode::Minus,
+        '=' | '+' => HidKeyCode::Equal,
+        '[' | '{' => HidKeyCode::LeftBrace,
+        ']' | '}' => HidKeyCode::RightBrace,
+        '\\' | '|' => HidKeyCode::Backslash,
+        ';' | ':' => HidKeyCode::Semicolon,
+        '\'' | '"' => HidKeyCode::Apostrophe,
+        '`' | '~' => HidKeyCode::Grave,
+        ',' | '<' => HidKeyCode::Comma,
+        '.' | '>' => HidKeyCode::Dot,
+        '/' | '?' => HidKeyCode::Slash,
+        _ => return None
+    };
+    let shift = c.is_ascii_uppercase() || "!@#$%^&*()_+{}|:\"~<>?".contains(c);
+    Some((key, shift))
+}
@@ -1,21 +1,70 @@
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::path::Path;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use inputshare_common::format_fingerprint;
 use mdns_sd::{DaemonEvent, Receiver, ServiceDaemon, ServiceInfo};
 use quinn::ServerConfig;
+use ring::digest::{digest, SHA256};
+use rustls::{Certificate, PrivateKey};
+use tokio::process::Command;
 use tracing::instrument;
 
+/// Generates a new certificate on every start unless it is kept in `identity`
 #[instrument]
-pub fn self_signed_config(concurrent_connections: u32) -> Result<ServerConfig> {
-    let cert = rcgen::generate_simple_self_signed([String::from("inputshare")])?;
-    let key = rustls::PrivateKey(cert.serialize_private_key_der());
-    let cert = rustls::Certificate(cert.serialize_der()?);
+pub fn self_signed_config(concurrent_connections: u32, identity: Option<&Path>) -> Result<ServerConfig> {
+    let (cert, key) = match identity {
+        Some(dir) => load_identity(dir)?,
+        None => generate_identity()?
+    };
+    tracing::info!("Certificate fingerprint: {}", format_fingerprint(digest(&SHA256, &cert.0).as_ref()));
     let mut config = ServerConfig::with_single_cert(vec![cert], key)?;
     config.concurrent_connections(concurrent_connections);
     Ok(config)
 }
 
+fn generate_identity() -> Result<(Certificate, PrivateKey)> {
+    let cert = rcgen::generate_simple_self_signed([String::from("inputshare")])?;
+    Ok((Certificate(cert.serialize_der()?), PrivateKey(cert.serialize_private_key_der())))
+}
+
+fn load_identity(dir: &Path) -> Result<(Certificate, PrivateKey)> {
+    let cert_path = dir.join("cert.der");
+    let key_path = dir.join("key.der");
+    if cert_path.exists() && key_path.exists() {
+        return Ok((Certificate(std::fs::read(cert_path)?), PrivateKey(std::fs::read(key_path)?)));
+    }
+    let (cert, key) = generate_identity()?;
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(&cert_path, &cert.0)?;
+    write_private(&key_path, &key.0)?;
+    tracing::info!("Stored a new certificate in {}", dir.display());
+    Ok((cert, key))
+}
+
+/// Creates the file so that only the owner can read it before anything is written
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::fs::{OpenOptions, Permissions};
+    use std::io::Write;
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // The mode only applies to new files
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    file.write_all(contents)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, contents)
+}
+
 #[instrument]
 pub fn start_mdns(interface: SocketAddr) -> Result<ServiceDaemon> {
     let mdns = ServiceDaemon::new()?;
@@ -34,6 +83,19 @@ pub fn start_mdns(interface: SocketAddr) -> Result<ServiceDaemon> {
     Ok(mdns)
 }
 
+#[instrument]
+pub async fn run_command(command: &str, args: &[&str]) -> Result<()> {
+    let output = Command::new(command).args(args).output().await?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if output.status.success() {
+        if !stderr.is_empty() {
+            tracing::debug!("{} succeeded with warnings: {}", command, stderr.trim_end());
+        }
+        return Ok(());
+    }
+    bail!("{} failed with {}: {}", command, output.status, stderr.trim_end());
+}
+
 #[instrument(skip(monitor))]
 async fn monitor_daemon(monitor: Receiver<DaemonEvent>) {
     while let Ok(event) = monitor.recv_async().await {
@@ -60,3 +122,32 @@ pub async fn quit() {
         .await
         .expect("Could not register signals")
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn private_files_are_only_readable_by_the_owner() {
+        let path = std::env::temp_dir().join(format!("inputshare-key-{}", std::process::id()));
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        write_private(&path, b"new").unwrap();
+        assert_eq!(mode(&path), 0o600);
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        write_private(&path, b"replaced").unwrap();
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(std::fs::read(&path).unwrap(), b"replaced");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn commands_fail_only_on_their_exit_status() {
+        run_command("sh", &["-c", "echo warning >&2"]).await.unwrap();
+        let err = run_command("sh", &["-c", "echo broken >&2; exit 3"]).await.unwrap_err();
+        assert!(err.to_string().ends_with("broken"), "{}", err);
+    }
+}
@@ -1,9 +1,16 @@
+mod packet;
+
 use std::fmt::Debug;
 
 use num_enum::{FromPrimitive, IntoPrimitive, TryFromPrimitive};
 
+pub use packet::{AckPacket, InputHeader};
+
 pub const IDENTIFIER: &str = concat!(env!("CARGO_CRATE_NAME"), "_", env!("CARGO_PKG_VERSION"));
 
+/// Exchanged in the handshake of the control stream. Has to be increased for every incompatible change of the protocol.
+pub const PROTOCOL_VERSION: u16 = 3;
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Vec2<T>
 where
@@ -24,6 +31,23 @@ where
 
 pub type MouseType = i64;
 
+/// Compares packet sequence numbers in a way that survives a wraparound
+pub fn is_newer_sequence(sequence: u64, last: u64) -> bool {
+    (sequence.wrapping_sub(last) as i64) > 0
+}
+
+/// Formats the SHA-256 hash of a certificate like `AB:CD:...`, which is how clients pin the certificate of a server
+pub fn format_fingerprint(digest: &[u8]) -> String {
+    digest
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// The number of high resolution scroll units that make up one wheel detent.
+pub const SCROLL_RESOLUTION: i32 = 120;
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
 #[repr(u8)]
 pub enum MessageType {
@@ -36,7 +60,281 @@ pub enum MessageType {
     HorizontalScrolling,
     VerticalScrolling,
     Reset,
-    Shutdown
+    /// Only sent by clients that predate the control stream
+    Shutdown,
+    HighResHorizontalScrolling,
+    HighResVerticalScrolling,
+    SystemControlPress,
+    SystemControlRelease,
+    GamepadButtonPress,
+    GamepadButtonRelease,
+    GamepadHat,
+    GamepadLeftStickX,
+    GamepadLeftStickY,
+    GamepadRightStickX,
+    GamepadRightStickY,
+    GamepadLeftTrigger,
+    GamepadRightTrigger,
+    PenSample,
+    TouchFrame
+}
+
+impl MessageType {
+    /// The number of bytes that follow the message type
+    pub fn payload_size(self) -> usize {
+        match self {
+            MessageType::PenSample => PenSample::SIZE,
+            MessageType::TouchFrame => TouchFrame::SIZE,
+            _ => 1
+        }
+    }
+}
+
+/// The first byte of every bidirectional stream opened by the client.
+/// The server answers with a single byte that is 0 on success and otherwise followed by an error message.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum StreamType {
+    /// Detaches the image of the mass storage function from the host
+    EjectStorage,
+    /// Attaches the image of the mass storage function to the host again
+    InsertStorage,
+    /// Replaces the image of the mass storage function with the rest of the stream
+    ReplaceStorageImage,
+    /// Replaces the image of the mass storage function with a new one that contains a single file.
+    /// Followed by the length of the file name as u16, the file name and the content of the file.
+    PushStorageFile,
+    /// Connects the stream to the serial port of the acm function. The data follows directly after the status byte.
+    Serial,
+    /// Types the UTF-8 text of the stream on the keyboard. After the status byte the server repeatedly reports
+    /// the number of processed characters as u32. Resetting the stream cancels the typing.
+    TypeText,
+    /// Opened once by the client right after connecting. Carries `ControlMessage`s in both directions instead of a status byte.
+    Control
+}
+
+/// The reliable counterpart to the input datagrams.
+/// Every message is framed by its length as u16 followed by a tag byte and the payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlMessage {
+    /// The first message in both directions
+    Hello { protocol: u16 },
+    /// Turns off the device of the server
+    Shutdown,
+    /// Sent by the server whenever the state of its USB gadget changes and once after the handshake
+    Status(DeviceStatus),
+    Error(String)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum DeviceStatus {
+    /// Writing a HID report failed, so input is no longer forwarded
+    HidWriteFailed,
+    /// The USB host configured the gadget again
+    GadgetReconnected,
+    HostSuspended,
+    HostDisconnected,
+    /// The input processor works again after a failure
+    ProcessorRestarted
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+enum ControlTag {
+    Hello,
+    Shutdown,
+    Status,
+    Error
+}
+
+impl ControlMessage {
+    pub fn to_frame(&self) -> Vec<u8> {
+        let mut frame = vec![0, 0];
+        match self {
+            ControlMessage::Hello { protocol } => {
+                frame.push(ControlTag::Hello.into());
+                frame.extend_from_slice(&protocol.to_le_bytes());
+            }
+            ControlMessage::Shutdown => frame.push(ControlTag::Shutdown.into()),
+            ControlMessage::Status(status) => {
+                frame.push(ControlTag::Status.into());
+                frame.push((*status).into());
+            }
+            ControlMessage::Error(msg) => {
+                frame.push(ControlTag::Error.into());
+                frame.extend_from_slice(&msg.as_bytes()[..msg.len().min(u16::MAX as usize - 1)]);
+            }
+        }
+        let len = (frame.len() - 2) as u16;
+        frame[..2].copy_from_slice(&len.to_le_bytes());
+        frame
+    }
+
+    /// Parses a frame without its length prefix
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        let (tag, payload) = payload.split_first()?;
+        match ControlTag::try_from(*tag).ok()? {
+            ControlTag::Hello => Some(ControlMessage::Hello {
+                protocol: u16::from_le_bytes(payload.try_into().ok()?)
+            }),
+            ControlTag::Shutdown => Some(ControlMessage::Shutdown),
+            ControlTag::Status => match payload {
+                [status] => DeviceStatus::try_from(*status).ok().map(ControlMessage::Status),
+                _ => None
+            },
+            ControlTag::Error => Some(ControlMessage::Error(String::from_utf8_lossy(payload).into_owned()))
+        }
+    }
+}
+
+/// A single sample of a pen digitizer
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct PenSample {
+    pub in_range: bool,
+    pub tip: bool,
+    pub barrel: bool,
+    /// The pen is flipped around; a pressed tip erases
+    pub eraser: bool,
+    /// The absolute position from 0 to `PenSample::MAX_POSITION` on both axes
+    pub x: u16,
+    pub y: u16,
+    /// From 0 to `PenSample::MAX_PRESSURE`
+    pub pressure: u16,
+    /// The tilt in degrees from -90 to 90
+    pub tilt_x: i8,
+    pub tilt_y: i8
+}
+
+impl PenSample {
+    pub const SIZE: usize = 9;
+    pub const MAX_POSITION: u16 = 0x7FFF;
+    pub const MAX_PRESSURE: u16 = 0x0FFF;
+
+    pub fn same_buttons(&self, other: &PenSample) -> bool {
+        (self.in_range, self.tip, self.barrel, self.eraser) == (other.in_range, other.tip, other.barrel, other.eraser)
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let flags = self.in_range as u8 | (self.tip as u8) << 1 | (self.barrel as u8) << 2 | (self.eraser as u8) << 3;
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0] = flags;
+        bytes[1..=2].copy_from_slice(&self.x.to_le_bytes());
+        bytes[3..=4].copy_from_slice(&self.y.to_le_bytes());
+        bytes[5..=6].copy_from_slice(&self.pressure.to_le_bytes());
+        bytes[7] = self.tilt_x as u8;
+        bytes[8] = self.tilt_y as u8;
+        bytes
+    }
+
+    pub fn from_bytes(bytes: [u8; Self::SIZE]) -> Self {
+        Self {
+            in_range: bytes[0] & 0x01 != 0,
+            tip: bytes[0] & 0x02 != 0,
+            barrel: bytes[0] & 0x04 != 0,
+            eraser: bytes[0] & 0x08 != 0,
+            x: u16::from_le_bytes([bytes[1], bytes[2]]).min(Self::MAX_POSITION),
+            y: u16::from_le_bytes([bytes[3], bytes[4]]).min(Self::MAX_POSITION),
+            pressure: u16::from_le_bytes([bytes[5], bytes[6]]).min(Self::MAX_PRESSURE),
+            tilt_x: (bytes[7] as i8).clamp(-90, 90),
+            tilt_y: (bytes[8] as i8).clamp(-90, 90)
+        }
+    }
+}
+
+/// A single finger on a touchpad
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct TouchContact {
+    pub id: u8,
+    /// The finger touches the surface. A contact has to be reported once with `tip` unset after being lifted
+    pub tip: bool,
+    /// The contact is a finger and not for example a palm
+    pub confidence: bool,
+    /// From 0 to `TouchFrame::MAX_X`
+    pub x: u16,
+    /// From 0 to `TouchFrame::MAX_Y`
+    pub y: u16
+}
+
+impl TouchContact {
+    const SIZE: usize = 6;
+
+    fn write_bytes(&self, bytes: &mut [u8]) {
+        bytes[0] = self.tip as u8 | (self.confidence as u8) << 1;
+        bytes[1] = self.id;
+        bytes[2..=3].copy_from_slice(&self.x.to_le_bytes());
+        bytes[4..=5].copy_from_slice(&self.y.to_le_bytes());
+    }
+
+    fn read_bytes(bytes: &[u8]) -> Self {
+        Self {
+            id: bytes[1],
+            tip: bytes[0] & 0x01 != 0,
+            confidence: bytes[0] & 0x02 != 0,
+            x: u16::from_le_bytes([bytes[2], bytes[3]]).min(TouchFrame::MAX_X),
+            y: u16::from_le_bytes([bytes[4], bytes[5]]).min(TouchFrame::MAX_Y)
+        }
+    }
+}
+
+/// All contacts of a touchpad at a single point in time
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct TouchFrame {
+    /// A relative timestamp in units of 100µs that is allowed to wrap around
+    pub scan_time: u16,
+    pub button: bool,
+    count: u8,
+    contacts: [TouchContact; TouchFrame::MAX_CONTACTS]
+}
+
+impl TouchFrame {
+    pub const MAX_CONTACTS: usize = 5;
+    /// The touchpad is 100mm x 62.5mm with a resolution of 0.025mm
+    pub const MAX_X: u16 = 4000;
+    pub const MAX_Y: u16 = 2500;
+    pub const SIZE: usize = 4 + Self::MAX_CONTACTS * TouchContact::SIZE;
+
+    /// Contacts past `TouchFrame::MAX_CONTACTS` are dropped
+    pub fn new(scan_time: u16, button: bool, contacts: &[TouchContact]) -> Self {
+        let count = contacts.len().min(Self::MAX_CONTACTS);
+        let mut frame = Self {
+            scan_time,
+            button,
+            count: count as u8,
+            ..Self::default()
+        };
+        frame.contacts[..count].copy_from_slice(&contacts[..count]);
+        frame
+    }
+
+    pub fn contacts(&self) -> &[TouchContact] {
+        &self.contacts[..self.count as usize]
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0..=1].copy_from_slice(&self.scan_time.to_le_bytes());
+        bytes[2] = self.button as u8;
+        bytes[3] = self.count;
+        for (contact, chunk) in self.contacts().iter().zip(bytes[4..].chunks_exact_mut(TouchContact::SIZE)) {
+            contact.write_bytes(chunk);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: [u8; Self::SIZE]) -> Self {
+        let mut frame = Self {
+            scan_time: u16::from_le_bytes([bytes[0], bytes[1]]),
+            button: bytes[2] & 0x01 != 0,
+            count: bytes[3].min(Self::MAX_CONTACTS as u8),
+            ..Self::default()
+        };
+        let count = frame.count as usize;
+        for (contact, chunk) in frame.contacts[..count].iter_mut().zip(bytes[4..].chunks_exact(TouchContact::SIZE)) {
+            *contact = TouchContact::read_bytes(chunk);
+        }
+        frame
+    }
 }
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, IntoPrimitive, FromPrimitive)]
@@ -79,7 +377,106 @@ pub enum ConsumerDeviceCode {
     BrowserForward,
     BrowserStop,
     BrowserRefresh,
-    BrowserFavorites
+    BrowserFavorites,
+    FastForward,
+    Rewind,
+    Eject,
+    RandomPlay,
+    Repeat,
+    BrightnessUp,
+    BrightnessDown,
+    ScreenSaver,
+    ControlPanel,
+    TaskManager,
+    InternetBrowser,
+    ZoomIn,
+    ZoomOut,
+    Play
+}
+
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, IntoPrimitive, FromPrimitive)]
+#[repr(u8)]
+pub enum SystemControlCode {
+    #[num_enum(default)]
+    None = 0x00,
+    PowerDown,
+    Sleep,
+    WakeUp
+}
+
+/// The gamepad buttons; the value is the HID button number
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, IntoPrimitive, FromPrimitive)]
+#[repr(u8)]
+pub enum GamepadButton {
+    #[num_enum(default)]
+    None = 0x00,
+    South,
+    East,
+    West,
+    North,
+    LeftShoulder,
+    RightShoulder,
+    Select,
+    Start,
+    LeftStick,
+    RightStick,
+    Mode
+}
+
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, IntoPrimitive, FromPrimitive)]
+#[repr(u8)]
+pub enum GamepadHat {
+    Up = 0x00,
+    UpRight,
+    Right,
+    DownRight,
+    Down,
+    DownLeft,
+    Left,
+    UpLeft,
+    #[num_enum(default)]
+    Centered
+}
+
+/// Axis values are unsigned bytes; sticks rest at `0x80`, triggers at `0x00`
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum GamepadAxis {
+    LeftStickX,
+    LeftStickY,
+    RightStickX,
+    RightStickY,
+    LeftTrigger,
+    RightTrigger
+}
+
+impl GamepadAxis {
+    pub const ALL: [GamepadAxis; 6] = [
+        GamepadAxis::LeftStickX,
+        GamepadAxis::LeftStickY,
+        GamepadAxis::RightStickX,
+        GamepadAxis::RightStickY,
+        GamepadAxis::LeftTrigger,
+        GamepadAxis::RightTrigger
+    ];
+
+    pub fn neutral(self) -> u8 {
+        match self {
+            GamepadAxis::LeftTrigger | GamepadAxis::RightTrigger => 0x00,
+            _ => 0x80
+        }
+    }
+
+    pub fn message_type(self) -> MessageType {
+        match self {
+            GamepadAxis::LeftStickX => MessageType::GamepadLeftStickX,
+            GamepadAxis::LeftStickY => MessageType::GamepadLeftStickY,
+            GamepadAxis::RightStickX => MessageType::GamepadRightStickX,
+            GamepadAxis::RightStickY => MessageType::GamepadRightStickY,
+            GamepadAxis::LeftTrigger => MessageType::GamepadLeftTrigger,
+            GamepadAxis::RightTrigger => MessageType::GamepadRightTrigger
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, IntoPrimitive, FromPrimitive)]
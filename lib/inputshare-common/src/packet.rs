@@ -0,0 +1,175 @@
+use crate::MouseType;
+
+/// The header of every input packet, followed by `message_count` messages.
+/// The mouse position is relative to the last position that the server acknowledged,
+/// so resending a packet still describes the same absolute position.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct InputHeader {
+    pub session: u32,
+    pub sequence: u64,
+    /// How many packets before this one the client sent the packet whose position the server acknowledged
+    pub base_distance: u64,
+    pub mouse_x: MouseType,
+    pub mouse_y: MouseType,
+    pub start_message: u64,
+    pub message_count: u8
+}
+
+impl InputHeader {
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.session.to_le_bytes());
+        write_varint(buf, self.sequence);
+        write_varint(buf, self.base_distance);
+        write_signed_varint(buf, self.mouse_x);
+        write_signed_varint(buf, self.mouse_y);
+        write_varint(buf, self.start_message);
+        buf.push(self.message_count);
+    }
+
+    pub fn read(buf: &mut &[u8]) -> Option<Self> {
+        Some(Self {
+            session: read_u32(buf)?,
+            sequence: read_varint(buf)?,
+            base_distance: read_varint(buf)?,
+            mouse_x: read_signed_varint(buf)?,
+            mouse_y: read_signed_varint(buf)?,
+            start_message: read_varint(buf)?,
+            message_count: read_u8(buf)?
+        })
+    }
+}
+
+/// The answer of the server to every input packet
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct AckPacket {
+    pub session: u32,
+    pub sequence: u64,
+    /// The sequence of the newest input packet that the server applied
+    pub applied_sequence: u64,
+    /// The absolute mouse position after applying that packet
+    pub mouse_x: MouseType,
+    pub mouse_y: MouseType,
+    pub last_message: u64
+}
+
+impl AckPacket {
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.session.to_le_bytes());
+        write_varint(buf, self.sequence);
+        write_varint(buf, self.applied_sequence);
+        write_signed_varint(buf, self.mouse_x);
+        write_signed_varint(buf, self.mouse_y);
+        write_varint(buf, self.last_message);
+    }
+
+    pub fn read(buf: &mut &[u8]) -> Option<Self> {
+        Some(Self {
+            session: read_u32(buf)?,
+            sequence: read_varint(buf)?,
+            applied_sequence: read_varint(buf)?,
+            mouse_x: read_signed_varint(buf)?,
+            mouse_y: read_signed_varint(buf)?,
+            last_message: read_varint(buf)?
+        })
+    }
+}
+
+/// LEB128: seven bits per byte, the highest bit marks that more bytes follow
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn read_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = read_u8(buf)?;
+        value |= u64::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Zigzag encoding keeps small negative numbers small
+fn write_signed_varint(buf: &mut Vec<u8>, value: i64) {
+    write_varint(buf, ((value << 1) ^ (value >> 63)) as u64)
+}
+
+fn read_signed_varint(buf: &mut &[u8]) -> Option<i64> {
+    let value = read_varint(buf)?;
+    Some((value >> 1) as i64 ^ -((value & 1) as i64))
+}
+
+fn read_u8(buf: &mut &[u8]) -> Option<u8> {
+    let (byte, rest) = buf.split_first()?;
+    *buf = rest;
+    Some(*byte)
+}
+
+fn read_u32(buf: &mut &[u8]) -> Option<u32> {
+    let (bytes, rest) = buf.split_first_chunk::<4>()?;
+    *buf = rest;
+    Some(u32::from_le_bytes(*bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_roundtrip() {
+        for value in [0, 1, 0x7F, 0x80, 0x3FFF, 0x4000, u32::MAX as u64, u64::MAX - 1, u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value);
+            assert_eq!(buf.len(), (64 - value.leading_zeros() as usize).max(1).div_ceil(7));
+            let mut slice = buf.as_slice();
+            assert_eq!(read_varint(&mut slice), Some(value));
+            assert!(slice.is_empty());
+        }
+    }
+
+    #[test]
+    fn signed_varint_roundtrip() {
+        for value in [0, 1, -1, 63, -64, 64, -65, i32::MIN as i64, i64::MAX, i64::MIN] {
+            let mut buf = Vec::new();
+            write_signed_varint(&mut buf, value);
+            let mut slice = buf.as_slice();
+            assert_eq!(read_signed_varint(&mut slice), Some(value));
+            assert!(slice.is_empty());
+        }
+        // Small values of both signs take a single byte
+        for value in [-64, 63] {
+            let mut buf = Vec::new();
+            write_signed_varint(&mut buf, value);
+            assert_eq!(buf.len(), 1);
+        }
+    }
+
+    #[test]
+    fn truncated_varint() {
+        assert_eq!(read_varint(&mut [0x80, 0x80].as_slice()), None);
+        assert_eq!(read_varint(&mut [0xFF; 11].as_slice()), None);
+    }
+
+    #[test]
+    fn header_roundtrip() {
+        let header = InputHeader {
+            session: 0xDEADBEEF,
+            sequence: 300,
+            base_distance: 2,
+            mouse_x: -1000,
+            mouse_y: 1 << 40,
+            start_message: u64::MAX - 5,
+            message_count: 5
+        };
+        let mut buf = Vec::new();
+        header.write(&mut buf);
+        assert_eq!(InputHeader::read(&mut buf.as_slice()), Some(header));
+        assert_eq!(InputHeader::read(&mut &buf[..buf.len() - 1]), None);
+    }
+}
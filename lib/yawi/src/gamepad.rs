@@ -0,0 +1,95 @@
+use windows::Win32::UI::Input::XboxController::*;
+
+/// The buttons of an XInput controller
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ControllerButton {
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+    Start,
+    Back,
+    LeftThumb,
+    RightThumb,
+    LeftShoulder,
+    RightShoulder,
+    A,
+    B,
+    X,
+    Y
+}
+
+impl ControllerButton {
+    pub const ALL: [ControllerButton; 14] = [
+        ControllerButton::DPadUp,
+        ControllerButton::DPadDown,
+        ControllerButton::DPadLeft,
+        ControllerButton::DPadRight,
+        ControllerButton::Start,
+        ControllerButton::Back,
+        ControllerButton::LeftThumb,
+        ControllerButton::RightThumb,
+        ControllerButton::LeftShoulder,
+        ControllerButton::RightShoulder,
+        ControllerButton::A,
+        ControllerButton::B,
+        ControllerButton::X,
+        ControllerButton::Y
+    ];
+
+    fn mask(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+/// A snapshot of an XInput controller
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct ControllerState {
+    buttons: u16,
+    pub left_trigger: u8,
+    pub right_trigger: u8,
+    pub left_stick: (i16, i16),
+    pub right_stick: (i16, i16)
+}
+
+impl ControllerState {
+    pub fn is_pressed(&self, button: ControllerButton) -> bool {
+        self.buttons & button.mask() != 0
+    }
+}
+
+/// Returns the current state of the controller in slot `index` (0 - 3) or `None` if no controller is connected
+pub fn get_controller_state(index: u32) -> Option<ControllerState> {
+    let mut state = XINPUT_STATE::default();
+    if unsafe { XInputGetState(index, &mut state) } != 0 {
+        return None;
+    }
+    let gamepad = state.Gamepad;
+    let flags = [
+        (ControllerButton::DPadUp, XINPUT_GAMEPAD_DPAD_UP),
+        (ControllerButton::DPadDown, XINPUT_GAMEPAD_DPAD_DOWN),
+        (ControllerButton::DPadLeft, XINPUT_GAMEPAD_DPAD_LEFT),
+        (ControllerButton::DPadRight, XINPUT_GAMEPAD_DPAD_RIGHT),
+        (ControllerButton::Start, XINPUT_GAMEPAD_START),
+        (ControllerButton::Back, XINPUT_GAMEPAD_BACK),
+        (ControllerButton::LeftThumb, XINPUT_GAMEPAD_LEFT_THUMB),
+        (ControllerButton::RightThumb, XINPUT_GAMEPAD_RIGHT_THUMB),
+        (ControllerButton::LeftShoulder, XINPUT_GAMEPAD_LEFT_SHOULDER),
+        (ControllerButton::RightShoulder, XINPUT_GAMEPAD_RIGHT_SHOULDER),
+        (ControllerButton::A, XINPUT_GAMEPAD_A),
+        (ControllerButton::B, XINPUT_GAMEPAD_B),
+        (ControllerButton::X, XINPUT_GAMEPAD_X),
+        (ControllerButton::Y, XINPUT_GAMEPAD_Y)
+    ];
+    let buttons = flags
+        .into_iter()
+        .filter(|(_, flag)| gamepad.wButtons & *flag == *flag)
+        .fold(0, |acc, (button, _)| acc | button.mask());
+    Some(ControllerState {
+        buttons,
+        left_trigger: gamepad.bLeftTrigger,
+        right_trigger: gamepad.bRightTrigger,
+        left_stick: (gamepad.sThumbLX, gamepad.sThumbLY),
+        right_stick: (gamepad.sThumbRX, gamepad.sThumbRY)
+    })
+}
@@ -1,4 +1,5 @@
 mod enums;
+mod gamepad;
 mod hook;
 mod message;
 mod query;
@@ -7,7 +8,8 @@ mod send;
 pub type WinResult<T> = windows::core::Result<T>;
 
 pub use enums::{Input, InputEvent, KeyEvent, KeyState, ScrollDirection, VirtualKey, WindowsScanCode};
+pub use gamepad::{get_controller_state, ControllerButton, ControllerState};
 pub use hook::{HookAction, HookFn, InputHook};
 pub use message::{quit, run};
-pub use query::get_cursor_pos;
+pub use query::{get_cursor_pos, get_virtual_screen, set_cursor_pos};
 pub use send::{send_input, send_inputs};
@@ -1,5 +1,9 @@
 use windows::Win32::Foundation::POINT;
-use windows::Win32::UI::WindowsAndMessaging::GetCursorPos;
+use windows::Win32::UI::WindowsAndMessaging::{
+    GetCursorPos, GetSystemMetrics, SetCursorPos, SM_CXVIRTUALSCREEN, SM_CYVIRTUALSCREEN, SM_XVIRTUALSCREEN, SM_YVIRTUALSCREEN
+};
+
+use crate::WinResult;
 
 pub fn get_cursor_pos() -> (i32, i32) {
     unsafe {
@@ -8,3 +12,20 @@ pub fn get_cursor_pos() -> (i32, i32) {
         (pt.x, pt.y)
     }
 }
+
+/// Moves the cursor to `(x, y)` in the coordinates of the virtual screen
+pub fn set_cursor_pos(x: i32, y: i32) -> WinResult<()> {
+    unsafe { SetCursorPos(x, y) }
+}
+
+/// Returns the `(x, y, width, height)` of the rectangle that contains all monitors
+pub fn get_virtual_screen() -> (i32, i32, i32, i32) {
+    unsafe {
+        (
+            GetSystemMetrics(SM_XVIRTUALSCREEN),
+            GetSystemMetrics(SM_YVIRTUALSCREEN),
+            GetSystemMetrics(SM_CXVIRTUALSCREEN),
+            GetSystemMetrics(SM_CYVIRTUALSCREEN)
+        )
+    }
+}